@@ -1,11 +1,21 @@
+#[cfg(windows)]
+use std::time::Duration;
+
+#[cfg(windows)]
 use windows_hypervisor::{
+    exit::RunOutcome,
     flags::MapGpaRangeFlags,
     memory::MemoryRegion,
     partition::{PartitionBuilder, PartitionProperty},
-    processor::{Register, RegisterVal},
     query_capability, CapabilityCode,
 };
 
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("this example requires the Windows Hypervisor Platform");
+}
+
+#[cfg(windows)]
 fn main() -> Result<(), windows_hypervisor::Error> {
     for code in [
         CapabilityCode::HypervisorPresent,
@@ -43,14 +53,12 @@ fn main() -> Result<(), windows_hypervisor::Error> {
 
     println!("Virtual Processor: {:?}", vcpu);
 
-    let run_exit_ctx = vcpu.run()?;
-
-    println!("Run Exit Context: {:#?}", run_exit_ctx);
+    match vcpu.run_for(Duration::from_secs(1))? {
+        RunOutcome::Finished(run_exit_ctx) => println!("Run Exit Context: {:#?}", run_exit_ctx),
+        RunOutcome::BudgetExpired(ctx) => println!("Timed out at: {:#?}", ctx),
+    }
 
-    let beef = match vcpu.get_register(Register::Rax)? {
-        RegisterVal::Reg64(v) => v as u16,
-        _ => unreachable!(),
-    };
+    let beef = vcpu.get_regs()?.rax as u16;
 
     println!("Ax value: 0x{:x}", beef);
 
@@ -0,0 +1,668 @@
+use std::fmt::Debug;
+
+use c2rust_bitfields::BitfieldStruct;
+#[cfg(windows)]
+use windows::Win32::System::Hypervisor::{
+    WHV_INTERRUPT_CONTROL, WHV_INTERRUPT_DESTINATION_MODE, WHV_INTERRUPT_TRIGGER_MODE,
+    WHV_INTERRUPT_TYPE,
+};
+
+use crate::{Error, Result};
+
+pub const LAPIC_PAGE_SIZE: usize = 4096;
+
+const ID: usize = 0x20;
+const VERSION: usize = 0x30;
+const TPR: usize = 0x80;
+const APR: usize = 0x90;
+const PPR: usize = 0xa0;
+const LDR: usize = 0xd0;
+const DFR: usize = 0xe0;
+const SVR: usize = 0xf0;
+const ISR: usize = 0x100;
+const TMR: usize = 0x180;
+const IRR: usize = 0x200;
+const ESR: usize = 0x280;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3e0;
+
+/// The local vector table entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lvt {
+    Cmci,
+    Timer,
+    Thermal,
+    PerfCounter,
+    Lint0,
+    Lint1,
+    Error,
+}
+
+impl Lvt {
+    pub fn all() -> impl Iterator<Item = Lvt> {
+        [
+            Self::Cmci,
+            Self::Timer,
+            Self::Thermal,
+            Self::PerfCounter,
+            Self::Lint0,
+            Self::Lint1,
+            Self::Error,
+        ]
+        .into_iter()
+    }
+
+    pub const fn offset(&self) -> usize {
+        match self {
+            Self::Cmci => 0x2f0,
+            Self::Timer => 0x320,
+            Self::Thermal => 0x330,
+            Self::PerfCounter => 0x340,
+            Self::Lint0 => 0x350,
+            Self::Lint1 => 0x360,
+            Self::Error => 0x370,
+        }
+    }
+}
+
+#[repr(C, align(1))]
+#[derive(BitfieldStruct, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LvtEntry {
+    #[bitfield(name = "vector", ty = "u8", bits = "0..=7")]
+    #[bitfield(name = "delivery_mode", ty = "u8", bits = "8..=10")]
+    #[bitfield(name = "delivery_pending", ty = "bool", bits = "12..=12")]
+    #[bitfield(name = "active_low", ty = "bool", bits = "13..=13")]
+    #[bitfield(name = "remote_irr", ty = "bool", bits = "14..=14")]
+    #[bitfield(name = "level_triggered", ty = "bool", bits = "15..=15")]
+    #[bitfield(name = "masked", ty = "bool", bits = "16..=16")]
+    #[bitfield(name = "timer_mode", ty = "u8", bits = "17..=18")]
+    bitfield: [u8; 4],
+}
+
+impl Debug for LvtEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LvtEntry")
+            .field("vector", &self.vector())
+            .field("delivery_mode", &self.delivery_mode())
+            .field("delivery_pending", &self.delivery_pending())
+            .field("active_low", &self.active_low())
+            .field("remote_irr", &self.remote_irr())
+            .field("level_triggered", &self.level_triggered())
+            .field("masked", &self.masked())
+            .field("timer_mode", &self.timer_mode())
+            .finish()
+    }
+}
+
+impl From<u32> for LvtEntry {
+    fn from(value: u32) -> Self {
+        Self {
+            bitfield: value.to_le_bytes(),
+        }
+    }
+}
+
+impl From<LvtEntry> for u32 {
+    fn from(value: LvtEntry) -> Self {
+        u32::from_le_bytes(value.bitfield)
+    }
+}
+
+/// One bit per interrupt vector, as held by the ISR, TMR and IRR.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VectorBitmap(pub [u32; 8]);
+
+impl VectorBitmap {
+    pub fn contains(&self, vector: u8) -> bool {
+        self.0[vector as usize / 32] & (1 << (vector % 32)) != 0
+    }
+
+    pub fn insert(&mut self, vector: u8) {
+        self.0[vector as usize / 32] |= 1 << (vector % 32);
+    }
+
+    pub fn remove(&mut self, vector: u8) {
+        self.0[vector as usize / 32] &= !(1 << (vector % 32));
+    }
+
+    /// The set vectors, lowest first.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=u8::MAX).filter(|&vector| self.contains(vector))
+    }
+
+    /// The highest set vector, which is also the one of the highest priority.
+    pub fn highest(&self) -> Option<u8> {
+        (0..=u8::MAX).rev().find(|&vector| self.contains(vector))
+    }
+}
+
+/// The state of a local APIC, held as the 4 KiB page of its registers.
+///
+/// Every register sits in the low 32 bits of its own 16 byte slot, at the offset it has in the
+/// xAPIC MMIO page. Registers without an accessor can still be reached through
+/// [`LapicState::register`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct LapicState {
+    page: Box<[u8; LAPIC_PAGE_SIZE]>,
+}
+
+/// The state after a reset, every LVT entry is masked and the APIC is software disabled.
+impl Default for LapicState {
+    fn default() -> Self {
+        let mut state = Self {
+            page: Box::new([0; LAPIC_PAGE_SIZE]),
+        };
+        state.set_register(DFR, u32::MAX);
+        state.set_register(SVR, 0xff);
+        for lvt in Lvt::all() {
+            let mut entry = LvtEntry::default();
+            entry.set_masked(true);
+            state.set_lvt(lvt, entry);
+        }
+        state
+    }
+}
+
+impl LapicState {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let page: [u8; LAPIC_PAGE_SIZE] = bytes
+            .try_into()
+            .map_err(|_| Error::InvalidLapicPageSize(bytes.len()))?;
+        Ok(Self {
+            page: Box::new(page),
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.page.as_slice()
+    }
+
+    /// The register at `offset` in the page.
+    pub fn register(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.page[offset..offset + 4].try_into().unwrap())
+    }
+
+    pub fn set_register(&mut self, offset: usize, value: u32) {
+        self.page[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// The raw ID register, in xAPIC mode the ID is held by its top byte.
+    pub fn id(&self) -> u32 {
+        self.register(ID)
+    }
+
+    pub fn set_id(&mut self, id: u32) {
+        self.set_register(ID, id)
+    }
+
+    pub fn version(&self) -> u32 {
+        self.register(VERSION)
+    }
+
+    pub fn tpr(&self) -> u32 {
+        self.register(TPR)
+    }
+
+    pub fn set_tpr(&mut self, tpr: u32) {
+        self.set_register(TPR, tpr)
+    }
+
+    pub fn apr(&self) -> u32 {
+        self.register(APR)
+    }
+
+    pub fn ppr(&self) -> u32 {
+        self.register(PPR)
+    }
+
+    pub fn ldr(&self) -> u32 {
+        self.register(LDR)
+    }
+
+    pub fn set_ldr(&mut self, ldr: u32) {
+        self.set_register(LDR, ldr)
+    }
+
+    pub fn dfr(&self) -> u32 {
+        self.register(DFR)
+    }
+
+    pub fn set_dfr(&mut self, dfr: u32) {
+        self.set_register(DFR, dfr)
+    }
+
+    pub fn svr(&self) -> u32 {
+        self.register(SVR)
+    }
+
+    pub fn set_svr(&mut self, svr: u32) {
+        self.set_register(SVR, svr)
+    }
+
+    pub fn spurious_vector(&self) -> u8 {
+        self.svr() as u8
+    }
+
+    pub fn is_software_enabled(&self) -> bool {
+        self.svr() & (1 << 8) != 0
+    }
+
+    pub fn isr(&self) -> VectorBitmap {
+        self.bitmap(ISR)
+    }
+
+    pub fn set_isr(&mut self, isr: VectorBitmap) {
+        self.set_bitmap(ISR, isr)
+    }
+
+    pub fn tmr(&self) -> VectorBitmap {
+        self.bitmap(TMR)
+    }
+
+    pub fn set_tmr(&mut self, tmr: VectorBitmap) {
+        self.set_bitmap(TMR, tmr)
+    }
+
+    pub fn irr(&self) -> VectorBitmap {
+        self.bitmap(IRR)
+    }
+
+    pub fn set_irr(&mut self, irr: VectorBitmap) {
+        self.set_bitmap(IRR, irr)
+    }
+
+    pub fn esr(&self) -> u32 {
+        self.register(ESR)
+    }
+
+    pub fn icr(&self) -> u64 {
+        self.register(ICR_LOW) as u64 | (self.register(ICR_HIGH) as u64) << 32
+    }
+
+    pub fn set_icr(&mut self, icr: u64) {
+        self.set_register(ICR_LOW, icr as u32);
+        self.set_register(ICR_HIGH, (icr >> 32) as u32);
+    }
+
+    pub fn lvt(&self, lvt: Lvt) -> LvtEntry {
+        self.register(lvt.offset()).into()
+    }
+
+    pub fn set_lvt(&mut self, lvt: Lvt, entry: LvtEntry) {
+        self.set_register(lvt.offset(), entry.into())
+    }
+
+    pub fn timer_initial_count(&self) -> u32 {
+        self.register(TIMER_INITIAL_COUNT)
+    }
+
+    pub fn set_timer_initial_count(&mut self, count: u32) {
+        self.set_register(TIMER_INITIAL_COUNT, count)
+    }
+
+    pub fn timer_current_count(&self) -> u32 {
+        self.register(TIMER_CURRENT_COUNT)
+    }
+
+    pub fn set_timer_current_count(&mut self, count: u32) {
+        self.set_register(TIMER_CURRENT_COUNT, count)
+    }
+
+    pub fn timer_divide(&self) -> u32 {
+        self.register(TIMER_DIVIDE)
+    }
+
+    pub fn set_timer_divide(&mut self, divide: u32) {
+        self.set_register(TIMER_DIVIDE, divide)
+    }
+
+    /// What the timer divides the bus clock by, decoded from the divide configuration.
+    pub fn timer_divisor(&self) -> u32 {
+        let divide = self.timer_divide();
+        match (divide & 0b11) | (divide & 0b1000) >> 1 {
+            0b111 => 1,
+            shift => 2 << shift,
+        }
+    }
+
+    fn bitmap(&self, offset: usize) -> VectorBitmap {
+        VectorBitmap(std::array::from_fn(|i| self.register(offset + i * 0x10)))
+    }
+
+    fn set_bitmap(&mut self, offset: usize, bitmap: VectorBitmap) {
+        for (i, bits) in bitmap.0.into_iter().enumerate() {
+            self.set_register(offset + i * 0x10, bits);
+        }
+    }
+}
+
+impl Debug for LapicState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut s = f.debug_struct("LapicState");
+        s.field("id", &self.id())
+            .field("version", &self.version())
+            .field("tpr", &self.tpr())
+            .field("ppr", &self.ppr())
+            .field("ldr", &self.ldr())
+            .field("dfr", &self.dfr())
+            .field("svr", &self.svr())
+            .field("isr", &self.isr().iter().collect::<Vec<_>>())
+            .field("tmr", &self.tmr().iter().collect::<Vec<_>>())
+            .field("irr", &self.irr().iter().collect::<Vec<_>>())
+            .field("esr", &self.esr())
+            .field("icr", &self.icr());
+        for lvt in Lvt::all() {
+            s.field(&format!("{lvt:?}"), &self.lvt(lvt));
+        }
+        s.field("timer_initial_count", &self.timer_initial_count())
+            .field("timer_current_count", &self.timer_current_count())
+            .field("timer_divide", &self.timer_divide())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(i32)]
+pub enum InterruptType {
+    Fixed = 0,
+    LowestPriority = 1,
+    Nmi = 4,
+    Init = 5,
+    Sipi = 6,
+    LocalInt1 = 9,
+}
+
+#[cfg(windows)]
+impl From<WHV_INTERRUPT_TYPE> for InterruptType {
+    fn from(value: WHV_INTERRUPT_TYPE) -> Self {
+        // TODO: Can we enforce this differently?
+        match value.0 {
+            0x00000000 => Self::Fixed,
+            0x00000001 => Self::LowestPriority,
+            0x00000004 => Self::Nmi,
+            0x00000005 => Self::Init,
+            0x00000006 => Self::Sipi,
+            0x00000009 => Self::LocalInt1,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<InterruptType> for WHV_INTERRUPT_TYPE {
+    fn from(value: InterruptType) -> Self {
+        Self(value as i32)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(i32)]
+pub enum InterruptDestinationMode {
+    /// The destination is an APIC ID.
+    #[default]
+    Physical,
+    /// The destination is matched against the logical destination of every APIC.
+    Logical,
+}
+
+#[cfg(windows)]
+impl From<WHV_INTERRUPT_DESTINATION_MODE> for InterruptDestinationMode {
+    fn from(value: WHV_INTERRUPT_DESTINATION_MODE) -> Self {
+        // TODO: Can we enforce this differently?
+        match value.0 {
+            0x00000000 => Self::Physical,
+            0x00000001 => Self::Logical,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<InterruptDestinationMode> for WHV_INTERRUPT_DESTINATION_MODE {
+    fn from(value: InterruptDestinationMode) -> Self {
+        Self(value as i32)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(i32)]
+pub enum InterruptTriggerMode {
+    #[default]
+    Edge,
+    Level,
+}
+
+#[cfg(windows)]
+impl From<WHV_INTERRUPT_TRIGGER_MODE> for InterruptTriggerMode {
+    fn from(value: WHV_INTERRUPT_TRIGGER_MODE) -> Self {
+        // TODO: Can we enforce this differently?
+        match value.0 {
+            0x00000000 => Self::Edge,
+            0x00000001 => Self::Level,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<InterruptTriggerMode> for WHV_INTERRUPT_TRIGGER_MODE {
+    fn from(value: InterruptTriggerMode) -> Self {
+        Self(value as i32)
+    }
+}
+
+/// An interrupt to deliver through the local APICs, see
+/// [`crate::partition::Partition::request_interrupt`].
+///
+/// The helpers address a virtual processor by its index, which is also its APIC ID unless the
+/// guest or the host has changed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InterruptControl {
+    pub ty: InterruptType,
+    pub destination_mode: InterruptDestinationMode,
+    pub trigger_mode: InterruptTriggerMode,
+    pub destination: u32,
+    /// The vector of a fixed or lowest priority interrupt, the start page of a SIPI.
+    pub vector: u8,
+}
+
+impl InterruptControl {
+    pub fn new(ty: InterruptType, destination: u32, vector: u8) -> Self {
+        Self {
+            ty,
+            destination_mode: Default::default(),
+            trigger_mode: Default::default(),
+            destination,
+            vector,
+        }
+    }
+
+    /// An edge triggered interrupt with `vector` for the virtual processor `vp_index`.
+    pub fn fixed(vp_index: u32, vector: u8) -> Self {
+        Self::new(InterruptType::Fixed, vp_index, vector)
+    }
+
+    pub fn nmi(vp_index: u32) -> Self {
+        Self::new(InterruptType::Nmi, vp_index, 0)
+    }
+
+    pub fn init(vp_index: u32) -> Self {
+        Self::new(InterruptType::Init, vp_index, 0)
+    }
+
+    /// Start the virtual processor `vp_index` in real mode at `start_page << 12`.
+    pub fn sipi(vp_index: u32, start_page: u8) -> Self {
+        Self::new(InterruptType::Sipi, vp_index, start_page)
+    }
+
+    pub fn with_destination_mode(mut self, destination_mode: InterruptDestinationMode) -> Self {
+        self.destination_mode = destination_mode;
+        self
+    }
+
+    pub fn with_trigger_mode(mut self, trigger_mode: InterruptTriggerMode) -> Self {
+        self.trigger_mode = trigger_mode;
+        self
+    }
+}
+
+#[cfg(windows)]
+impl From<WHV_INTERRUPT_CONTROL> for InterruptControl {
+    fn from(value: WHV_INTERRUPT_CONTROL) -> Self {
+        Self {
+            ty: WHV_INTERRUPT_TYPE((value._bitfield & 0xff) as i32).into(),
+            destination_mode: WHV_INTERRUPT_DESTINATION_MODE(((value._bitfield >> 8) & 0xf) as i32)
+                .into(),
+            trigger_mode: WHV_INTERRUPT_TRIGGER_MODE(((value._bitfield >> 12) & 0xf) as i32).into(),
+            destination: value.Destination,
+            vector: value.Vector as u8,
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<InterruptControl> for WHV_INTERRUPT_CONTROL {
+    fn from(value: InterruptControl) -> Self {
+        Self {
+            _bitfield: value.ty as u64
+                | (value.destination_mode as u64) << 8
+                | (value.trigger_mode as u64) << 12,
+            Destination: value.destination,
+            Vector: value.vector as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        backend::MockBackend,
+        partition::{PartitionBuilder, PartitionProperty},
+        Error,
+    };
+
+    use super::{
+        InterruptControl, InterruptDestinationMode, InterruptTriggerMode, InterruptType,
+        LapicState, Lvt, VectorBitmap, LAPIC_PAGE_SIZE,
+    };
+
+    #[test]
+    fn reset_state() {
+        let state = LapicState::default();
+        assert!(Lvt::all().all(|lvt| state.lvt(lvt).masked()));
+        assert_eq!(state.dfr(), u32::MAX);
+        assert_eq!(state.spurious_vector(), 0xff);
+        assert!(!state.is_software_enabled());
+        assert_eq!(state.irr().highest(), None);
+        assert_eq!(state.timer_divisor(), 2);
+    }
+
+    #[test]
+    fn registers() {
+        let mut state = LapicState::default();
+        let mut irr = VectorBitmap::default();
+        irr.insert(0x20);
+        irr.insert(0xfe);
+        state.set_irr(irr);
+        assert_eq!(state.register(0x200 + 0x10), 1);
+        assert_eq!(state.register(0x270), 1 << 30);
+        assert_eq!(state.irr().iter().collect::<Vec<_>>(), [0x20, 0xfe]);
+        assert_eq!(state.irr().highest(), Some(0xfe));
+
+        let mut timer = state.lvt(Lvt::Timer);
+        timer.set_vector(0xec);
+        timer.set_masked(false);
+        timer.set_timer_mode(1);
+        state.set_lvt(Lvt::Timer, timer);
+        assert_eq!(state.register(0x320), 0x200ec);
+
+        state.set_icr(0x0300_0000_0000_4031);
+        assert_eq!(state.register(0x300), 0x4031);
+        assert_eq!(state.register(0x310), 0x0300_0000);
+
+        state.set_timer_divide(0b1011);
+        assert_eq!(state.timer_divisor(), 1);
+        state.set_timer_divide(0b1010);
+        assert_eq!(state.timer_divisor(), 128);
+
+        let parsed = LapicState::from_bytes(state.as_bytes()).unwrap();
+        assert_eq!(parsed, state);
+        assert!(matches!(
+            LapicState::from_bytes(&[0; 1024]),
+            Err(Error::InvalidLapicPageSize(1024))
+        ));
+    }
+
+    #[test]
+    fn processor_state() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(2))
+            .unwrap()
+            .setup()
+            .unwrap();
+        let mut vcpu = partition.create_virtual_processor(1).unwrap();
+
+        let mut state = vcpu.get_lapic_state().unwrap();
+        assert_eq!(state.as_bytes().len(), LAPIC_PAGE_SIZE);
+        assert_eq!(state.id(), 1 << 24);
+
+        state.set_tpr(0x20);
+        state.set_svr(0x1ff);
+        vcpu.set_lapic_state(&state).unwrap();
+        let state = vcpu.get_lapic_state().unwrap();
+        assert_eq!(state.tpr(), 0x20);
+        assert!(state.is_software_enabled());
+    }
+
+    #[test]
+    fn request_interrupt() {
+        let mock = Arc::new(MockBackend::new());
+        let partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(2))
+            .unwrap()
+            .setup()
+            .unwrap();
+
+        let level = InterruptControl::new(InterruptType::LowestPriority, 0b11, 0x30)
+            .with_destination_mode(InterruptDestinationMode::Logical)
+            .with_trigger_mode(InterruptTriggerMode::Level);
+        partition.request_interrupt(level).unwrap();
+        partition
+            .request_interrupt(InterruptControl::fixed(0, 0x20))
+            .unwrap();
+        partition
+            .request_interrupt(InterruptControl::nmi(1))
+            .unwrap();
+        partition.request_init_sipi(1, 0x8).unwrap();
+
+        let interrupts = mock.requested_interrupts();
+        assert_eq!(interrupts[0], level);
+        assert_eq!(
+            interrupts[1],
+            InterruptControl {
+                ty: InterruptType::Fixed,
+                destination_mode: InterruptDestinationMode::Physical,
+                trigger_mode: InterruptTriggerMode::Edge,
+                destination: 0,
+                vector: 0x20,
+            }
+        );
+        assert_eq!(
+            interrupts[2..]
+                .iter()
+                .map(|i| (i.ty, i.destination, i.vector))
+                .collect::<Vec<_>>(),
+            [
+                (InterruptType::Nmi, 1, 0),
+                (InterruptType::Init, 1, 0),
+                (InterruptType::Sipi, 1, 0x8),
+            ]
+        );
+    }
+}
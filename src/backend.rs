@@ -0,0 +1,70 @@
+use std::fmt::Debug;
+
+use crate::{
+    apic::InterruptControl,
+    flags::TranslateGvaFlags,
+    memory::{DirtyBitmap, MemoryRegion},
+    partition::{PartitionProperty, PartitionPropertyCode},
+    processor::{GvaTranslation, Register, RegisterVal, RunExitContext},
+    Result,
+};
+
+pub mod mock;
+#[cfg(windows)]
+pub mod whp;
+
+pub use mock::MockBackend;
+#[cfg(windows)]
+pub use whp::WhpBackend;
+
+/// The hypervisor operations a single partition is built on.
+///
+/// [`crate::partition::Partition`] and [`crate::processor::VirtualProcessor`] only ever talk to a
+/// backend, so the same code can run against `WhpBackend` on Windows or the in-process
+/// [`MockBackend`].
+pub trait Backend: Debug + Send + Sync {
+    fn set_property(&self, prop: PartitionProperty) -> Result<()>;
+
+    fn get_property(&self, code: PartitionPropertyCode) -> Result<PartitionProperty>;
+
+    fn setup(&self) -> Result<()>;
+
+    fn map_gpa_range(&self, region: &MemoryRegion) -> Result<()>;
+
+    fn unmap_gpa_range(&self, guest_address: u64, size: u64) -> Result<()>;
+
+    /// Query and reset the dirty pages of a range mapped with [`crate::flags::MapGpaRangeFlags::TrackDirtyPages`].
+    fn query_dirty_bitmap(&self, guest_address: u64, size: u64) -> Result<DirtyBitmap>;
+
+    fn request_interrupt(&self, interrupt: &InterruptControl) -> Result<()>;
+
+    fn create_virtual_processor(&self, index: u32) -> Result<()>;
+
+    fn delete_virtual_processor(&self, index: u32) -> Result<()>;
+
+    fn run_virtual_processor(&self, index: u32) -> Result<RunExitContext>;
+
+    /// Make the current or next run of the virtual processor `index` return a canceled exit.
+    fn cancel_run_virtual_processor(&self, index: u32) -> Result<()>;
+
+    fn get_registers(&self, index: u32, registers: &[Register]) -> Result<Vec<RegisterVal>>;
+
+    fn set_registers(&self, index: u32, register_vals: &[(Register, RegisterVal)]) -> Result<()>;
+
+    /// The XSAVE area of the virtual processor `index`, in the compacted format.
+    fn get_xsave_state(&self, index: u32) -> Result<Vec<u8>>;
+
+    fn set_xsave_state(&self, index: u32, state: &[u8]) -> Result<()>;
+
+    /// The local APIC page of the virtual processor `index`.
+    fn get_lapic_state(&self, index: u32) -> Result<Vec<u8>>;
+
+    fn set_lapic_state(&self, index: u32, state: &[u8]) -> Result<()>;
+
+    fn translate_gva(
+        &self,
+        index: u32,
+        gva: u64,
+        flags: TranslateGvaFlags,
+    ) -> Result<GvaTranslation>;
+}
@@ -0,0 +1,635 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Mutex,
+};
+
+use crate::{
+    apic::{InterruptControl, LapicState},
+    flags::{MapGpaRangeFlags, TranslateGvaFlags, XsaveComponents},
+    memory::{DirtyBitmap, GuestMemoryRead, GuestMemoryWrite, MemoryRegion, PAGE_SIZE},
+    paging::{PageAccess, PageFaultErrorCode, PagingState},
+    partition::{PartitionProperty, PartitionPropertyCode},
+    processor::{
+        GvaTranslation, Register, RegisterVal, RunExitContext, TranslateGvaResultCode, VmExit,
+        VpCancelReason,
+    },
+    xsave::XSAVE_MIN_SIZE,
+    Error, Result,
+};
+
+use super::Backend;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MockMapping {
+    pub address: usize,
+    pub guest_address: usize,
+    pub size: usize,
+    pub flags: MapGpaRangeFlags,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    is_setup: bool,
+    properties: HashMap<PartitionPropertyCode, PartitionProperty>,
+    mappings: Vec<MockMapping>,
+    dirty_pages: HashSet<u64>,
+    processors: HashSet<u32>,
+    registers: HashMap<(u32, Register), RegisterVal>,
+    exits: HashMap<u32, VecDeque<RunExitContext>>,
+    canceled: HashSet<u32>,
+    xsave: HashMap<u32, Vec<u8>>,
+    lapic: HashMap<u32, Vec<u8>>,
+    interrupts: Vec<InterruptControl>,
+    failing_maps: usize,
+}
+
+/// An in-process backend that never touches a hypervisor.
+///
+/// Exits are scripted per virtual processor with [`MockBackend::push_exit`] and handed out in order
+/// by [`Backend::run_virtual_processor`]. A cancellation is kept until the next run like the
+/// hypervisor does and returned before the scripted exits.
+///
+/// Registers live in memory and read as zero until written.
+///
+/// The XSAVE area starts out as a compacted area holding only the x87 and SSE state. The local APIC
+/// starts out in its reset state with the processor index as its ID.
+///
+/// Guest memory is read and written through the host allocations of the mapped regions. Writes
+/// made with [`MockBackend::write_memory`] stand in for guest writes when tracking dirty pages.
+///
+/// Translations walk the guest page tables in software, the privilege level is taken from the
+/// selector in `Cs`.
+#[derive(Debug, Default)]
+pub struct MockBackend {
+    state: Mutex<MockState>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn is_setup(&self) -> bool {
+        self.state().is_setup
+    }
+
+    /// Queue `exit` to be returned by the next run of the virtual processor `index`.
+    pub fn push_exit(&self, index: u32, exit: RunExitContext) {
+        self.state().exits.entry(index).or_default().push_back(exit);
+    }
+
+    /// The number of scripted exits the virtual processor `index` has yet to return.
+    pub fn pending_exits(&self, index: u32) -> usize {
+        self.state().exits.get(&index).map_or(0, |q| q.len())
+    }
+
+    pub fn register(&self, index: u32, register: Register) -> Option<RegisterVal> {
+        self.state().registers.get(&(index, register)).copied()
+    }
+
+    pub fn set_register(&self, index: u32, register: Register, value: RegisterVal) {
+        self.state().registers.insert((index, register), value);
+    }
+
+    /// Make the next `count` calls to [`Backend::map_gpa_range`] fail.
+    pub fn fail_maps(&self, count: usize) {
+        self.state().failing_maps = count;
+    }
+
+    pub fn mappings(&self) -> Vec<MockMapping> {
+        self.state().mappings.clone()
+    }
+
+    /// Every interrupt requested so far, oldest first.
+    pub fn requested_interrupts(&self) -> Vec<InterruptControl> {
+        self.state().interrupts.clone()
+    }
+
+    pub fn is_processor_created(&self, index: u32) -> bool {
+        self.state().processors.contains(&index)
+    }
+
+    /// Read guest memory starting at `gpa`, the access may span multiple mapped regions.
+    pub fn read_memory(&self, gpa: u64, buf: &mut [u8]) -> Result<()> {
+        let state = self.state();
+        let mut done = 0;
+        while done < buf.len() {
+            let (host, len) = state.translate(gpa + done as u64, buf.len() - done)?;
+            // SAFETY: The mapping stays valid for as long as the partition owns the region.
+            unsafe {
+                std::ptr::copy_nonoverlapping(host as *const u8, buf[done..].as_mut_ptr(), len)
+            };
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Write guest memory starting at `gpa`, the access may span multiple mapped regions.
+    pub fn write_memory(&self, gpa: u64, buf: &[u8]) -> Result<()> {
+        let mut state = self.state();
+        let mut done = 0;
+        while done < buf.len() {
+            let (host, len) = state.translate(gpa + done as u64, buf.len() - done)?;
+            // SAFETY: The mapping stays valid for as long as the partition owns the region.
+            unsafe { std::ptr::copy_nonoverlapping(buf[done..].as_ptr(), host as *mut u8, len) };
+            done += len;
+        }
+
+        let page = PAGE_SIZE as u64;
+        for pfn in gpa / page..(gpa + buf.len() as u64).div_ceil(page) {
+            let tracked = state.mappings.iter().any(|m| {
+                m.flags.contains(MapGpaRangeFlags::TrackDirtyPages)
+                    && m.guest_address as u64 <= pfn * page
+                    && pfn * page < (m.guest_address + m.size) as u64
+            });
+            if tracked {
+                state.dirty_pages.insert(pfn);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GuestMemoryRead for MockBackend {
+    fn read_physical(&self, gpa: u64, buf: &mut [u8]) -> Result<()> {
+        self.read_memory(gpa, buf)
+    }
+}
+
+impl GuestMemoryWrite for MockBackend {
+    fn write_physical(&self, gpa: u64, buf: &[u8]) -> Result<()> {
+        self.write_memory(gpa, buf)
+    }
+}
+
+impl MockState {
+    /// Returns the host address of `gpa` and how many of `len` bytes are contiguous from there.
+    fn translate(&self, gpa: u64, len: usize) -> Result<(usize, usize)> {
+        let gpa_usize = usize::try_from(gpa)?;
+        self.mappings
+            .iter()
+            .find(|m| gpa_usize >= m.guest_address && gpa_usize - m.guest_address < m.size)
+            .map(|m| {
+                let offset = gpa_usize - m.guest_address;
+                (m.address + offset, len.min(m.size - offset))
+            })
+            .ok_or(Error::GpaUnmapped(gpa))
+    }
+}
+
+impl Backend for MockBackend {
+    fn set_property(&self, prop: PartitionProperty) -> Result<()> {
+        self.state().properties.insert(prop.code(), prop);
+        Ok(())
+    }
+
+    fn get_property(&self, code: PartitionPropertyCode) -> Result<PartitionProperty> {
+        self.state()
+            .properties
+            .get(&code)
+            .copied()
+            .ok_or(Error::UnsetProperty(code))
+    }
+
+    fn setup(&self) -> Result<()> {
+        self.state().is_setup = true;
+        Ok(())
+    }
+
+    fn map_gpa_range(&self, region: &MemoryRegion) -> Result<()> {
+        let mut state = self.state();
+        if state.failing_maps > 0 {
+            state.failing_maps -= 1;
+            return Err(Error::ScriptedMapFailure(region.guest_address as u64));
+        }
+        state.mappings.push(MockMapping {
+            address: region.address,
+            guest_address: region.guest_address,
+            size: region.size,
+            flags: region.flags,
+        });
+        Ok(())
+    }
+
+    fn unmap_gpa_range(&self, guest_address: u64, size: u64) -> Result<()> {
+        let mut state = self.state();
+        let index = state
+            .mappings
+            .iter()
+            .position(|m| m.guest_address as u64 == guest_address && m.size as u64 == size)
+            .ok_or(Error::GpaUnmapped(guest_address))?;
+        state.mappings.remove(index);
+        Ok(())
+    }
+
+    fn query_dirty_bitmap(&self, guest_address: u64, size: u64) -> Result<DirtyBitmap> {
+        let mut state = self.state();
+        let mut bitmap = DirtyBitmap::new(guest_address, size);
+        let first_pfn = guest_address / PAGE_SIZE as u64;
+        for page in 0..bitmap.pages() {
+            if state.dirty_pages.remove(&(first_pfn + page as u64)) {
+                bitmap.set_dirty(page);
+            }
+        }
+        Ok(bitmap)
+    }
+
+    fn request_interrupt(&self, interrupt: &InterruptControl) -> Result<()> {
+        self.state().interrupts.push(*interrupt);
+        Ok(())
+    }
+
+    fn create_virtual_processor(&self, index: u32) -> Result<()> {
+        self.state().processors.insert(index);
+        Ok(())
+    }
+
+    fn delete_virtual_processor(&self, index: u32) -> Result<()> {
+        self.state().processors.remove(&index);
+        Ok(())
+    }
+
+    fn run_virtual_processor(&self, index: u32) -> Result<RunExitContext> {
+        let mut state = self.state();
+        if state.canceled.remove(&index) {
+            return Ok(RunExitContext {
+                context: Default::default(),
+                exit: VmExit::Canceled(VpCancelReason::User),
+            });
+        }
+        state
+            .exits
+            .get_mut(&index)
+            .and_then(|q| q.pop_front())
+            .ok_or(Error::NoScriptedExit(index))
+    }
+
+    fn cancel_run_virtual_processor(&self, index: u32) -> Result<()> {
+        self.state().canceled.insert(index);
+        Ok(())
+    }
+
+    fn get_registers(&self, index: u32, registers: &[Register]) -> Result<Vec<RegisterVal>> {
+        let state = self.state();
+        Ok(registers
+            .iter()
+            .map(|&r| {
+                state
+                    .registers
+                    .get(&(index, r))
+                    .copied()
+                    .unwrap_or_else(|| RegisterVal::zeroed(r.ty()))
+            })
+            .collect())
+    }
+
+    fn set_registers(&self, index: u32, register_vals: &[(Register, RegisterVal)]) -> Result<()> {
+        let mut state = self.state();
+        for &(r, v) in register_vals {
+            state.registers.insert((index, r), v);
+        }
+        Ok(())
+    }
+
+    fn get_xsave_state(&self, index: u32) -> Result<Vec<u8>> {
+        Ok(self.state().xsave.get(&index).cloned().unwrap_or_else(|| {
+            let mut area = vec![0; XSAVE_MIN_SIZE];
+            let xcomp_bv = XsaveComponents::Compacted | XsaveComponents::X87 | XsaveComponents::Sse;
+            area[520..528].copy_from_slice(&xcomp_bv.bits().to_le_bytes());
+            area
+        }))
+    }
+
+    fn set_xsave_state(&self, index: u32, state: &[u8]) -> Result<()> {
+        self.state().xsave.insert(index, state.to_vec());
+        Ok(())
+    }
+
+    fn get_lapic_state(&self, index: u32) -> Result<Vec<u8>> {
+        Ok(self.state().lapic.get(&index).cloned().unwrap_or_else(|| {
+            let mut lapic = LapicState::default();
+            lapic.set_id(index << 24);
+            lapic.as_bytes().to_vec()
+        }))
+    }
+
+    fn set_lapic_state(&self, index: u32, state: &[u8]) -> Result<()> {
+        self.state().lapic.insert(index, state.to_vec());
+        Ok(())
+    }
+
+    fn translate_gva(
+        &self,
+        index: u32,
+        gva: u64,
+        flags: TranslateGvaFlags,
+    ) -> Result<GvaTranslation> {
+        let registers = self.get_registers(
+            index,
+            &[
+                Register::Cr0,
+                Register::Cr3,
+                Register::Cr4,
+                Register::Efer,
+                Register::Cs,
+            ],
+        )?;
+        let value = |i: usize| registers[i].as_u64().unwrap_or_default();
+        let paging = PagingState {
+            cr0: value(0),
+            cr3: value(1),
+            cr4: value(2),
+            efer: value(3),
+        };
+
+        let mut access = PageAccess::empty();
+        if flags.contains(TranslateGvaFlags::ValidateWrite) {
+            access |= PageAccess::Write;
+        }
+        if flags.contains(TranslateGvaFlags::ValidateExecute) {
+            access |= PageAccess::Execute;
+        }
+        if matches!(registers[4], RegisterVal::Segment(cs) if cs.selector & 3 == 3)
+            && !flags.contains(TranslateGvaFlags::PrivilegeExempt)
+        {
+            access |= PageAccess::User;
+        }
+
+        let failed = |result_code| GvaTranslation {
+            result_code,
+            gpa: 0,
+        };
+        let gpa = match paging.translate(self, gva, access) {
+            Ok(translation) => translation.gpa,
+            Err(Error::PageFault(fault)) => {
+                let code = if fault.error_code.contains(PageFaultErrorCode::ReservedBit) {
+                    TranslateGvaResultCode::InvalidPageTableFlags
+                } else if fault.error_code.contains(PageFaultErrorCode::Present) {
+                    TranslateGvaResultCode::PrivilegeViolation
+                } else {
+                    TranslateGvaResultCode::PageNotPresent
+                };
+                return Ok(failed(code));
+            }
+            Err(Error::GpaUnmapped(_)) => return Ok(failed(TranslateGvaResultCode::GpaUnmapped)),
+            Err(e) => return Err(e),
+        };
+
+        let state = self.state();
+        let Some(mapping) = state
+            .mappings
+            .iter()
+            .find(|m| m.guest_address as u64 <= gpa && gpa < (m.guest_address + m.size) as u64)
+        else {
+            return Ok(failed(TranslateGvaResultCode::GpaUnmapped));
+        };
+        let result_code = if flags.contains(TranslateGvaFlags::ValidateRead)
+            && !mapping.flags.contains(MapGpaRangeFlags::Read)
+        {
+            TranslateGvaResultCode::GpaNoReadAccess
+        } else if flags.contains(TranslateGvaFlags::ValidateWrite)
+            && !mapping.flags.contains(MapGpaRangeFlags::Write)
+        {
+            TranslateGvaResultCode::GpaNoWriteAccess
+        } else {
+            TranslateGvaResultCode::Success
+        };
+        Ok(GvaTranslation { result_code, gpa })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        flags::{MapGpaRangeFlags, TranslateGvaFlags},
+        memory::MemoryRegion,
+        partition::{PartitionBuilder, PartitionProperty},
+        processor::{
+            ExitContext, Register, RegisterVal, RunExitContext, SegmentRegister,
+            TranslateGvaResultCode, VmExit, VpCancelHandle, VpCancelReason,
+        },
+        Error,
+    };
+
+    use super::{Backend, MockBackend};
+
+    fn halt_exit(rip: u64) -> RunExitContext {
+        let mut context = ExitContext::default();
+        context.rip = rip;
+        RunExitContext {
+            context,
+            exit: VmExit::Halt,
+        }
+    }
+
+    #[test]
+    fn scripted_run() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(1))
+            .unwrap()
+            .setup()
+            .unwrap();
+        assert!(mock.is_setup());
+
+        assert!(matches!(
+            partition.create_virtual_processor(1),
+            Err(Error::InvalidVpIndex(1, 1))
+        ));
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+        assert!(mock.is_processor_created(0));
+
+        mock.push_exit(0, halt_exit(0xfff2));
+        let exit = vcpu.run().unwrap();
+        assert_eq!(exit.exit, VmExit::Halt);
+        assert_eq!(exit.context.rip, 0xfff2);
+        assert!(matches!(vcpu.run(), Err(Error::NoScriptedExit(0))));
+
+        drop(vcpu);
+        assert!(!mock.is_processor_created(0));
+    }
+
+    #[test]
+    fn cancel() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<VpCancelHandle>();
+
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(1))
+            .unwrap()
+            .setup()
+            .unwrap();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+        vcpu.set_register(Register::Rip, 0xfff0_u64.into()).unwrap();
+
+        // Requested before the run, so the guest is never entered.
+        let handle = vcpu.cancel_handle();
+        std::thread::spawn(move || handle.cancel().unwrap())
+            .join()
+            .unwrap();
+        mock.push_exit(0, halt_exit(0xfff2));
+        let exit = vcpu.run().unwrap();
+        assert_eq!(exit.exit, VmExit::Canceled(VpCancelReason::User));
+        assert_eq!(exit.context.rip, 0xfff0);
+        assert_eq!(mock.pending_exits(0), 1);
+
+        // Delivered once.
+        assert_eq!(vcpu.run().unwrap().exit, VmExit::Halt);
+
+        // Requested of the hypervisor while running.
+        mock.cancel_run_virtual_processor(0).unwrap();
+        assert_eq!(
+            vcpu.run().unwrap().exit,
+            VmExit::Canceled(VpCancelReason::User)
+        );
+        assert!(matches!(vcpu.run(), Err(Error::NoScriptedExit(0))));
+    }
+
+    #[test]
+    fn registers() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(1))
+            .unwrap()
+            .setup()
+            .unwrap();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+
+        assert_eq!(
+            vcpu.get_register(Register::Rax).unwrap(),
+            RegisterVal::Reg64(0)
+        );
+        vcpu.set_register(Register::Rax, 0xbeef_u64.into()).unwrap();
+        assert_eq!(
+            mock.register(0, Register::Rax),
+            Some(RegisterVal::Reg64(0xbeef))
+        );
+
+        mock.set_register(0, Register::Rip, RegisterVal::Reg64(0xfff0));
+        let regs = vcpu.get_registers(&[Register::Rax, Register::Rip]).unwrap();
+        assert_eq!(regs[0].1, RegisterVal::Reg64(0xbeef));
+        assert_eq!(regs[1].1, RegisterVal::Reg64(0xfff0));
+    }
+
+    #[test]
+    fn guest_memory() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .setup()
+            .unwrap();
+
+        partition
+            .map_memory_region(MemoryRegion::from_bytes(
+                0x1000,
+                MapGpaRangeFlags::Read | MapGpaRangeFlags::Write,
+                &[0xf4; 0x1000],
+            ))
+            .unwrap();
+        partition
+            .map_memory_region(MemoryRegion::from_bytes(
+                0x2000,
+                MapGpaRangeFlags::Read | MapGpaRangeFlags::Write,
+                &[0x90; 0x1000],
+            ))
+            .unwrap();
+        assert_eq!(mock.mappings().len(), 2);
+
+        let mut buf = [0; 4];
+        mock.read_memory(0x1ffe, &mut buf).unwrap();
+        assert_eq!(buf, [0xf4, 0xf4, 0x90, 0x90]);
+
+        mock.write_memory(0x1fff, &[1, 2]).unwrap();
+        mock.read_memory(0x1ffe, &mut buf).unwrap();
+        assert_eq!(buf, [0xf4, 1, 2, 0x90]);
+
+        assert!(matches!(
+            mock.read_memory(0x2ffe, &mut buf),
+            Err(Error::GpaUnmapped(0x3000))
+        ));
+    }
+
+    #[test]
+    fn translate_gva() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(1))
+            .unwrap()
+            .setup()
+            .unwrap();
+        partition
+            .map_memory_region(MemoryRegion::from_bytes(
+                0,
+                MapGpaRangeFlags::Read | MapGpaRangeFlags::Write,
+                &[0; 0x5000],
+            ))
+            .unwrap();
+        partition
+            .map_memory_region(MemoryRegion::from_bytes(
+                0x5000,
+                MapGpaRangeFlags::Read,
+                &[0; 0x1000],
+            ))
+            .unwrap();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+
+        // Identity map the first 2MiB as a supervisor only, writable large page.
+        mock.write_memory(0x1000, &(0x2000_u64 | 0x7).to_le_bytes())
+            .unwrap();
+        mock.write_memory(0x2000, &(0x3000_u64 | 0x7).to_le_bytes())
+            .unwrap();
+        mock.write_memory(0x3000, &0x83_u64.to_le_bytes()).unwrap();
+        vcpu.set_registers(&[
+            (Register::Cr0, 0x8001_0001_u64.into()),
+            (Register::Cr3, 0x1000_u64.into()),
+            (Register::Cr4, 0x20_u64.into()),
+            (Register::Efer, 0xd00_u64.into()),
+        ])
+        .unwrap();
+
+        let t = vcpu
+            .translate_gva(0x4321, TranslateGvaFlags::ValidateWrite)
+            .unwrap();
+        assert_eq!(t.result_code, TranslateGvaResultCode::Success);
+        assert_eq!(t.gpa, 0x4321);
+
+        let t = vcpu
+            .translate_gva(0x5000, TranslateGvaFlags::ValidateWrite)
+            .unwrap();
+        assert_eq!(t.result_code, TranslateGvaResultCode::GpaNoWriteAccess);
+
+        let t = vcpu
+            .translate_gva(0x6000, TranslateGvaFlags::ValidateRead)
+            .unwrap();
+        assert_eq!(t.result_code, TranslateGvaResultCode::GpaUnmapped);
+
+        let t = vcpu
+            .translate_gva(0x20_0000, TranslateGvaFlags::ValidateRead)
+            .unwrap();
+        assert_eq!(t.result_code, TranslateGvaResultCode::PageNotPresent);
+
+        let cs = SegmentRegister {
+            selector: 0x33,
+            ..Default::default()
+        };
+        vcpu.set_register(Register::Cs, RegisterVal::Segment(cs))
+            .unwrap();
+        let t = vcpu
+            .translate_gva(0x4000, TranslateGvaFlags::ValidateRead)
+            .unwrap();
+        assert_eq!(t.result_code, TranslateGvaResultCode::PrivilegeViolation);
+        let t = vcpu
+            .translate_gva(
+                0x4000,
+                TranslateGvaFlags::ValidateRead | TranslateGvaFlags::PrivilegeExempt,
+            )
+            .unwrap();
+        assert_eq!(t.result_code, TranslateGvaResultCode::Success);
+    }
+}
@@ -0,0 +1,287 @@
+use windows::Win32::{
+    Foundation::WHV_E_INSUFFICIENT_BUFFER,
+    System::Hypervisor::{
+        WHvCancelRunVirtualProcessor, WHvCreatePartition, WHvCreateVirtualProcessor,
+        WHvDeletePartition, WHvDeleteVirtualProcessor, WHvGetPartitionProperty,
+        WHvGetVirtualProcessorInterruptControllerState2, WHvGetVirtualProcessorRegisters,
+        WHvGetVirtualProcessorXsaveState, WHvMapGpaRange, WHvQueryGpaRangeDirtyBitmap,
+        WHvRequestInterrupt, WHvRunVirtualProcessor, WHvSetPartitionProperty,
+        WHvSetVirtualProcessorInterruptControllerState2, WHvSetVirtualProcessorRegisters,
+        WHvSetVirtualProcessorXsaveState, WHvSetupPartition, WHvTranslateGva, WHvUnmapGpaRange,
+        WHV_INTERRUPT_CONTROL, WHV_PARTITION_HANDLE, WHV_PARTITION_PROPERTY, WHV_REGISTER_NAME,
+        WHV_REGISTER_VALUE, WHV_RUN_VP_EXIT_CONTEXT, WHV_TRANSLATE_GVA_RESULT,
+    },
+};
+
+use crate::{
+    apic::{InterruptControl, LAPIC_PAGE_SIZE},
+    flags::TranslateGvaFlags,
+    memory::{DirtyBitmap, MemoryRegion, PAGE_SIZE},
+    partition::{PartitionProperty, PartitionPropertyCode},
+    processor::{GvaTranslation, Register, RegisterVal, RunExitContext},
+    Result,
+};
+
+use super::Backend;
+
+/// A partition created through the Windows Hypervisor Platform.
+#[derive(Debug)]
+pub struct WhpBackend(pub WHV_PARTITION_HANDLE);
+
+impl WhpBackend {
+    pub fn new() -> Result<Self> {
+        let raw_handle = unsafe { WHvCreatePartition()? };
+        Ok(Self(raw_handle))
+    }
+}
+
+impl From<WHV_PARTITION_HANDLE> for WhpBackend {
+    fn from(raw_handle: WHV_PARTITION_HANDLE) -> Self {
+        Self(raw_handle)
+    }
+}
+
+impl Drop for WhpBackend {
+    fn drop(&mut self) {
+        // TODO: Error handling... in drop?
+        let _ = unsafe { WHvDeletePartition(self.0) };
+    }
+}
+
+impl Backend for WhpBackend {
+    fn set_property(&self, prop: PartitionProperty) -> Result<()> {
+        unsafe {
+            WHvSetPartitionProperty(
+                self.0,
+                prop.code().into(),
+                (&WHV_PARTITION_PROPERTY::from(prop) as *const WHV_PARTITION_PROPERTY).cast(),
+                std::mem::size_of::<WHV_PARTITION_PROPERTY>().try_into()?,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn get_property(&self, code: PartitionPropertyCode) -> Result<PartitionProperty> {
+        let mut raw_property: WHV_PARTITION_PROPERTY = Default::default();
+
+        unsafe {
+            WHvGetPartitionProperty(
+                self.0,
+                code.into(),
+                (&mut raw_property as *mut WHV_PARTITION_PROPERTY).cast(),
+                std::mem::size_of::<WHV_PARTITION_PROPERTY>().try_into()?,
+                None,
+            )?;
+        }
+
+        Ok(PartitionProperty::from_union(code, raw_property))
+    }
+
+    fn setup(&self) -> Result<()> {
+        unsafe { WHvSetupPartition(self.0)? };
+        Ok(())
+    }
+
+    fn map_gpa_range(&self, region: &MemoryRegion) -> Result<()> {
+        unsafe {
+            WHvMapGpaRange(
+                self.0,
+                region.address as *const _,
+                region.guest_address.try_into()?,
+                region.size.try_into()?,
+                region.flags.into(),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn unmap_gpa_range(&self, guest_address: u64, size: u64) -> Result<()> {
+        unsafe { WHvUnmapGpaRange(self.0, guest_address, size)? };
+        Ok(())
+    }
+
+    fn query_dirty_bitmap(&self, guest_address: u64, size: u64) -> Result<DirtyBitmap> {
+        let pages = (size as usize).div_ceil(PAGE_SIZE);
+        let mut words = vec![0u64; pages.div_ceil(u64::BITS as usize)];
+        unsafe {
+            WHvQueryGpaRangeDirtyBitmap(
+                self.0,
+                guest_address,
+                size,
+                Some(words.as_mut_ptr()),
+                std::mem::size_of_val(words.as_slice()).try_into()?,
+            )?;
+        }
+        Ok(DirtyBitmap::from_words(guest_address, size, &words))
+    }
+
+    fn request_interrupt(&self, interrupt: &InterruptControl) -> Result<()> {
+        let raw_interrupt = WHV_INTERRUPT_CONTROL::from(*interrupt);
+        unsafe {
+            WHvRequestInterrupt(
+                self.0,
+                &raw_interrupt,
+                std::mem::size_of::<WHV_INTERRUPT_CONTROL>().try_into()?,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn create_virtual_processor(&self, index: u32) -> Result<()> {
+        unsafe { WHvCreateVirtualProcessor(self.0, index, 0)? };
+        Ok(())
+    }
+
+    fn delete_virtual_processor(&self, index: u32) -> Result<()> {
+        unsafe { WHvDeleteVirtualProcessor(self.0, index)? };
+        Ok(())
+    }
+
+    fn run_virtual_processor(&self, index: u32) -> Result<RunExitContext> {
+        let mut raw_exit_context: WHV_RUN_VP_EXIT_CONTEXT = Default::default();
+        unsafe {
+            WHvRunVirtualProcessor(
+                self.0,
+                index,
+                (&mut raw_exit_context as *mut WHV_RUN_VP_EXIT_CONTEXT).cast(),
+                std::mem::size_of::<WHV_RUN_VP_EXIT_CONTEXT>().try_into()?,
+            )?;
+        }
+        Ok(raw_exit_context.into())
+    }
+
+    fn cancel_run_virtual_processor(&self, index: u32) -> Result<()> {
+        unsafe { WHvCancelRunVirtualProcessor(self.0, index, 0)? };
+        Ok(())
+    }
+
+    fn get_registers(&self, index: u32, registers: &[Register]) -> Result<Vec<RegisterVal>> {
+        let raw_registers: Vec<_> = registers
+            .iter()
+            .map(|&r| WHV_REGISTER_NAME::from(r))
+            .collect();
+        let mut raw_values: Vec<WHV_REGISTER_VALUE> = vec![Default::default(); registers.len()];
+
+        unsafe {
+            WHvGetVirtualProcessorRegisters(
+                self.0,
+                index,
+                raw_registers.as_ptr(),
+                raw_registers.len().try_into()?,
+                raw_values.as_mut_ptr(),
+            )?;
+        }
+
+        Ok(registers
+            .iter()
+            .zip(raw_values)
+            .map(|(r, v)| RegisterVal::from_union(r.ty(), v))
+            .collect())
+    }
+
+    fn set_registers(&self, index: u32, register_vals: &[(Register, RegisterVal)]) -> Result<()> {
+        let (raw_registers, raw_values): (Vec<_>, Vec<_>) = register_vals
+            .iter()
+            .map(|(r, v)| (WHV_REGISTER_NAME::from(*r), WHV_REGISTER_VALUE::from(*v)))
+            .unzip();
+
+        unsafe {
+            WHvSetVirtualProcessorRegisters(
+                self.0,
+                index,
+                raw_registers.as_ptr(),
+                raw_registers.len().try_into()?,
+                raw_values.as_ptr(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn get_xsave_state(&self, index: u32) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; 4096];
+        loop {
+            let mut bytes_written = 0;
+            let result = unsafe {
+                WHvGetVirtualProcessorXsaveState(
+                    self.0,
+                    index,
+                    buffer.as_mut_ptr().cast(),
+                    buffer.len().try_into()?,
+                    &mut bytes_written,
+                )
+            };
+            match result {
+                Ok(()) => {
+                    buffer.truncate(bytes_written as usize);
+                    return Ok(buffer);
+                }
+                // The needed size is reported back through `bytes_written`.
+                Err(e)
+                    if e.code() == WHV_E_INSUFFICIENT_BUFFER
+                        && bytes_written as usize > buffer.len() =>
+                {
+                    buffer.resize(bytes_written as usize, 0);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn set_xsave_state(&self, index: u32, state: &[u8]) -> Result<()> {
+        unsafe {
+            WHvSetVirtualProcessorXsaveState(
+                self.0,
+                index,
+                state.as_ptr().cast(),
+                state.len().try_into()?,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn get_lapic_state(&self, index: u32) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; LAPIC_PAGE_SIZE];
+        let mut bytes_written = 0;
+        unsafe {
+            WHvGetVirtualProcessorInterruptControllerState2(
+                self.0,
+                index,
+                buffer.as_mut_ptr().cast(),
+                buffer.len().try_into()?,
+                Some(&mut bytes_written),
+            )?;
+        }
+        buffer.truncate(bytes_written as usize);
+        Ok(buffer)
+    }
+
+    fn set_lapic_state(&self, index: u32, state: &[u8]) -> Result<()> {
+        unsafe {
+            WHvSetVirtualProcessorInterruptControllerState2(
+                self.0,
+                index,
+                state.as_ptr().cast(),
+                state.len().try_into()?,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn translate_gva(
+        &self,
+        index: u32,
+        gva: u64,
+        flags: TranslateGvaFlags,
+    ) -> Result<GvaTranslation> {
+        let mut result = WHV_TRANSLATE_GVA_RESULT::default();
+        let mut gpa = 0;
+        unsafe {
+            WHvTranslateGva(self.0, index, gva, flags.into(), &mut result, &mut gpa)?;
+        }
+        Ok(GvaTranslation {
+            result_code: result.ResultCode.into(),
+            gpa,
+        })
+    }
+}
@@ -0,0 +1,933 @@
+use bitflags::bitflags;
+
+use crate::{
+    flags::{X64ExecutionState, X64SegmentRegisterAttributes},
+    processor::{ExitContext, Register},
+    Error, Result,
+};
+
+/// The longest encoding the processor accepts, exits carry at most 16 bytes.
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// The code segment mode an instruction is decoded in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExecutionMode {
+    /// Real mode, virtual 8086 mode or a 16-bit protected mode code segment.
+    Bits16,
+    Bits32,
+    /// A 64-bit code segment in long mode.
+    Bits64,
+}
+
+impl From<&ExitContext> for ExecutionMode {
+    fn from(context: &ExitContext) -> Self {
+        let attributes = context.cs.attributes;
+        if context.execution_state.contains(X64ExecutionState::EferLma)
+            && attributes.contains(X64SegmentRegisterAttributes::Long)
+        {
+            Self::Bits64
+        } else if attributes.contains(X64SegmentRegisterAttributes::Default) {
+            Self::Bits32
+        } else {
+            Self::Bits16
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Segment {
+    Es,
+    Cs,
+    Ss,
+    Ds,
+    Fs,
+    Gs,
+}
+
+impl Segment {
+    pub fn register(&self) -> Register {
+        match self {
+            Segment::Es => Register::Es,
+            Segment::Cs => Register::Cs,
+            Segment::Ss => Register::Ss,
+            Segment::Ds => Register::Ds,
+            Segment::Fs => Register::Fs,
+            Segment::Gs => Register::Gs,
+        }
+    }
+}
+
+bitflags! {
+    /// The legacy prefixes other than segment overrides.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct Prefixes: u8 {
+        const Lock = 1 << 0;
+        /// `0xf2`, the last of `0xf2` and `0xf3` wins.
+        const Repne = 1 << 1;
+        /// `0xf3`, the last of `0xf2` and `0xf3` wins.
+        const Rep = 1 << 2;
+        const OperandSize = 1 << 3;
+        const AddressSize = 1 << 4;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OpcodeMap {
+    OneByte,
+    /// Opcodes escaped with `0x0f`.
+    TwoByte,
+    /// Opcodes escaped with `0x0f 0x38`.
+    ThreeByte38,
+    /// Opcodes escaped with `0x0f 0x3a`.
+    ThreeByte3A,
+}
+
+/// A general purpose register accessed at the width of an operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RegisterOperand {
+    /// The full 64-bit register.
+    pub register: Register,
+    /// The operand size in bytes.
+    pub size: usize,
+    /// Selects AH, CH, DH or BH rather than the low byte.
+    pub high_byte: bool,
+}
+
+impl RegisterOperand {
+    /// Extract the operand from the full value of the register.
+    pub fn read(&self, value: u64) -> u64 {
+        (value >> (8 * self.high_byte as u32)) & size_mask(self.size)
+    }
+
+    /// Merge `value` into `old`, the full value of the register, the way writing the operand
+    /// does. 8 and 16-bit writes leave the other bits alone while 32-bit writes zero extend.
+    pub fn merge(&self, old: u64, value: u64) -> u64 {
+        match (self.size, self.high_byte) {
+            (_, true) => (old & !0xff00) | (value & 0xff) << 8,
+            (1 | 2, false) => {
+                let mask = size_mask(self.size);
+                (old & !mask) | (value & mask)
+            }
+            (4, false) => value & 0xffff_ffff,
+            _ => value,
+        }
+    }
+}
+
+/// A memory operand, addressed as `segment:base + index * scale + displacement`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MemoryOperand {
+    /// The segment accessed, either the override prefix or the default for the base register.
+    pub segment: Segment,
+    /// [`Register::Rip`] for RIP-relative addressing, relative to the next instruction.
+    pub base: Option<Register>,
+    pub index: Option<Register>,
+    pub scale: u8,
+    pub displacement: i64,
+    /// The address size in bytes, the effective address wraps around at this width.
+    pub address_size: usize,
+    /// The number of bytes accessed by integer instructions, zero when the width depends on the
+    /// x87 or SIMD form.
+    pub size: usize,
+}
+
+impl MemoryOperand {
+    /// The effective address, i.e. the offset into `segment`.
+    ///
+    /// `register` supplies the full value of the base and index registers, with RIP reading as
+    /// the address of the next instruction.
+    pub fn effective_address(&self, mut register: impl FnMut(Register) -> u64) -> u64 {
+        let base = self.base.map_or(0, &mut register);
+        let index = self.index.map_or(0, &mut register);
+        base.wrapping_add(index.wrapping_mul(self.scale as u64))
+            .wrapping_add(self.displacement as u64)
+            & size_mask(self.address_size)
+    }
+}
+
+/// The operand encoded by the ModRM `rm` field, or the address of the `moffs` forms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RmOperand {
+    Register(RegisterOperand),
+    Memory(MemoryOperand),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Immediate {
+    /// The encoded value, zero extended. `enter` and far pointers pack both of their immediates
+    /// with the first one in the low bits.
+    pub value: u64,
+    /// The encoded size in bytes.
+    pub size: usize,
+}
+
+impl Immediate {
+    pub fn sign_extended(&self) -> u64 {
+        let shift = 64 - 8 * self.size.min(8) as u32;
+        ((self.value << shift) as i64 >> shift) as u64
+    }
+}
+
+/// A decoded instruction.
+///
+/// Operands are only typed for the general purpose instructions, the ModRM byte is kept for
+/// everything else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Instruction {
+    /// The encoded length in bytes, including prefixes.
+    pub len: usize,
+    pub mode: ExecutionMode,
+    pub prefixes: Prefixes,
+    pub segment_override: Option<Segment>,
+    /// The REX prefix, zero if there is none.
+    pub rex: u8,
+    pub map: OpcodeMap,
+    pub opcode: u8,
+    pub modrm: Option<u8>,
+    /// The operand size in bytes, one for the byte forms. For `movzx` and `movsx` this is the size
+    /// of the destination.
+    pub operand_size: usize,
+    pub address_size: usize,
+    /// The general purpose register in the ModRM `reg` field.
+    pub register: Option<RegisterOperand>,
+    pub rm: Option<RmOperand>,
+    pub immediate: Option<Immediate>,
+}
+
+impl Instruction {
+    /// Decode the instruction at the start of `bytes`, trailing bytes are ignored.
+    ///
+    /// VEX, EVEX and XOP encoded instructions are not supported.
+    pub fn decode(bytes: &[u8], mode: ExecutionMode) -> Result<Self> {
+        let bytes = &bytes[..bytes.len().min(MAX_INSTRUCTION_LEN)];
+        let invalid = || Error::InvalidInstruction(bytes.to_vec());
+        let mut cursor = Cursor { bytes, pos: 0 };
+
+        let mut prefixes = Prefixes::empty();
+        let mut segment_override = None;
+        let mut rex = 0;
+        let opcode = loop {
+            let b = cursor.byte().ok_or_else(invalid)?;
+            match b {
+                0xf0 => prefixes.insert(Prefixes::Lock),
+                0xf2 => {
+                    prefixes.remove(Prefixes::Rep);
+                    prefixes.insert(Prefixes::Repne);
+                }
+                0xf3 => {
+                    prefixes.remove(Prefixes::Repne);
+                    prefixes.insert(Prefixes::Rep);
+                }
+                0x66 => prefixes.insert(Prefixes::OperandSize),
+                0x67 => prefixes.insert(Prefixes::AddressSize),
+                0x26 => segment_override = Some(Segment::Es),
+                0x2e => segment_override = Some(Segment::Cs),
+                0x36 => segment_override = Some(Segment::Ss),
+                0x3e => segment_override = Some(Segment::Ds),
+                0x64 => segment_override = Some(Segment::Fs),
+                0x65 => segment_override = Some(Segment::Gs),
+                0x40..=0x4f if mode == ExecutionMode::Bits64 => {
+                    rex = b;
+                    continue;
+                }
+                _ => break b,
+            }
+            // A REX prefix only counts right before the opcode.
+            rex = 0;
+        };
+
+        let (map, opcode) = match opcode {
+            0x0f => match cursor.byte().ok_or_else(invalid)? {
+                0x38 => (OpcodeMap::ThreeByte38, cursor.byte().ok_or_else(invalid)?),
+                0x3a => (OpcodeMap::ThreeByte3A, cursor.byte().ok_or_else(invalid)?),
+                b => (OpcodeMap::TwoByte, b),
+            },
+            b => (OpcodeMap::OneByte, b),
+        };
+
+        if map == OpcodeMap::OneByte {
+            let next_is_register = cursor.peek().is_some_and(|b| b >> 6 == 0b11);
+            let vex = match opcode {
+                0xc4 | 0xc5 | 0x62 => mode == ExecutionMode::Bits64 || next_is_register,
+                0x8f => cursor.peek().is_some_and(|b| b & 0x38 != 0),
+                _ => false,
+            };
+            if vex {
+                return Err(Error::UnsupportedInstruction(bytes.to_vec()));
+            }
+            if mode == ExecutionMode::Bits64 && invalid_in_64bit(opcode) {
+                return Err(invalid());
+            }
+        }
+
+        let modrm = if has_modrm(map, opcode) {
+            Some(cursor.byte().ok_or_else(invalid)?)
+        } else {
+            None
+        };
+        let extension = modrm.map(|modrm| (modrm >> 3) & 0x7);
+
+        let operand_override = prefixes.contains(Prefixes::OperandSize);
+        let address_override = prefixes.contains(Prefixes::AddressSize);
+        let word_size = match mode {
+            ExecutionMode::Bits16 => 2 << operand_override as usize,
+            _ => 4 >> operand_override as usize,
+        };
+        let word_size = if mode != ExecutionMode::Bits64 {
+            word_size
+        } else if rex & 0x8 != 0 || (default_64bit(map, opcode) && !operand_override) {
+            8
+        } else {
+            word_size
+        };
+        let address_size = match mode {
+            ExecutionMode::Bits16 => 2 << address_override as usize,
+            ExecutionMode::Bits32 => 4 >> address_override as usize,
+            ExecutionMode::Bits64 => 8 >> address_override as usize,
+        };
+        let operand_size = if byte_op(map, opcode) { 1 } else { word_size };
+
+        let (reg_gp, rm_gp) = gp_operands(map, opcode);
+        let rm_size = match (map, opcode) {
+            _ if !rm_gp => 0,
+            (OpcodeMap::OneByte, 0x63) => operand_size.min(4),
+            (OpcodeMap::OneByte, 0x8c | 0x8e) => 2,
+            (OpcodeMap::TwoByte, 0xb6 | 0xbe) => 1,
+            (OpcodeMap::TwoByte, 0xb7 | 0xbf) => 2,
+            (OpcodeMap::TwoByte, 0x20..=0x23) => word_size.max(4),
+            _ => operand_size,
+        };
+
+        let mut register = None;
+        let mut rm = None;
+        if let Some(modrm) = modrm {
+            let reg = (modrm >> 3) & 0x7 | (rex & 0x4) << 1;
+            if reg_gp {
+                register = Some(gp_operand(reg, operand_size, rex));
+            }
+            if modrm >> 6 == 0b11 {
+                if rm_gp {
+                    let index = modrm & 0x7 | (rex & 0x1) << 3;
+                    rm = Some(RmOperand::Register(gp_operand(index, rm_size, rex)));
+                }
+            } else {
+                let memory = modrm_memory(
+                    &mut cursor,
+                    modrm,
+                    rex,
+                    mode,
+                    address_size,
+                    segment_override,
+                    rm_size,
+                )
+                .ok_or_else(invalid)?;
+                rm = Some(RmOperand::Memory(memory));
+            }
+        } else if map == OpcodeMap::OneByte && (0xa0..=0xa3).contains(&opcode) {
+            // The accumulator forms carry the address instead of a ModRM byte.
+            let displacement = cursor.le(address_size).ok_or_else(invalid)? as i64;
+            rm = Some(RmOperand::Memory(MemoryOperand {
+                segment: segment_override.unwrap_or(Segment::Ds),
+                base: None,
+                index: None,
+                scale: 1,
+                displacement,
+                address_size,
+                size: operand_size,
+            }));
+        }
+
+        let immediate_size = immediate_size(map, opcode, extension, word_size);
+        let immediate = if immediate_size > 0 {
+            let value = cursor.le(immediate_size).ok_or_else(invalid)?;
+            Some(Immediate {
+                value,
+                size: immediate_size,
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            len: cursor.pos,
+            mode,
+            prefixes,
+            segment_override,
+            rex,
+            map,
+            opcode,
+            modrm,
+            operand_size,
+            address_size,
+            register,
+            rm,
+            immediate,
+        })
+    }
+
+    /// The opcode extension in the ModRM `reg` field of group opcodes, i.e. the `/digit`.
+    pub fn extension(&self) -> Option<u8> {
+        self.modrm.map(|modrm| (modrm >> 3) & 0x7)
+    }
+
+    pub fn memory(&self) -> Option<&MemoryOperand> {
+        match &self.rm {
+            Some(RmOperand::Memory(memory)) => Some(memory),
+            _ => None,
+        }
+    }
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn le(&mut self, size: usize) -> Option<u64> {
+        let bytes = self.bytes.get(self.pos..self.pos + size)?;
+        self.pos += size;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, &b| value << 8 | b as u64),
+        )
+    }
+
+    /// Read a sign extended displacement.
+    fn displacement(&mut self, size: usize) -> Option<i64> {
+        let value = self.le(size)?;
+        let shift = 64 - 8 * size as u32;
+        Some((value << shift) as i64 >> shift)
+    }
+}
+
+/// Decode the memory operand of a ModRM byte that does not encode a register.
+fn modrm_memory(
+    cursor: &mut Cursor,
+    modrm: u8,
+    rex: u8,
+    mode: ExecutionMode,
+    address_size: usize,
+    segment_override: Option<Segment>,
+    size: usize,
+) -> Option<MemoryOperand> {
+    let mode_bits = modrm >> 6;
+    let rm = modrm & 0x7;
+    let mut scale = 1;
+
+    let (base, index, displacement) = if address_size == 2 {
+        let (base, index) = match rm {
+            0 => (Some(Register::Rbx), Some(Register::Rsi)),
+            1 => (Some(Register::Rbx), Some(Register::Rdi)),
+            2 => (Some(Register::Rbp), Some(Register::Rsi)),
+            3 => (Some(Register::Rbp), Some(Register::Rdi)),
+            4 => (Some(Register::Rsi), None),
+            5 => (Some(Register::Rdi), None),
+            6 if mode_bits == 0b00 => (None, None),
+            6 => (Some(Register::Rbp), None),
+            _ => (Some(Register::Rbx), None),
+        };
+        let displacement = match mode_bits {
+            0b00 if base.is_none() => cursor.displacement(2)?,
+            0b00 => 0,
+            0b01 => cursor.displacement(1)?,
+            _ => cursor.displacement(2)?,
+        };
+        (base, index, displacement)
+    } else {
+        let mut disp_size = match mode_bits {
+            0b00 => 0,
+            0b01 => 1,
+            _ => 4,
+        };
+        let (base, index) = if rm == 0b100 {
+            let sib = cursor.byte()?;
+            scale = 1 << (sib >> 6);
+            let index = (sib >> 3) & 0x7 | (rex & 0x2) << 2;
+            // An index of RSP means there is none.
+            let index = (index != 0b100).then(|| gp_register(index));
+            let base = if sib & 0x7 == 0b101 && mode_bits == 0b00 {
+                disp_size = 4;
+                None
+            } else {
+                Some(gp_register(sib & 0x7 | (rex & 0x1) << 3))
+            };
+            (base, index)
+        } else if rm == 0b101 && mode_bits == 0b00 {
+            disp_size = 4;
+            let base = (mode == ExecutionMode::Bits64).then_some(Register::Rip);
+            (base, None)
+        } else {
+            (Some(gp_register(rm | (rex & 0x1) << 3)), None)
+        };
+        let displacement = if disp_size > 0 {
+            cursor.displacement(disp_size)?
+        } else {
+            0
+        };
+        (base, index, displacement)
+    };
+
+    let default_segment = match base {
+        Some(Register::Rsp | Register::Rbp) => Segment::Ss,
+        _ => Segment::Ds,
+    };
+    Some(MemoryOperand {
+        segment: segment_override.unwrap_or(default_segment),
+        base,
+        index,
+        scale,
+        displacement,
+        address_size,
+        size,
+    })
+}
+
+pub(crate) fn size_mask(size: usize) -> u64 {
+    match size {
+        8.. => u64::MAX,
+        _ => (1 << (8 * size)) - 1,
+    }
+}
+
+fn gp_operand(index: u8, size: usize, rex: u8) -> RegisterOperand {
+    // Without a REX prefix byte registers 4 to 7 are AH, CH, DH and BH.
+    let high_byte = size == 1 && rex == 0 && (4..8).contains(&index);
+    RegisterOperand {
+        register: gp_register(if high_byte { index - 4 } else { index }),
+        size,
+        high_byte,
+    }
+}
+
+fn gp_register(index: u8) -> Register {
+    match index {
+        0 => Register::Rax,
+        1 => Register::Rcx,
+        2 => Register::Rdx,
+        3 => Register::Rbx,
+        4 => Register::Rsp,
+        5 => Register::Rbp,
+        6 => Register::Rsi,
+        7 => Register::Rdi,
+        8 => Register::R8,
+        9 => Register::R9,
+        10 => Register::R10,
+        11 => Register::R11,
+        12 => Register::R12,
+        13 => Register::R13,
+        14 => Register::R14,
+        15 => Register::R15,
+        _ => unreachable!(),
+    }
+}
+
+fn invalid_in_64bit(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        0x06 | 0x07
+            | 0x0e
+            | 0x16
+            | 0x17
+            | 0x1e
+            | 0x1f
+            | 0x27
+            | 0x2f
+            | 0x37
+            | 0x3f
+            | 0x60
+            | 0x61
+            | 0x82
+            | 0x9a
+            | 0xce
+            | 0xd4
+            | 0xd5
+            | 0xd6
+            | 0xea
+    )
+}
+
+fn has_modrm(map: OpcodeMap, opcode: u8) -> bool {
+    match map {
+        OpcodeMap::OneByte => {
+            matches!(
+                opcode,
+                0x00..=0x3f if opcode & 0x7 < 4
+            ) || matches!(
+                opcode,
+                0x62 | 0x63
+                    | 0x69
+                    | 0x6b
+                    | 0x80..=0x8f
+                    | 0xc0
+                    | 0xc1
+                    | 0xc4..=0xc7
+                    | 0xd0..=0xd3
+                    | 0xd8..=0xdf
+                    | 0xf6
+                    | 0xf7
+                    | 0xfe
+                    | 0xff
+            )
+        }
+        OpcodeMap::TwoByte => !matches!(
+            opcode,
+            0x04..=0x0c
+                | 0x0e
+                | 0x30..=0x37
+                | 0x77
+                | 0x80..=0x8f
+                | 0xa0..=0xa2
+                | 0xa8..=0xaa
+                | 0xc8..=0xcf
+        ),
+        OpcodeMap::ThreeByte38 | OpcodeMap::ThreeByte3A => true,
+    }
+}
+
+/// Instructions operating on bytes regardless of the operand size.
+fn byte_op(map: OpcodeMap, opcode: u8) -> bool {
+    match map {
+        OpcodeMap::OneByte => {
+            matches!(opcode, 0x00..=0x3f if matches!(opcode & 0x7, 0 | 2 | 4))
+                || matches!(
+                    opcode,
+                    0x6c | 0x6e
+                        | 0x80
+                        | 0x82
+                        | 0x84
+                        | 0x86
+                        | 0x88
+                        | 0x8a
+                        | 0xa0
+                        | 0xa2
+                        | 0xa4
+                        | 0xa6
+                        | 0xa8
+                        | 0xaa
+                        | 0xac
+                        | 0xae
+                        | 0xb0
+                        ..=0xb7
+                            | 0xc0
+                            | 0xc6
+                            | 0xd0
+                            | 0xd2
+                            | 0xe4
+                            | 0xe6
+                            | 0xec
+                            | 0xee
+                            | 0xf6
+                            | 0xfe
+                )
+        }
+        OpcodeMap::TwoByte => matches!(opcode, 0x90..=0x9f | 0xb0 | 0xc0),
+        _ => false,
+    }
+}
+
+/// Instructions defaulting to a 64-bit operand size in long mode, mostly stack and branch ones.
+fn default_64bit(map: OpcodeMap, opcode: u8) -> bool {
+    match map {
+        OpcodeMap::OneByte => matches!(
+            opcode,
+            0x50..=0x5f
+                | 0x68
+                | 0x6a
+                | 0x70..=0x7f
+                | 0x8f
+                | 0x9c
+                | 0x9d
+                | 0xc2
+                | 0xc3
+                | 0xc8
+                | 0xc9
+                | 0xe0..=0xe3
+                | 0xe8
+                | 0xe9
+                | 0xeb
+                | 0xff
+        ),
+        OpcodeMap::TwoByte => matches!(opcode, 0x80..=0x8f | 0xa0 | 0xa1 | 0xa8 | 0xa9),
+        _ => false,
+    }
+}
+
+/// Whether the ModRM `reg` and `rm` fields encode general purpose registers.
+fn gp_operands(map: OpcodeMap, opcode: u8) -> (bool, bool) {
+    match (map, opcode) {
+        (
+            OpcodeMap::OneByte,
+            0x00..=0x3f | 0x62 | 0x63 | 0x69 | 0x6b | 0x84..=0x8b | 0x8d | 0xc4 | 0xc5,
+        ) => (true, true),
+        (
+            OpcodeMap::OneByte,
+            0x80..=0x83
+            | 0x8c
+            | 0x8e
+            | 0x8f
+            | 0xc0
+            | 0xc1
+            | 0xc6
+            | 0xc7
+            | 0xd0..=0xd3
+            | 0xf6
+            | 0xf7
+            | 0xfe
+            | 0xff,
+        ) => (false, true),
+        (
+            OpcodeMap::TwoByte,
+            0x02
+            | 0x03
+            | 0x40..=0x4f
+            | 0xa3..=0xa5
+            | 0xab..=0xad
+            | 0xaf
+            | 0xb0..=0xb9
+            | 0xbb..=0xbf
+            | 0xc0
+            | 0xc1
+            | 0xc3,
+        ) => (true, true),
+        (
+            OpcodeMap::TwoByte,
+            0x00 | 0x01 | 0x0d | 0x18..=0x23 | 0x90..=0x9f | 0xae | 0xba | 0xc7,
+        ) => (false, true),
+        (OpcodeMap::ThreeByte38, 0xf0 | 0xf1) => (true, true),
+        _ => (false, false),
+    }
+}
+
+/// The size of the immediate, `word_size` being the operand size of the non-byte forms.
+fn immediate_size(map: OpcodeMap, opcode: u8, extension: Option<u8>, word_size: usize) -> usize {
+    // 16 or 32 bits, a 64-bit operand takes a sign extended 32-bit immediate.
+    let iz = if word_size == 2 { 2 } else { 4 };
+    match map {
+        OpcodeMap::OneByte => match opcode {
+            0x00..=0x3f if opcode & 0x7 == 4 => 1,
+            0x00..=0x3f if opcode & 0x7 == 5 => iz,
+            0x68 | 0x69 | 0x81 | 0xa9 | 0xc7 | 0xe8 | 0xe9 => iz,
+            0x6a
+            | 0x6b
+            | 0x70..=0x7f
+            | 0x80
+            | 0x82
+            | 0x83
+            | 0xa8
+            | 0xb0..=0xb7
+            | 0xc0
+            | 0xc1
+            | 0xc6
+            | 0xcd
+            | 0xd4
+            | 0xd5
+            | 0xe0..=0xe7
+            | 0xeb => 1,
+            0xb8..=0xbf => word_size,
+            0xc2 | 0xca => 2,
+            // enter imm16, imm8
+            0xc8 => 3,
+            // Far pointers, offset then selector.
+            0x9a | 0xea => iz + 2,
+            0xf6 if extension < Some(2) => 1,
+            0xf7 if extension < Some(2) => iz,
+            _ => 0,
+        },
+        OpcodeMap::TwoByte => match opcode {
+            0x0f | 0x70..=0x73 | 0xa4 | 0xac | 0xba | 0xc2 | 0xc4..=0xc6 => 1,
+            0x80..=0x8f => iz,
+            _ => 0,
+        },
+        OpcodeMap::ThreeByte38 => 0,
+        OpcodeMap::ThreeByte3A => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{processor::Register, Error};
+
+    use super::{
+        ExecutionMode, Immediate, Instruction, MemoryOperand, OpcodeMap, Prefixes, RegisterOperand,
+        RmOperand, Segment,
+    };
+
+    fn reg(register: Register, size: usize) -> RegisterOperand {
+        RegisterOperand {
+            register,
+            size,
+            high_byte: false,
+        }
+    }
+
+    fn decode64(bytes: &[u8]) -> Instruction {
+        Instruction::decode(bytes, ExecutionMode::Bits64).unwrap()
+    }
+
+    #[test]
+    fn long_mode() {
+        // mov r9, qword [rax+rbx*4+0x12345678]
+        let i = decode64(&[0x4c, 0x8b, 0x8c, 0x98, 0x78, 0x56, 0x34, 0x12]);
+        assert_eq!((i.len, i.operand_size, i.address_size), (8, 8, 8));
+        assert_eq!(i.register, Some(reg(Register::R9, 8)));
+        assert_eq!(
+            i.rm,
+            Some(RmOperand::Memory(MemoryOperand {
+                segment: Segment::Ds,
+                base: Some(Register::Rax),
+                index: Some(Register::Rbx),
+                scale: 4,
+                displacement: 0x12345678,
+                address_size: 8,
+                size: 8,
+            }))
+        );
+
+        // mov word [rip-0x10], 0xbeef
+        let i = decode64(&[0x66, 0xc7, 0x05, 0xf0, 0xff, 0xff, 0xff, 0xef, 0xbe, 0x90]);
+        assert_eq!((i.len, i.operand_size), (9, 2));
+        assert_eq!(i.prefixes, Prefixes::OperandSize);
+        let memory = i.memory().unwrap();
+        assert_eq!(
+            (memory.base, memory.displacement),
+            (Some(Register::Rip), -0x10)
+        );
+        assert_eq!(memory.effective_address(|_| 0x1009), 0xff9);
+        assert_eq!(
+            i.immediate,
+            Some(Immediate {
+                value: 0xbeef,
+                size: 2
+            })
+        );
+
+        // movzx eax, byte [rbp+r12*2-1]
+        let i = decode64(&[0x42, 0x0f, 0xb6, 0x44, 0x65, 0xff]);
+        assert_eq!(
+            (i.map, i.opcode, i.len, i.operand_size),
+            (OpcodeMap::TwoByte, 0xb6, 6, 4)
+        );
+        let memory = i.memory().unwrap();
+        assert_eq!(
+            (memory.segment, memory.index, memory.size),
+            (Segment::Ss, Some(Register::R12), 1)
+        );
+
+        // mov byte [rbx], ah and mov byte [rbx], spl
+        let i = decode64(&[0x88, 0x23]);
+        assert_eq!(
+            i.register,
+            Some(RegisterOperand {
+                register: Register::Rax,
+                size: 1,
+                high_byte: true
+            })
+        );
+        let i = decode64(&[0x40, 0x88, 0x23]);
+        assert_eq!(i.register, Some(reg(Register::Rsp, 1)));
+
+        // lock or qword gs:[r8], -2
+        let i = decode64(&[0xf0, 0x65, 0x49, 0x83, 0x08, 0xfe]);
+        assert_eq!(i.extension(), Some(1));
+        assert_eq!(i.segment_override, Some(Segment::Gs));
+        assert_eq!(i.memory().unwrap().base, Some(Register::R8));
+        assert_eq!(i.immediate.unwrap().sign_extended(), -2_i64 as u64);
+
+        // mov rax, 0x1122334455667788 and push rbx
+        let i = decode64(&[0x48, 0xb8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]);
+        assert_eq!(i.immediate.unwrap().value, 0x1122334455667788);
+        assert_eq!(
+            (decode64(&[0x53]).operand_size, decode64(&[0x53]).len),
+            (8, 1)
+        );
+
+        // add eax, ecx leaves the register in rm.
+        let i = decode64(&[0x01, 0xc8]);
+        assert_eq!(i.rm, Some(RmOperand::Register(reg(Register::Rax, 4))));
+        assert_eq!(i.register, Some(reg(Register::Rcx, 4)));
+
+        // stosd and the moffs form
+        assert_eq!(decode64(&[0xf3, 0xab]).prefixes, Prefixes::Rep);
+        let i = decode64(&[0xa1, 0x00, 0x10, 0xe0, 0xfe, 0, 0, 0, 0]);
+        assert_eq!((i.len, i.memory().unwrap().displacement), (9, 0xfee0_1000));
+
+        // A REX prefix followed by a legacy prefix is ignored.
+        assert_eq!(decode64(&[0x48, 0x66, 0x89, 0x00]).operand_size, 2);
+    }
+
+    #[test]
+    fn legacy_modes() {
+        // mov ax, [bp+si+0x10]
+        let i = Instruction::decode(&[0x8b, 0x42, 0x10], ExecutionMode::Bits16).unwrap();
+        assert_eq!((i.len, i.operand_size, i.address_size), (3, 2, 2));
+        assert_eq!(
+            i.rm,
+            Some(RmOperand::Memory(MemoryOperand {
+                segment: Segment::Ss,
+                base: Some(Register::Rbp),
+                index: Some(Register::Rsi),
+                scale: 1,
+                displacement: 0x10,
+                address_size: 2,
+                size: 2,
+            }))
+        );
+        assert_eq!(
+            i.memory().unwrap().effective_address(|r| match r {
+                Register::Rbp => 0xfff0,
+                _ => 0x10,
+            }),
+            0x10
+        );
+
+        // mov eax, [0x1234] with 32-bit operand and address overrides
+        let i = Instruction::decode(&[0x66, 0x67, 0xa1, 0x34, 0x12, 0, 0], ExecutionMode::Bits16)
+            .unwrap();
+        assert_eq!((i.len, i.operand_size, i.address_size), (7, 4, 4));
+
+        // In 32-bit mode disp32 without a base is absolute and 0x40 is inc eax.
+        let i = Instruction::decode(&[0x89, 0x05, 0, 0x10, 0, 0], ExecutionMode::Bits32).unwrap();
+        assert_eq!(i.memory().unwrap().base, None);
+        let i = Instruction::decode(&[0x40], ExecutionMode::Bits32).unwrap();
+        assert_eq!((i.opcode, i.len), (0x40, 1));
+    }
+
+    #[test]
+    fn invalid() {
+        // Truncated
+        assert!(matches!(
+            Instruction::decode(&[0x8b, 0x84], ExecutionMode::Bits64),
+            Err(Error::InvalidInstruction(_))
+        ));
+        // Invalid in long mode
+        assert!(matches!(
+            Instruction::decode(&[0x06], ExecutionMode::Bits64),
+            Err(Error::InvalidInstruction(_))
+        ));
+        // Longer than 15 bytes
+        assert!(matches!(
+            Instruction::decode(&[0x66; 16], ExecutionMode::Bits64),
+            Err(Error::InvalidInstruction(_))
+        ));
+        // vmovdqu ymm0, [rax]
+        assert!(matches!(
+            Instruction::decode(&[0xc5, 0xfe, 0x6f, 0x00], ExecutionMode::Bits64),
+            Err(Error::UnsupportedInstruction(_))
+        ));
+        // les ax, [bx] is fine outside of long mode.
+        assert!(Instruction::decode(&[0xc4, 0x07], ExecutionMode::Bits16).is_ok());
+    }
+}
@@ -0,0 +1,602 @@
+#[cfg(windows)]
+use std::{ffi::c_void, marker::PhantomData};
+
+#[cfg(windows)]
+use windows::{
+    core::HRESULT,
+    Win32::{
+        Foundation::{E_FAIL, E_INVALIDARG, S_OK},
+        System::Hypervisor::{
+            WHvEmulatorCreateEmulator, WHvEmulatorDestroyEmulator, WHvEmulatorTryIoEmulation,
+            WHvEmulatorTryMmioEmulation, WHV_EMULATOR_CALLBACKS, WHV_EMULATOR_IO_ACCESS_INFO,
+            WHV_EMULATOR_MEMORY_ACCESS_INFO, WHV_MEMORY_ACCESS_CONTEXT, WHV_REGISTER_NAME,
+            WHV_REGISTER_VALUE, WHV_TRANSLATE_GVA_FLAGS, WHV_TRANSLATE_GVA_RESULT_CODE,
+            WHV_VP_EXIT_CONTEXT, WHV_X64_IO_PORT_ACCESS_CONTEXT,
+        },
+    },
+};
+
+#[cfg(windows)]
+use crate::{
+    flags::EmulatorStatus,
+    processor::{ExitContext, IoPortAccessContext, MemoryAccessContext},
+    Error,
+};
+use crate::{
+    flags::TranslateGvaFlags,
+    mmio::MmioBus,
+    pio::PortIoBus,
+    processor::{GvaTranslation, Register, RegisterVal, VirtualProcessor},
+    Result,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum AccessDirection {
+    Read = 0x0,
+    Write = 0x1,
+}
+
+impl AccessDirection {
+    pub const fn from_raw(value: u8) -> Option<Self> {
+        match value {
+            0x0 => Some(Self::Read),
+            0x1 => Some(Self::Write),
+            _ => None,
+        }
+    }
+}
+
+/// A port access made by the emulator, reads are completed by filling in `data`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmulatorIoAccess {
+    pub direction: AccessDirection,
+    pub port: u16,
+    /// 1, 2 or 4 bytes.
+    pub access_size: u16,
+    pub data: u32,
+}
+
+#[cfg(windows)]
+impl EmulatorIoAccess {
+    /// `None` if the direction is neither a read nor a write.
+    pub fn from_raw(value: WHV_EMULATOR_IO_ACCESS_INFO) -> Option<Self> {
+        Some(Self {
+            direction: AccessDirection::from_raw(value.Direction)?,
+            port: value.Port,
+            access_size: value.AccessSize,
+            data: value.Data,
+        })
+    }
+}
+
+#[cfg(windows)]
+impl From<EmulatorIoAccess> for WHV_EMULATOR_IO_ACCESS_INFO {
+    fn from(value: EmulatorIoAccess) -> Self {
+        Self {
+            Direction: value.direction as u8,
+            Port: value.port,
+            AccessSize: value.access_size,
+            Data: value.data,
+        }
+    }
+}
+
+/// A guest physical memory access made by the emulator, reads are completed by filling in the
+/// first `access_size` bytes of `data`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmulatorMemoryAccess {
+    pub gpa: u64,
+    pub direction: AccessDirection,
+    pub access_size: u8,
+    pub data: [u8; 8],
+}
+
+#[cfg(windows)]
+impl EmulatorMemoryAccess {
+    /// `None` if the direction is neither a read nor a write.
+    pub fn from_raw(value: WHV_EMULATOR_MEMORY_ACCESS_INFO) -> Option<Self> {
+        Some(Self {
+            gpa: value.GpaAddress,
+            direction: AccessDirection::from_raw(value.Direction)?,
+            access_size: value.AccessSize,
+            data: value.Data,
+        })
+    }
+}
+
+#[cfg(windows)]
+impl From<EmulatorMemoryAccess> for WHV_EMULATOR_MEMORY_ACCESS_INFO {
+    fn from(value: EmulatorMemoryAccess) -> Self {
+        Self {
+            GpaAddress: value.gpa,
+            Direction: value.direction as u8,
+            AccessSize: value.access_size,
+            Data: value.data,
+        }
+    }
+}
+
+/// What the emulator calls back into while completing an instruction, any error fails the
+/// emulation with the matching [`crate::flags::EmulatorStatus`] flag.
+pub trait EmulatorCallbacks {
+    fn io_port(&mut self, access: &mut EmulatorIoAccess) -> Result<()>;
+
+    fn memory(&mut self, access: &mut EmulatorMemoryAccess) -> Result<()>;
+
+    fn get_registers(&mut self, registers: &[Register]) -> Result<Vec<RegisterVal>>;
+
+    fn set_registers(&mut self, register_vals: &[(Register, RegisterVal)]) -> Result<()>;
+
+    fn translate_gva_page(&mut self, gva: u64, flags: TranslateGvaFlags) -> Result<GvaTranslation>;
+}
+
+/// Callbacks completing accesses against the virtual processor and the crate's buses.
+///
+/// Memory accesses only reach the [`MmioBus`], instructions touching guest RAM (e.g. `rep outsb`)
+/// need callbacks that can also read and write the partition's memory.
+#[derive(Debug)]
+pub struct BusCallbacks<'a> {
+    pub vcpu: &'a mut VirtualProcessor,
+    pub mmio: &'a mut MmioBus,
+    pub pio: &'a mut PortIoBus,
+}
+
+impl EmulatorCallbacks for BusCallbacks<'_> {
+    fn io_port(&mut self, access: &mut EmulatorIoAccess) -> Result<()> {
+        let mut data = access.data.to_le_bytes();
+        let data_slice = &mut data[..(access.access_size as usize).min(4)];
+        match access.direction {
+            AccessDirection::Read => {
+                self.pio.io_read(access.port, data_slice)?;
+                access.data = u32::from_le_bytes(data);
+            }
+            AccessDirection::Write => self.pio.io_write(access.port, data_slice)?,
+        }
+        Ok(())
+    }
+
+    fn memory(&mut self, access: &mut EmulatorMemoryAccess) -> Result<()> {
+        let size = (access.access_size as usize).min(access.data.len());
+        match access.direction {
+            AccessDirection::Read => self.mmio.read(access.gpa, &mut access.data[..size]),
+            AccessDirection::Write => self.mmio.write(access.gpa, &access.data[..size]),
+        }
+    }
+
+    fn get_registers(&mut self, registers: &[Register]) -> Result<Vec<RegisterVal>> {
+        Ok(self
+            .vcpu
+            .get_registers(registers)?
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect())
+    }
+
+    fn set_registers(&mut self, register_vals: &[(Register, RegisterVal)]) -> Result<()> {
+        self.vcpu.set_registers(register_vals)
+    }
+
+    fn translate_gva_page(&mut self, gva: u64, flags: TranslateGvaFlags) -> Result<GvaTranslation> {
+        self.vcpu.translate_gva(gva, flags)
+    }
+}
+
+/// The instruction emulator shipped with the Windows Hypervisor Platform (`WinHvEmulation.dll`).
+///
+/// The emulator is created for one type of callbacks, a fresh `C` is handed to every emulation
+/// attempt. Callbacks must not panic, unwinding out of them aborts the process.
+#[cfg(windows)]
+#[derive(Debug)]
+pub struct Emulator<C: EmulatorCallbacks> {
+    handle: *mut c_void,
+    _callbacks: PhantomData<fn(&mut C)>,
+}
+
+#[cfg(windows)]
+impl<C: EmulatorCallbacks> Emulator<C> {
+    pub fn new() -> Result<Self> {
+        let callbacks = WHV_EMULATOR_CALLBACKS {
+            Size: std::mem::size_of::<WHV_EMULATOR_CALLBACKS>().try_into()?,
+            Reserved: 0,
+            WHvEmulatorIoPortCallback: Some(io_port_callback::<C>),
+            WHvEmulatorMemoryCallback: Some(memory_callback::<C>),
+            WHvEmulatorGetVirtualProcessorRegisters: Some(get_registers_callback::<C>),
+            WHvEmulatorSetVirtualProcessorRegisters: Some(set_registers_callback::<C>),
+            WHvEmulatorTranslateGvaPage: Some(translate_gva_page_callback::<C>),
+        };
+        let mut handle = std::ptr::null_mut();
+        unsafe { WHvEmulatorCreateEmulator(&callbacks, &mut handle)? };
+        Ok(Self {
+            handle,
+            _callbacks: PhantomData,
+        })
+    }
+
+    /// Emulate the `in`, `out`, `ins` or `outs` behind an I/O port exit.
+    pub fn try_io_emulation(
+        &self,
+        callbacks: &mut C,
+        context: &ExitContext,
+        access: &IoPortAccessContext,
+    ) -> Result<EmulatorStatus> {
+        let vp_context = WHV_VP_EXIT_CONTEXT::from(*context);
+        let access = WHV_X64_IO_PORT_ACCESS_CONTEXT::from(*access);
+        // SAFETY: `callbacks` is only used by the callbacks for the duration of the call.
+        let status = unsafe {
+            WHvEmulatorTryIoEmulation(
+                self.handle,
+                callbacks as *mut C as *const c_void,
+                &vp_context,
+                &access,
+            )?
+        };
+        Ok(status.into())
+    }
+
+    /// Emulate the instruction behind a memory access exit.
+    pub fn try_mmio_emulation(
+        &self,
+        callbacks: &mut C,
+        context: &ExitContext,
+        access: &MemoryAccessContext,
+    ) -> Result<EmulatorStatus> {
+        let vp_context = WHV_VP_EXIT_CONTEXT::from(*context);
+        let access = WHV_MEMORY_ACCESS_CONTEXT::from(*access);
+        // SAFETY: `callbacks` is only used by the callbacks for the duration of the call.
+        let status = unsafe {
+            WHvEmulatorTryMmioEmulation(
+                self.handle,
+                callbacks as *mut C as *const c_void,
+                &vp_context,
+                &access,
+            )?
+        };
+        Ok(status.into())
+    }
+}
+
+#[cfg(windows)]
+impl<C: EmulatorCallbacks> Drop for Emulator<C> {
+    fn drop(&mut self) {
+        let _ = unsafe { WHvEmulatorDestroyEmulator(self.handle) };
+    }
+}
+
+#[cfg(windows)]
+fn to_hresult(result: Result<()>) -> HRESULT {
+    match result {
+        Ok(()) => S_OK,
+        Err(Error::Windows(err)) => err.code(),
+        Err(_) => E_FAIL,
+    }
+}
+
+// SAFETY (all callbacks): `context` is the `&mut C` passed to `try_*_emulation` and the pointers
+// are valid for the counts the emulator passes along.
+//
+// Unwinding out of a callback aborts the process, so values the crate has no representation for
+// fail the callback with `E_INVALIDARG` instead of panicking in a conversion.
+
+#[cfg(windows)]
+unsafe extern "system" fn io_port_callback<C: EmulatorCallbacks>(
+    context: *const c_void,
+    io_access: *mut WHV_EMULATOR_IO_ACCESS_INFO,
+) -> HRESULT {
+    let callbacks = &mut *(context as *mut C);
+    let Some(mut access) = EmulatorIoAccess::from_raw(*io_access) else {
+        return E_INVALIDARG;
+    };
+    let result = callbacks.io_port(&mut access);
+    *io_access = access.into();
+    to_hresult(result)
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn memory_callback<C: EmulatorCallbacks>(
+    context: *const c_void,
+    memory_access: *mut WHV_EMULATOR_MEMORY_ACCESS_INFO,
+) -> HRESULT {
+    let callbacks = &mut *(context as *mut C);
+    let Some(mut access) = EmulatorMemoryAccess::from_raw(*memory_access) else {
+        return E_INVALIDARG;
+    };
+    let result = callbacks.memory(&mut access);
+    *memory_access = access.into();
+    to_hresult(result)
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn get_registers_callback<C: EmulatorCallbacks>(
+    context: *const c_void,
+    register_names: *const WHV_REGISTER_NAME,
+    register_count: u32,
+    register_values: *mut WHV_REGISTER_VALUE,
+) -> HRESULT {
+    let callbacks = &mut *(context as *mut C);
+    let Some(registers) = std::slice::from_raw_parts(register_names, register_count as _)
+        .iter()
+        .map(|name| Register::from_raw(name.0 as u32))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return E_INVALIDARG;
+    };
+    let values = std::slice::from_raw_parts_mut(register_values, register_count as _);
+    match callbacks.get_registers(&registers) {
+        Ok(register_vals) if register_vals.len() == values.len() => {
+            for (value, register_val) in values.iter_mut().zip(register_vals) {
+                *value = register_val.into();
+            }
+            S_OK
+        }
+        Ok(_) => E_FAIL,
+        Err(err) => to_hresult(Err(err)),
+    }
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn set_registers_callback<C: EmulatorCallbacks>(
+    context: *const c_void,
+    register_names: *const WHV_REGISTER_NAME,
+    register_count: u32,
+    register_values: *const WHV_REGISTER_VALUE,
+) -> HRESULT {
+    let callbacks = &mut *(context as *mut C);
+    let names = std::slice::from_raw_parts(register_names, register_count as _);
+    let values = std::slice::from_raw_parts(register_values, register_count as _);
+    let Some(register_vals) = names
+        .iter()
+        .zip(values)
+        .map(|(name, &value)| {
+            let register = Register::from_raw(name.0 as u32)?;
+            Some((register, RegisterVal::from_union(register.ty(), value)))
+        })
+        .collect::<Option<Vec<_>>>()
+    else {
+        return E_INVALIDARG;
+    };
+    to_hresult(callbacks.set_registers(&register_vals))
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn translate_gva_page_callback<C: EmulatorCallbacks>(
+    context: *const c_void,
+    gva: u64,
+    translate_flags: WHV_TRANSLATE_GVA_FLAGS,
+    translation_result: *mut WHV_TRANSLATE_GVA_RESULT_CODE,
+    gpa: *mut u64,
+) -> HRESULT {
+    let callbacks = &mut *(context as *mut C);
+    to_hresult(
+        callbacks
+            .translate_gva_page(gva, translate_flags.into())
+            .map(|translation| {
+                *translation_result = translation.result_code.into();
+                *gpa = translation.gpa;
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(windows)]
+    use std::ffi::c_void;
+    use std::sync::Arc;
+
+    #[cfg(windows)]
+    use windows::Win32::{
+        Foundation::{E_FAIL, E_INVALIDARG},
+        System::Hypervisor::{
+            WHV_EMULATOR_IO_ACCESS_INFO, WHV_EMULATOR_MEMORY_ACCESS_INFO, WHV_REGISTER_NAME,
+            WHV_REGISTER_VALUE,
+        },
+    };
+
+    use crate::{
+        backend::MockBackend,
+        flags::{MapGpaRangeFlags, TranslateGvaFlags},
+        memory::MemoryRegion,
+        mmio::{MmioBus, MmioHandler},
+        partition::{PartitionBuilder, PartitionProperty},
+        pio::{PortIoBus, PortIoHandler},
+        processor::{Register, RegisterVal, TranslateGvaResultCode},
+        Error,
+    };
+    #[cfg(windows)]
+    use crate::{processor::GvaTranslation, Result};
+
+    #[cfg(windows)]
+    use super::{
+        get_registers_callback, io_port_callback, memory_callback, set_registers_callback,
+    };
+    use super::{
+        AccessDirection, BusCallbacks, EmulatorCallbacks, EmulatorIoAccess, EmulatorMemoryAccess,
+    };
+
+    /// Echoes the offset or port back on reads and ignores writes.
+    struct Echo;
+
+    impl MmioHandler for Echo {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            data.fill(offset as u8);
+        }
+
+        fn write(&mut self, _offset: u64, _data: &[u8]) {}
+    }
+
+    impl PortIoHandler for Echo {
+        fn io_read(&mut self, port: u16, data: &mut [u8]) {
+            data.fill(port as u8);
+        }
+
+        fn io_write(&mut self, _port: u16, _data: &[u8]) {}
+    }
+
+    #[test]
+    fn bus_callbacks() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(1))
+            .unwrap()
+            .setup()
+            .unwrap();
+        partition
+            .map_memory_region(MemoryRegion::from_bytes(
+                0,
+                MapGpaRangeFlags::Read,
+                &[0; 0x1000],
+            ))
+            .unwrap();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+        let mut mmio = MmioBus::new();
+        mmio.register(0xfee0_0000, 0x1000, Box::new(Echo)).unwrap();
+        let mut pio = PortIoBus::default();
+        pio.register(0x60, 0x10, Box::new(Echo)).unwrap();
+
+        let mut callbacks = BusCallbacks {
+            vcpu: &mut vcpu,
+            mmio: &mut mmio,
+            pio: &mut pio,
+        };
+        let callbacks: &mut dyn EmulatorCallbacks = &mut callbacks;
+
+        let mut io = EmulatorIoAccess {
+            direction: AccessDirection::Read,
+            port: 0x64,
+            access_size: 2,
+            data: 0xdead_beef,
+        };
+        callbacks.io_port(&mut io).unwrap();
+        assert_eq!(io.data, 0xdead_0404);
+
+        let mut memory = EmulatorMemoryAccess {
+            gpa: 0xfee0_0020,
+            direction: AccessDirection::Read,
+            access_size: 4,
+            data: [0xff; 8],
+        };
+        callbacks.memory(&mut memory).unwrap();
+        assert_eq!(
+            memory.data,
+            [0x20, 0x20, 0x20, 0x20, 0xff, 0xff, 0xff, 0xff]
+        );
+        memory.gpa = 0x1000;
+        memory.direction = AccessDirection::Write;
+        assert!(matches!(
+            callbacks.memory(&mut memory),
+            Err(Error::MmioUnclaimed(0x1000))
+        ));
+
+        callbacks
+            .set_registers(&[(Register::Rip, 0x7c00_u64.into())])
+            .unwrap();
+        assert_eq!(
+            callbacks.get_registers(&[Register::Rip]).unwrap(),
+            [RegisterVal::Reg64(0x7c00)]
+        );
+        assert_eq!(
+            mock.register(0, Register::Rip),
+            Some(RegisterVal::Reg64(0x7c00))
+        );
+
+        // Paging is off, so the page translates to itself.
+        let translation = callbacks
+            .translate_gva_page(0x234, TranslateGvaFlags::ValidateRead)
+            .unwrap();
+        assert_eq!(translation.result_code, TranslateGvaResultCode::Success);
+        assert_eq!(translation.gpa, 0x234);
+    }
+
+    #[test]
+    fn access_direction() {
+        assert_eq!(AccessDirection::from_raw(0), Some(AccessDirection::Read));
+        assert_eq!(AccessDirection::from_raw(1), Some(AccessDirection::Write));
+        assert_eq!(AccessDirection::from_raw(2), None);
+    }
+
+    /// Hands back no register values at all.
+    #[cfg(windows)]
+    struct Forgetful;
+
+    #[cfg(windows)]
+    impl EmulatorCallbacks for Forgetful {
+        fn io_port(&mut self, _access: &mut EmulatorIoAccess) -> Result<()> {
+            Ok(())
+        }
+
+        fn memory(&mut self, _access: &mut EmulatorMemoryAccess) -> Result<()> {
+            Ok(())
+        }
+
+        fn get_registers(&mut self, _registers: &[Register]) -> Result<Vec<RegisterVal>> {
+            Ok(Vec::new())
+        }
+
+        fn set_registers(&mut self, _register_vals: &[(Register, RegisterVal)]) -> Result<()> {
+            Ok(())
+        }
+
+        fn translate_gva_page(
+            &mut self,
+            gva: u64,
+            _flags: TranslateGvaFlags,
+        ) -> Result<GvaTranslation> {
+            Err(Error::GpaUnmapped(gva))
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn malformed_callbacks() {
+        let mut forgetful = Forgetful;
+        let context = &mut forgetful as *mut Forgetful as *const c_void;
+        let names = [
+            WHV_REGISTER_NAME(Register::Rip as i32),
+            WHV_REGISTER_NAME(0x7fff_ffff),
+        ];
+        let mut values = [WHV_REGISTER_VALUE::default(); 2];
+
+        // SAFETY: The pointers are valid for the counts passed.
+        unsafe {
+            assert_eq!(
+                get_registers_callback::<Forgetful>(
+                    context,
+                    names.as_ptr(),
+                    2,
+                    values.as_mut_ptr()
+                ),
+                E_INVALIDARG
+            );
+            assert_eq!(
+                get_registers_callback::<Forgetful>(
+                    context,
+                    names.as_ptr(),
+                    1,
+                    values.as_mut_ptr()
+                ),
+                E_FAIL
+            );
+            assert_eq!(
+                set_registers_callback::<Forgetful>(context, names.as_ptr(), 2, values.as_ptr()),
+                E_INVALIDARG
+            );
+
+            let mut io = WHV_EMULATOR_IO_ACCESS_INFO {
+                Direction: 2,
+                ..Default::default()
+            };
+            assert_eq!(
+                io_port_callback::<Forgetful>(context, &mut io),
+                E_INVALIDARG
+            );
+            let mut memory = WHV_EMULATOR_MEMORY_ACCESS_INFO {
+                Direction: 2,
+                ..Default::default()
+            };
+            assert_eq!(
+                memory_callback::<Forgetful>(context, &mut memory),
+                E_INVALIDARG
+            );
+        }
+    }
+}
@@ -0,0 +1,448 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    processor::{
+        ApicEoiContext, ApicSmiContext, CpuidAccessContext, ExitContext, HypercallContext,
+        InterruptionDeliverableContext, IoPortAccessContext, MemoryAccessContext, MsrAccessContext,
+        RdtscContext, RunExitContext, RunExitReason, SynicSintDeliverableContext,
+        UnsupportedFeatureContext, VirtualProcessor, VmExit, VpCancelReason, VpExceptionContext,
+        X64ApicInitSipiContext, X64ApicWriteContext,
+    },
+    Error, Result,
+};
+
+/// What [`VirtualProcessor::run_loop`] does after an exit has been handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExitAction<S> {
+    /// Run the virtual processor again.
+    Continue,
+    /// Return `S` from the run loop.
+    Stop(S),
+}
+
+/// Limits how long [`VirtualProcessor::run_loop_with_budget`] runs the guest for.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RunBudget {
+    /// The number of exits handled before giving up, at least one exit is always handled.
+    pub max_exits: Option<u64>,
+    /// The wall-clock time after which the running virtual processor is canceled.
+    pub deadline: Option<Instant>,
+}
+
+impl RunBudget {
+    pub fn exits(max_exits: u64) -> Self {
+        Self::default().with_exits(max_exits)
+    }
+
+    pub fn timeout(timeout: Duration) -> Self {
+        Self::default().with_timeout(timeout)
+    }
+
+    pub fn with_exits(self, max_exits: u64) -> Self {
+        Self {
+            max_exits: Some(max_exits),
+            ..self
+        }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            deadline: Some(Instant::now() + timeout),
+            ..self
+        }
+    }
+}
+
+/// How a run with a [`RunBudget`] or timeout ended.
+#[derive(Debug, Clone, Copy)]
+pub enum RunOutcome<T> {
+    Finished(T),
+    /// The budget ran out, with the context of the last exit.
+    BudgetExpired(ExitContext),
+}
+
+/// Handles the exits of a virtual processor, one method per [`RunExitReason`].
+///
+/// Every method fails with [`Error::UnhandledExit`] unless it is overridden.
+pub trait ExitHandler {
+    /// Returned by [`VirtualProcessor::run_loop`] when a handler stops it.
+    type Stop;
+
+    fn memory_access(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _access: &MemoryAccessContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::MemoryAccess))
+    }
+
+    fn io_port(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _access: &IoPortAccessContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::X64IoPortAccess))
+    }
+
+    fn unrecoverable_exception(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::UnrecoverableException))
+    }
+
+    fn invalid_vp_register_value(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::InvalidVpRegisterValue))
+    }
+
+    fn unsupported_feature(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _feature: &UnsupportedFeatureContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::UnsupportedFeature))
+    }
+
+    fn interrupt_window(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _window: &InterruptionDeliverableContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::X64InterruptWindow))
+    }
+
+    fn halt(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::X64Halt))
+    }
+
+    fn apic_eoi(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _eoi: &ApicEoiContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::X64ApicEoi))
+    }
+
+    fn synic_sint_deliverable(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _sint: &SynicSintDeliverableContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::SynicSintDeliverable))
+    }
+
+    fn msr(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _access: &MsrAccessContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::X64MsrAccess))
+    }
+
+    fn cpuid(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _access: &CpuidAccessContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::X64Cpuid))
+    }
+
+    fn exception(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _exception: &VpExceptionContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::Exception))
+    }
+
+    fn rdtsc(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _rdtsc: &RdtscContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::X64Rdtsc))
+    }
+
+    fn apic_smi(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _smi: &ApicSmiContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::X64ApicSmiTrap))
+    }
+
+    fn hypercall(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _hypercall: &HypercallContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::Hypercall))
+    }
+
+    fn apic_init_sipi(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _init_sipi: &X64ApicInitSipiContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::X64ApicInitSipiTrap))
+    }
+
+    fn apic_write(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _write: &X64ApicWriteContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::X64ApicWriteTrap))
+    }
+
+    fn canceled(
+        &mut self,
+        _vcpu: &mut VirtualProcessor,
+        _context: &ExitContext,
+        _reason: VpCancelReason,
+    ) -> Result<ExitAction<Self::Stop>> {
+        Err(Error::UnhandledExit(RunExitReason::Canceled))
+    }
+
+    /// Route `exit` to the method for its reason.
+    fn handle(
+        &mut self,
+        vcpu: &mut VirtualProcessor,
+        exit: &RunExitContext,
+    ) -> Result<ExitAction<Self::Stop>> {
+        let context = &exit.context;
+        match &exit.exit {
+            VmExit::None => Err(Error::UnhandledExit(RunExitReason::None)),
+            VmExit::MemoryAccess(access) => self.memory_access(vcpu, context, access),
+            VmExit::IoPortAccess(access) => self.io_port(vcpu, context, access),
+            VmExit::UnrecoverableException => self.unrecoverable_exception(vcpu, context),
+            VmExit::InvalidVpRegisterValue => self.invalid_vp_register_value(vcpu, context),
+            VmExit::UnsupportedFeature(feature) => self.unsupported_feature(vcpu, context, feature),
+            VmExit::InterruptWindow(window) => self.interrupt_window(vcpu, context, window),
+            VmExit::Halt => self.halt(vcpu, context),
+            VmExit::ApicEoi(eoi) => self.apic_eoi(vcpu, context, eoi),
+            VmExit::SynicSintDeliverable(sint) => self.synic_sint_deliverable(vcpu, context, sint),
+            VmExit::MsrAccess(access) => self.msr(vcpu, context, access),
+            VmExit::Cpuid(access) => self.cpuid(vcpu, context, access),
+            VmExit::Exception(exception) => self.exception(vcpu, context, exception),
+            VmExit::Rdtsc(rdtsc) => self.rdtsc(vcpu, context, rdtsc),
+            VmExit::ApicSmi(smi) => self.apic_smi(vcpu, context, smi),
+            VmExit::Hypercall(hypercall) => self.hypercall(vcpu, context, hypercall),
+            VmExit::ApicInitSipi(init_sipi) => self.apic_init_sipi(vcpu, context, init_sipi),
+            VmExit::ApicWrite(write) => self.apic_write(vcpu, context, write),
+            VmExit::Canceled(reason) => self.canceled(vcpu, context, *reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use crate::{
+        backend::MockBackend,
+        partition::{Partition, PartitionBuilder, PartitionProperty},
+        processor::{
+            ApicEoiContext, ExitContext, Register, RunExitContext, RunExitReason, VirtualProcessor,
+            VmExit, VpCancelReason,
+        },
+        Error, Result,
+    };
+
+    use super::{ExitAction, ExitHandler, RunBudget, RunOutcome};
+
+    fn partition() -> (Arc<MockBackend>, Partition) {
+        let mock = Arc::new(MockBackend::new());
+        let partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(1))
+            .unwrap()
+            .setup()
+            .unwrap();
+        (mock, partition)
+    }
+
+    fn exit(exit: VmExit) -> RunExitContext {
+        RunExitContext {
+            context: ExitContext::default(),
+            exit,
+        }
+    }
+
+    fn eoi_exit(interrupt_vec: u32) -> RunExitContext {
+        exit(VmExit::ApicEoi(ApicEoiContext { interrupt_vec }))
+    }
+
+    /// Counts EOIs and stops on HLT.
+    #[derive(Default)]
+    struct Eois(Vec<u32>);
+
+    impl ExitHandler for Eois {
+        type Stop = usize;
+
+        fn apic_eoi(
+            &mut self,
+            _vcpu: &mut VirtualProcessor,
+            _context: &ExitContext,
+            eoi: &ApicEoiContext,
+        ) -> Result<ExitAction<usize>> {
+            self.0.push(eoi.interrupt_vec);
+            Ok(ExitAction::Continue)
+        }
+
+        fn halt(
+            &mut self,
+            _vcpu: &mut VirtualProcessor,
+            _context: &ExitContext,
+        ) -> Result<ExitAction<usize>> {
+            Ok(ExitAction::Stop(self.0.len()))
+        }
+    }
+
+    #[test]
+    fn run_loop() {
+        let (mock, mut partition) = partition();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+
+        mock.push_exit(0, eoi_exit(0x20));
+        mock.push_exit(0, eoi_exit(0x21));
+        mock.push_exit(0, exit(VmExit::Halt));
+        let mut handler = Eois::default();
+        assert_eq!(vcpu.run_loop(&mut handler).unwrap(), 2);
+        assert_eq!(handler.0, [0x20, 0x21]);
+        assert_eq!(mock.pending_exits(0), 0);
+    }
+
+    #[test]
+    fn unhandled() {
+        let (mock, mut partition) = partition();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+
+        mock.push_exit(0, eoi_exit(0x20));
+        mock.push_exit(0, exit(VmExit::UnrecoverableException));
+        mock.push_exit(0, exit(VmExit::Halt));
+        assert!(matches!(
+            vcpu.run_loop(&mut Eois::default()),
+            Err(Error::UnhandledExit(RunExitReason::UnrecoverableException))
+        ));
+        assert_eq!(mock.pending_exits(0), 1);
+
+        // There is no method for an exit without a reason.
+        assert!(matches!(
+            Eois::default().handle(&mut vcpu, &exit(VmExit::None)),
+            Err(Error::UnhandledExit(RunExitReason::None))
+        ));
+    }
+
+    /// Continues after every EOI, taking its time doing so.
+    struct Slow;
+
+    impl ExitHandler for Slow {
+        type Stop = ();
+
+        fn apic_eoi(
+            &mut self,
+            _vcpu: &mut VirtualProcessor,
+            _context: &ExitContext,
+            _eoi: &ApicEoiContext,
+        ) -> Result<ExitAction<()>> {
+            thread::sleep(Duration::from_millis(50));
+            Ok(ExitAction::Continue)
+        }
+    }
+
+    #[test]
+    fn exit_budget() {
+        let (mock, mut partition) = partition();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+
+        for rip in [0x1000, 0x2000, 0x3000] {
+            let mut exit = eoi_exit(0x20);
+            exit.context.rip = rip;
+            mock.push_exit(0, exit);
+        }
+        let mut handler = Eois::default();
+        let outcome = vcpu
+            .run_loop_with_budget(&mut handler, RunBudget::exits(2))
+            .unwrap();
+        assert!(matches!(outcome, RunOutcome::BudgetExpired(context) if context.rip == 0x2000));
+        assert_eq!(handler.0.len(), 2);
+        assert_eq!(mock.pending_exits(0), 1);
+
+        mock.push_exit(0, exit(VmExit::Halt));
+        let outcome = vcpu
+            .run_loop_with_budget(&mut handler, RunBudget::exits(2))
+            .unwrap();
+        assert!(matches!(outcome, RunOutcome::Finished(3)));
+    }
+
+    #[test]
+    fn deadline() {
+        let (mock, mut partition) = partition();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+
+        for _ in 0..4 {
+            mock.push_exit(0, eoi_exit(0x20));
+        }
+        let outcome = vcpu
+            .run_loop_with_budget(&mut Slow, RunBudget::timeout(Duration::from_millis(1)))
+            .unwrap();
+        assert!(matches!(outcome, RunOutcome::BudgetExpired(_)));
+        assert!(mock.pending_exits(0) > 0);
+
+        // The expired deadline does not cancel later runs.
+        assert!(matches!(vcpu.run().unwrap().exit, VmExit::ApicEoi(_)));
+    }
+
+    #[test]
+    fn run_for() {
+        let (mock, mut partition) = partition();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+        vcpu.set_register(Register::Rip, 0xfff0_u64.into()).unwrap();
+
+        mock.push_exit(0, exit(VmExit::Halt));
+        assert!(matches!(
+            vcpu.run_for(Duration::ZERO).unwrap(),
+            RunOutcome::BudgetExpired(context) if context.rip == 0xfff0
+        ));
+        assert!(matches!(
+            vcpu.run_for(Duration::from_secs(60)).unwrap(),
+            RunOutcome::Finished(RunExitContext {
+                exit: VmExit::Halt,
+                ..
+            })
+        ));
+
+        // Cancellations from elsewhere are not mistaken for the timeout.
+        vcpu.cancel_handle().cancel().unwrap();
+        assert!(matches!(
+            vcpu.run_for(Duration::from_secs(60)).unwrap(),
+            RunOutcome::Finished(RunExitContext {
+                exit: VmExit::Canceled(VpCancelReason::User),
+                ..
+            })
+        ));
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt::Debug;
 
 use c2rust_bitfields::BitfieldStruct;
+#[cfg(windows)]
 use windows::Win32::System::Hypervisor::{
     WHV_X64_DELIVERABILITY_NOTIFICATIONS_REGISTER, WHV_X64_FP_REGISTER, WHV_X64_FP_REGISTER_0,
     WHV_X64_PENDING_EXCEPTION_EVENT, WHV_X64_PENDING_EXCEPTION_EVENT_0,
@@ -12,7 +13,7 @@ use windows::Win32::System::Hypervisor::{
 // TODO: Unit tests for these.
 
 #[repr(C, align(1))]
-#[derive(BitfieldStruct, Clone, Copy, PartialEq, Eq)]
+#[derive(BitfieldStruct, Default, Clone, Copy, PartialEq, Eq)]
 pub struct FpRegister {
     mantissa: u64,
     #[bitfield(name = "biased_exponent", ty = "u16", bits = "0..=14")]
@@ -30,6 +31,25 @@ impl Debug for FpRegister {
     }
 }
 
+impl FpRegister {
+    /// Read a register from its 16 byte slot in an FXSAVE or XSAVE area.
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        let (mantissa, bitfield) = bytes.split_at(8);
+        Self {
+            mantissa: u64::from_le_bytes(mantissa.try_into().unwrap()),
+            bitfield: bitfield.try_into().unwrap(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.mantissa.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.bitfield);
+        bytes
+    }
+}
+
+#[cfg(windows)]
 impl From<WHV_X64_FP_REGISTER> for FpRegister {
     fn from(value: WHV_X64_FP_REGISTER) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -42,6 +62,7 @@ impl From<WHV_X64_FP_REGISTER> for FpRegister {
     }
 }
 
+#[cfg(windows)]
 impl From<FpRegister> for WHV_X64_FP_REGISTER {
     fn from(value: FpRegister) -> Self {
         Self {
@@ -54,7 +75,7 @@ impl From<FpRegister> for WHV_X64_FP_REGISTER {
 }
 
 #[repr(C, align(1))]
-#[derive(BitfieldStruct, Clone, Copy, PartialEq, Eq)]
+#[derive(BitfieldStruct, Default, Clone, Copy, PartialEq, Eq)]
 pub struct PendingInterruptionRegister {
     #[bitfield(name = "interruption_pending", ty = "bool", bits = "0..=0")]
     #[bitfield(name = "interruption_type", ty = "u8", bits = "1..=3")] // TODO: Interruption type... type?
@@ -80,6 +101,7 @@ impl Debug for PendingInterruptionRegister {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_PENDING_INTERRUPTION_REGISTER> for PendingInterruptionRegister {
     fn from(value: WHV_X64_PENDING_INTERRUPTION_REGISTER) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -89,6 +111,7 @@ impl From<WHV_X64_PENDING_INTERRUPTION_REGISTER> for PendingInterruptionRegister
     }
 }
 
+#[cfg(windows)]
 impl From<PendingInterruptionRegister> for WHV_X64_PENDING_INTERRUPTION_REGISTER {
     fn from(value: PendingInterruptionRegister) -> Self {
         Self {
@@ -98,7 +121,7 @@ impl From<PendingInterruptionRegister> for WHV_X64_PENDING_INTERRUPTION_REGISTER
 }
 
 #[repr(C, align(1))]
-#[derive(BitfieldStruct, Clone, Copy, PartialEq, Eq)]
+#[derive(BitfieldStruct, Default, Clone, Copy, PartialEq, Eq)]
 pub struct DeliverabilityNotificationsRegister {
     #[bitfield(name = "nmi_notification", ty = "bool", bits = "0..=0")]
     #[bitfield(name = "interrupt_notification", ty = "bool", bits = "1..=1")]
@@ -118,6 +141,7 @@ impl Debug for DeliverabilityNotificationsRegister {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_DELIVERABILITY_NOTIFICATIONS_REGISTER> for DeliverabilityNotificationsRegister {
     fn from(value: WHV_X64_DELIVERABILITY_NOTIFICATIONS_REGISTER) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -127,6 +151,7 @@ impl From<WHV_X64_DELIVERABILITY_NOTIFICATIONS_REGISTER> for DeliverabilityNotif
     }
 }
 
+#[cfg(windows)]
 impl From<DeliverabilityNotificationsRegister> for WHV_X64_DELIVERABILITY_NOTIFICATIONS_REGISTER {
     fn from(value: DeliverabilityNotificationsRegister) -> Self {
         Self {
@@ -136,7 +161,7 @@ impl From<DeliverabilityNotificationsRegister> for WHV_X64_DELIVERABILITY_NOTIFI
 }
 
 #[repr(C, align(1))]
-#[derive(BitfieldStruct, Clone, Copy, PartialEq, Eq)]
+#[derive(BitfieldStruct, Default, Clone, Copy, PartialEq, Eq)]
 pub struct PendingExceptionEvent {
     #[bitfield(name = "event_pending", ty = "bool", bits = "0..=0")]
     #[bitfield(name = "event_type", ty = "bool", bits = "1..=3")]
@@ -160,6 +185,7 @@ impl Debug for PendingExceptionEvent {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_PENDING_EXCEPTION_EVENT> for PendingExceptionEvent {
     fn from(value: WHV_X64_PENDING_EXCEPTION_EVENT) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -173,6 +199,7 @@ impl From<WHV_X64_PENDING_EXCEPTION_EVENT> for PendingExceptionEvent {
     }
 }
 
+#[cfg(windows)]
 impl From<PendingExceptionEvent> for WHV_X64_PENDING_EXCEPTION_EVENT {
     fn from(value: PendingExceptionEvent) -> Self {
         Self {
@@ -186,7 +213,7 @@ impl From<PendingExceptionEvent> for WHV_X64_PENDING_EXCEPTION_EVENT {
 }
 
 #[repr(C, align(1))]
-#[derive(BitfieldStruct, Clone, Copy, PartialEq, Eq)]
+#[derive(BitfieldStruct, Default, Clone, Copy, PartialEq, Eq)]
 pub struct PendingExtIntEvent {
     #[bitfield(name = "event_pending", ty = "bool", bits = "0..=0")]
     #[bitfield(name = "event_type", ty = "bool", bits = "1..=3")]
@@ -204,6 +231,7 @@ impl Debug for PendingExtIntEvent {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_PENDING_EXT_INT_EVENT> for PendingExtIntEvent {
     fn from(value: WHV_X64_PENDING_EXT_INT_EVENT) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -215,6 +243,7 @@ impl From<WHV_X64_PENDING_EXT_INT_EVENT> for PendingExtIntEvent {
     }
 }
 
+#[cfg(windows)]
 impl From<PendingExtIntEvent> for WHV_X64_PENDING_EXT_INT_EVENT {
     fn from(value: PendingExtIntEvent) -> Self {
         Self {
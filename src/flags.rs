@@ -1,33 +1,37 @@
 // NOTE: We used numerical bit values instead of masks due to the 64 bit flags, otherwise this would be unreadable.
 
+#[cfg(windows)]
 use windows::Win32::System::Hypervisor::{
-    WHV_CAPABILITY_FEATURES, WHV_EXTENDED_VM_EXITS, WHV_MAP_GPA_RANGE_FLAGS,
+    WHV_CAPABILITY_FEATURES, WHV_EMULATOR_STATUS, WHV_EXTENDED_VM_EXITS, WHV_MAP_GPA_RANGE_FLAGS,
     WHV_MEMORY_ACCESS_INFO, WHV_PROCESSOR_FEATURES, WHV_PROCESSOR_FEATURES1,
     WHV_PROCESSOR_PERFMON_FEATURES, WHV_PROCESSOR_XSAVE_FEATURES, WHV_SYNTHETIC_PROCESSOR_FEATURES,
-    WHV_VP_EXCEPTION_INFO, WHV_X64_CPUID_RESULT2_FLAGS, WHV_X64_INTERRUPT_STATE_REGISTER,
-    WHV_X64_IO_PORT_ACCESS_INFO, WHV_X64_MSR_ACCESS_INFO, WHV_X64_MSR_EXIT_BITMAP,
-    WHV_X64_RDTSC_INFO, WHV_X64_SEGMENT_REGISTER_0, WHV_X64_VP_EXECUTION_STATE,
+    WHV_TRANSLATE_GVA_FLAGS, WHV_VP_EXCEPTION_INFO, WHV_X64_CPUID_RESULT2_FLAGS,
+    WHV_X64_INTERRUPT_STATE_REGISTER, WHV_X64_IO_PORT_ACCESS_INFO, WHV_X64_MSR_ACCESS_INFO,
+    WHV_X64_MSR_EXIT_BITMAP, WHV_X64_RDTSC_INFO, WHV_X64_SEGMENT_REGISTER_0,
+    WHV_X64_VP_EXECUTION_STATE,
 };
 
 use bitflags::bitflags;
 
+use crate::processor::MemoryAccessType;
+
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct CapabilityFeatures: u64 {
-        const PartialUnmap = 1;
-        const LocalApicEmulation = 2;
-        const Xsave = 3;
-        const DirtyPageTracking = 4;
-        // TODO: This looks to be wrong...
-        const SpeculationControl = 5;
-        const ApicRemoteRead = 6;
-        const IdleSuspend = 7;
-        const VirtualPciDeviceSupport = 8;
-        const IommuSupport = 9;
-        const VpHotAddRemove = 10;
+        const PartialUnmap = 1 << 0;
+        const LocalApicEmulation = 1 << 1;
+        const Xsave = 1 << 2;
+        const DirtyPageTracking = 1 << 3;
+        const SpeculationControl = 1 << 4;
+        const ApicRemoteRead = 1 << 5;
+        const IdleSuspend = 1 << 6;
+        const VirtualPciDeviceSupport = 1 << 7;
+        const IommuSupport = 1 << 8;
+        const VpHotAddRemove = 1 << 9;
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_CAPABILITY_FEATURES> for CapabilityFeatures {
     fn from(value: WHV_CAPABILITY_FEATURES) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -36,6 +40,7 @@ impl From<WHV_CAPABILITY_FEATURES> for CapabilityFeatures {
     }
 }
 
+#[cfg(windows)]
 impl From<CapabilityFeatures> for WHV_CAPABILITY_FEATURES {
     fn from(value: CapabilityFeatures) -> Self {
         Self {
@@ -55,18 +60,50 @@ bitflags! {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_MAP_GPA_RANGE_FLAGS> for MapGpaRangeFlags {
     fn from(value: WHV_MAP_GPA_RANGE_FLAGS) -> Self {
         Self::from_bits_retain(value.0)
     }
 }
 
+#[cfg(windows)]
 impl From<MapGpaRangeFlags> for WHV_MAP_GPA_RANGE_FLAGS {
     fn from(value: MapGpaRangeFlags) -> Self {
         Self(value.bits())
     }
 }
 
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct TranslateGvaFlags: i32 {
+        const None = 0x0;
+        const ValidateRead = 0x1;
+        const ValidateWrite = 0x2;
+        const ValidateExecute = 0x4;
+        /// Translate as if the processor was in supervisor mode, regardless of the current privilege level.
+        const PrivilegeExempt = 0x8;
+        /// Set the accessed and dirty bits of the walked page table entries.
+        const SetPageTableBits = 0x10;
+        const EnforceSmap = 0x100;
+        const OverrideSmap = 0x200;
+    }
+}
+
+#[cfg(windows)]
+impl From<WHV_TRANSLATE_GVA_FLAGS> for TranslateGvaFlags {
+    fn from(value: WHV_TRANSLATE_GVA_FLAGS) -> Self {
+        Self::from_bits_retain(value.0)
+    }
+}
+
+#[cfg(windows)]
+impl From<TranslateGvaFlags> for WHV_TRANSLATE_GVA_FLAGS {
+    fn from(value: TranslateGvaFlags) -> Self {
+        Self(value.bits())
+    }
+}
+
 bitflags! {
     /// Represents a set of additional exit reasons, can be adjusted by [PartitionBuilder::set_extended_vm_exits].
     #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -98,6 +135,7 @@ bitflags! {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_EXTENDED_VM_EXITS> for ExtendedVmExits {
     fn from(value: WHV_EXTENDED_VM_EXITS) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -106,6 +144,7 @@ impl From<WHV_EXTENDED_VM_EXITS> for ExtendedVmExits {
     }
 }
 
+#[cfg(windows)]
 impl From<ExtendedVmExits> for WHV_EXTENDED_VM_EXITS {
     fn from(value: ExtendedVmExits) -> Self {
         Self {
@@ -175,6 +214,7 @@ bitflags! {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_PROCESSOR_FEATURES> for ProcessorFeatures {
     fn from(value: WHV_PROCESSOR_FEATURES) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -183,6 +223,7 @@ impl From<WHV_PROCESSOR_FEATURES> for ProcessorFeatures {
     }
 }
 
+#[cfg(windows)]
 impl From<ProcessorFeatures> for WHV_PROCESSOR_FEATURES {
     fn from(value: ProcessorFeatures) -> Self {
         Self {
@@ -219,6 +260,7 @@ bitflags! {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_PROCESSOR_FEATURES1> for ProcessorFeatures1 {
     fn from(value: WHV_PROCESSOR_FEATURES1) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -227,6 +269,7 @@ impl From<WHV_PROCESSOR_FEATURES1> for ProcessorFeatures1 {
     }
 }
 
+#[cfg(windows)]
 impl From<ProcessorFeatures1> for WHV_PROCESSOR_FEATURES1 {
     fn from(value: ProcessorFeatures1) -> Self {
         Self {
@@ -235,6 +278,35 @@ impl From<ProcessorFeatures1> for WHV_PROCESSOR_FEATURES1 {
     }
 }
 
+bitflags! {
+    /// The state components of an XSAVE area, as in `XCR0` and the `XSTATE_BV` header field.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct XsaveComponents: u64 {
+        const X87 = 1 << 0;
+        const Sse = 1 << 1;
+        /// The upper halves of the YMM registers.
+        const Avx = 1 << 2;
+        const BndRegs = 1 << 3;
+        const BndCsr = 1 << 4;
+        const Opmask = 1 << 5;
+        const ZmmHi256 = 1 << 6;
+        const Hi16Zmm = 1 << 7;
+        const Pt = 1 << 8;
+        const Pkru = 1 << 9;
+        const Pasid = 1 << 10;
+        const CetU = 1 << 11;
+        const CetS = 1 << 12;
+        const Hdc = 1 << 13;
+        const Uintr = 1 << 14;
+        const Lbr = 1 << 15;
+        const Hwp = 1 << 16;
+        const TileCfg = 1 << 17;
+        const TileData = 1 << 18;
+        /// Set in `XCOMP_BV` for areas in the compacted format.
+        const Compacted = 1 << 63;
+    }
+}
+
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct ProcessorXsaveFeatures: u64 {
@@ -264,6 +336,7 @@ bitflags! {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_PROCESSOR_XSAVE_FEATURES> for ProcessorXsaveFeatures {
     fn from(value: WHV_PROCESSOR_XSAVE_FEATURES) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -272,6 +345,7 @@ impl From<WHV_PROCESSOR_XSAVE_FEATURES> for ProcessorXsaveFeatures {
     }
 }
 
+#[cfg(windows)]
 impl From<ProcessorXsaveFeatures> for WHV_PROCESSOR_XSAVE_FEATURES {
     fn from(value: ProcessorXsaveFeatures) -> Self {
         Self {
@@ -282,19 +356,20 @@ impl From<ProcessorXsaveFeatures> for WHV_PROCESSOR_XSAVE_FEATURES {
 
 // TODO: Keep Vp prefix?
 bitflags! {
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct X64ExecutionState: u16 {
-        const Cpl = 1 | 2;
-        const Cr0Pe = 3;
-        const Cr0Am = 4;
-        const EferLma = 5;
-        const DebugActive = 6;
-        const InterruptionPending = 7;
-        const Reserved0 = 8 | 9 | 10 | 11 | 12;
-        const InterruptShadow = 13;
+        const Cpl = 0x3;
+        const Cr0Pe = 1 << 2;
+        const Cr0Am = 1 << 3;
+        const EferLma = 1 << 4;
+        const DebugActive = 1 << 5;
+        const InterruptionPending = 1 << 6;
+        const Reserved0 = 0x1f << 7;
+        const InterruptShadow = 1 << 12;
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_VP_EXECUTION_STATE> for X64ExecutionState {
     fn from(value: WHV_X64_VP_EXECUTION_STATE) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -303,6 +378,7 @@ impl From<WHV_X64_VP_EXECUTION_STATE> for X64ExecutionState {
     }
 }
 
+#[cfg(windows)]
 impl From<X64ExecutionState> for WHV_X64_VP_EXECUTION_STATE {
     fn from(value: X64ExecutionState) -> Self {
         Self {
@@ -312,20 +388,21 @@ impl From<X64ExecutionState> for WHV_X64_VP_EXECUTION_STATE {
 }
 
 bitflags! {
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct X64SegmentRegisterAttributes: u16 {
-        const SegmentType = 1 | 2 | 3 | 4;
-        const NonSystemSegment = 5;
-        const DescriptorPrivilegeLevel = 6 | 7;
-        const Present = 8;
-        const Reserved = 9 | 10 | 11 | 12;
-        const Available = 13;
-        const Long = 14;
-        const Default = 15;
-        const Granularity = 16;
+        const SegmentType = 0xf;
+        const NonSystemSegment = 1 << 4;
+        const DescriptorPrivilegeLevel = 0x3 << 5;
+        const Present = 1 << 7;
+        const Reserved = 0xf << 8;
+        const Available = 1 << 12;
+        const Long = 1 << 13;
+        const Default = 1 << 14;
+        const Granularity = 1 << 15;
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_SEGMENT_REGISTER_0> for X64SegmentRegisterAttributes {
     fn from(value: WHV_X64_SEGMENT_REGISTER_0) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -334,6 +411,7 @@ impl From<WHV_X64_SEGMENT_REGISTER_0> for X64SegmentRegisterAttributes {
     }
 }
 
+#[cfg(windows)]
 impl From<X64SegmentRegisterAttributes> for WHV_X64_SEGMENT_REGISTER_0 {
     fn from(value: X64SegmentRegisterAttributes) -> Self {
         Self {
@@ -342,17 +420,29 @@ impl From<X64SegmentRegisterAttributes> for WHV_X64_SEGMENT_REGISTER_0 {
     }
 }
 
-// TODO: This is NOT working... its printing out some ugly 0x2 value, it should give us access type...
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct MemoryAccessInfo: u32 {
-        // TODO: Provide helper for access type?
-        const AccessType = 1 | 2;
-        const GpaUnmapped = 5;
-        const GpaValid = 6 | 7;
+        /// See [`MemoryAccessInfo::access_type`].
+        const AccessType = 0x3;
+        const GpaUnmapped = 1 << 2;
+        const GvaValid = 1 << 3;
     }
 }
 
+impl MemoryAccessInfo {
+    /// The kind of access, `None` for the value the hypervisor does not define.
+    pub fn access_type(&self) -> Option<MemoryAccessType> {
+        match self.bits() & Self::AccessType.bits() {
+            0x0 => Some(MemoryAccessType::Read),
+            0x1 => Some(MemoryAccessType::Write),
+            0x2 => Some(MemoryAccessType::Execute),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(windows)]
 impl From<WHV_MEMORY_ACCESS_INFO> for MemoryAccessInfo {
     fn from(value: WHV_MEMORY_ACCESS_INFO) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -361,6 +451,7 @@ impl From<WHV_MEMORY_ACCESS_INFO> for MemoryAccessInfo {
     }
 }
 
+#[cfg(windows)]
 impl From<MemoryAccessInfo> for WHV_MEMORY_ACCESS_INFO {
     fn from(value: MemoryAccessInfo) -> Self {
         Self {
@@ -372,13 +463,22 @@ impl From<MemoryAccessInfo> for WHV_MEMORY_ACCESS_INFO {
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct IoPortAccessInfo: u32 {
-        const IsWrite = 1;
-        const AccessSize = 2 | 3 | 4;
-        const StringOp = 5;
-        const RepPrefix = 6;
+        const IsWrite = 1 << 0;
+        /// See [`IoPortAccessInfo::access_size`].
+        const AccessSize = 0x7 << 1;
+        const StringOp = 1 << 4;
+        const RepPrefix = 1 << 5;
     }
 }
 
+impl IoPortAccessInfo {
+    /// The number of bytes transferred per access, 1, 2 or 4.
+    pub fn access_size(&self) -> usize {
+        ((self.bits() & Self::AccessSize.bits()) >> 1) as usize
+    }
+}
+
+#[cfg(windows)]
 impl From<WHV_X64_IO_PORT_ACCESS_INFO> for IoPortAccessInfo {
     fn from(value: WHV_X64_IO_PORT_ACCESS_INFO) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -387,6 +487,7 @@ impl From<WHV_X64_IO_PORT_ACCESS_INFO> for IoPortAccessInfo {
     }
 }
 
+#[cfg(windows)]
 impl From<IoPortAccessInfo> for WHV_X64_IO_PORT_ACCESS_INFO {
     fn from(value: IoPortAccessInfo) -> Self {
         Self {
@@ -402,6 +503,7 @@ bitflags! {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_MSR_ACCESS_INFO> for MsrAccessInfo {
     fn from(value: WHV_X64_MSR_ACCESS_INFO) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -410,6 +512,7 @@ impl From<WHV_X64_MSR_ACCESS_INFO> for MsrAccessInfo {
     }
 }
 
+#[cfg(windows)]
 impl From<MsrAccessInfo> for WHV_X64_MSR_ACCESS_INFO {
     fn from(value: MsrAccessInfo) -> Self {
         Self {
@@ -427,6 +530,7 @@ bitflags! {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_VP_EXCEPTION_INFO> for VpExceptionInfo {
     fn from(value: WHV_VP_EXCEPTION_INFO) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -435,6 +539,7 @@ impl From<WHV_VP_EXCEPTION_INFO> for VpExceptionInfo {
     }
 }
 
+#[cfg(windows)]
 impl From<VpExceptionInfo> for WHV_VP_EXCEPTION_INFO {
     fn from(value: VpExceptionInfo) -> Self {
         Self {
@@ -450,6 +555,7 @@ bitflags! {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_RDTSC_INFO> for RdtscInfo {
     fn from(value: WHV_X64_RDTSC_INFO) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -458,6 +564,7 @@ impl From<WHV_X64_RDTSC_INFO> for RdtscInfo {
     }
 }
 
+#[cfg(windows)]
 impl From<RdtscInfo> for WHV_X64_RDTSC_INFO {
     fn from(value: RdtscInfo) -> Self {
         Self {
@@ -508,6 +615,7 @@ bitflags! {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_SYNTHETIC_PROCESSOR_FEATURES> for SyntheticProcessorFeatures {
     fn from(value: WHV_SYNTHETIC_PROCESSOR_FEATURES) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -516,6 +624,7 @@ impl From<WHV_SYNTHETIC_PROCESSOR_FEATURES> for SyntheticProcessorFeatures {
     }
 }
 
+#[cfg(windows)]
 impl From<SyntheticProcessorFeatures> for WHV_SYNTHETIC_PROCESSOR_FEATURES {
     fn from(value: SyntheticProcessorFeatures) -> Self {
         Self {
@@ -532,12 +641,14 @@ bitflags! {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_CPUID_RESULT2_FLAGS> for X64CpuidResult2Flags {
     fn from(value: WHV_X64_CPUID_RESULT2_FLAGS) -> Self {
         Self::from_bits_retain(value.0)
     }
 }
 
+#[cfg(windows)]
 impl From<X64CpuidResult2Flags> for WHV_X64_CPUID_RESULT2_FLAGS {
     fn from(value: X64CpuidResult2Flags) -> Self {
         Self(value.bits())
@@ -556,6 +667,7 @@ bitflags! {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_MSR_EXIT_BITMAP> for X64MsrExitBitmap {
     fn from(value: WHV_X64_MSR_EXIT_BITMAP) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -564,6 +676,7 @@ impl From<WHV_X64_MSR_EXIT_BITMAP> for X64MsrExitBitmap {
     }
 }
 
+#[cfg(windows)]
 impl From<X64MsrExitBitmap> for WHV_X64_MSR_EXIT_BITMAP {
     fn from(value: X64MsrExitBitmap) -> Self {
         Self {
@@ -580,6 +693,7 @@ bitflags! {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_PROCESSOR_PERFMON_FEATURES> for ProcessorPerfmonFeatures {
     fn from(value: WHV_PROCESSOR_PERFMON_FEATURES) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -588,6 +702,7 @@ impl From<WHV_PROCESSOR_PERFMON_FEATURES> for ProcessorPerfmonFeatures {
     }
 }
 
+#[cfg(windows)]
 impl From<ProcessorPerfmonFeatures> for WHV_PROCESSOR_PERFMON_FEATURES {
     fn from(value: ProcessorPerfmonFeatures) -> Self {
         Self {
@@ -597,13 +712,14 @@ impl From<ProcessorPerfmonFeatures> for WHV_PROCESSOR_PERFMON_FEATURES {
 }
 
 bitflags! {
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct InterruptStateRegister: u64 {
         const InterruptShadow = 1;
         const NmiMasked = 2;
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_INTERRUPT_STATE_REGISTER> for InterruptStateRegister {
     fn from(value: WHV_X64_INTERRUPT_STATE_REGISTER) -> Self {
         // SAFETY: Reinterpreting the bits in-place.
@@ -612,6 +728,7 @@ impl From<WHV_X64_INTERRUPT_STATE_REGISTER> for InterruptStateRegister {
     }
 }
 
+#[cfg(windows)]
 impl From<InterruptStateRegister> for WHV_X64_INTERRUPT_STATE_REGISTER {
     fn from(value: InterruptStateRegister) -> Self {
         Self {
@@ -619,3 +736,35 @@ impl From<InterruptStateRegister> for WHV_X64_INTERRUPT_STATE_REGISTER {
         }
     }
 }
+
+bitflags! {
+    /// The outcome of an emulation attempt, see [`crate::emulator::Emulator`].
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct EmulatorStatus: u32 {
+        const EmulationSuccessful = 1 << 0;
+        const InternalEmulationFailure = 1 << 1;
+        const IoPortCallbackFailed = 1 << 2;
+        const MemoryCallbackFailed = 1 << 3;
+        const TranslateGvaPageCallbackFailed = 1 << 4;
+        const TranslateGvaPageCallbackGpaIsNotAligned = 1 << 5;
+        const GetVirtualProcessorRegistersCallbackFailed = 1 << 6;
+        const SetVirtualProcessorRegistersCallbackFailed = 1 << 7;
+        const InterruptCausedIntercept = 1 << 8;
+        const GuestCannotBeFaulted = 1 << 9;
+    }
+}
+
+impl EmulatorStatus {
+    pub fn is_success(&self) -> bool {
+        self.contains(Self::EmulationSuccessful)
+    }
+}
+
+#[cfg(windows)]
+impl From<WHV_EMULATOR_STATUS> for EmulatorStatus {
+    fn from(value: WHV_EMULATOR_STATUS) -> Self {
+        // SAFETY: Reinterpreting the bits in-place.
+        let bits = unsafe { value.AsUINT32 };
+        Self::from_bits_retain(bits)
+    }
+}
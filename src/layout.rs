@@ -0,0 +1,284 @@
+use crate::{memory::PAGE_SIZE, Error, Result};
+
+/// The start of the legacy BIOS ROM, just below 1MiB.
+pub const ROM_BASE: u64 = 0xF0000;
+/// The default start of the hole below 4GiB left free for MMIO.
+pub const DEFAULT_HOLE_START: u64 = 0xC000_0000;
+pub const FOUR_GIB: u64 = 0x1_0000_0000;
+/// The highest guest physical address the layout will place RAM below.
+pub const MAX_GPA: u64 = 1 << 52;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegionKind {
+    Ram,
+    Mmio,
+    Rom,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayoutRegion {
+    pub kind: RegionKind,
+    pub guest_address: u64,
+    pub size: u64,
+}
+
+impl LayoutRegion {
+    pub fn contains(&self, gpa: u64) -> bool {
+        gpa >= self.guest_address && gpa - self.guest_address < self.size
+    }
+
+    fn end(&self) -> u64 {
+        self.guest_address + self.size
+    }
+}
+
+/// Plans where regions go in guest physical memory before they are mapped.
+///
+/// RAM fills the free space below the 32-bit hole first and continues above 4GiB, MMIO windows
+/// are carved out of the hole and ROM sits at [`ROM_BASE`]. Regions never overlap, so the layout
+/// can answer which region a guest physical address belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpaLayout {
+    hole_start: u64,
+    /// Sorted by guest address.
+    regions: Vec<LayoutRegion>,
+}
+
+impl Default for GpaLayout {
+    fn default() -> Self {
+        Self {
+            hole_start: DEFAULT_HOLE_START,
+            regions: Vec::new(),
+        }
+    }
+}
+
+impl GpaLayout {
+    /// Create an empty layout with the hole spanning `hole_start` up to 4GiB.
+    pub fn new(hole_start: u64) -> Result<Self> {
+        if hole_start > FOUR_GIB || !hole_start.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(Error::InvalidHoleStart(hole_start));
+        }
+        Ok(Self {
+            hole_start,
+            regions: Vec::new(),
+        })
+    }
+
+    pub fn hole_start(&self) -> u64 {
+        self.hole_start
+    }
+
+    pub fn regions(&self) -> &[LayoutRegion] {
+        &self.regions
+    }
+
+    /// The region containing the guest physical address `gpa`.
+    pub fn region(&self, gpa: u64) -> Option<&LayoutRegion> {
+        let index = self.regions.partition_point(|r| r.end() <= gpa);
+        self.regions.get(index).filter(|r| r.contains(gpa))
+    }
+
+    /// Place a region at a fixed guest address.
+    pub fn insert(
+        &mut self,
+        kind: RegionKind,
+        guest_address: u64,
+        size: u64,
+    ) -> Result<LayoutRegion> {
+        check_aligned(guest_address, size)?;
+        let region = LayoutRegion {
+            kind,
+            guest_address,
+            size,
+        };
+        let end = guest_address
+            .checked_add(size)
+            .ok_or(Error::GpaOutOfRange(guest_address, size as usize))?;
+
+        let index = self
+            .regions
+            .partition_point(|r| r.guest_address < guest_address);
+        let before = index.checked_sub(1).map(|i| &self.regions[i]);
+        let after = self.regions.get(index);
+        if let Some(other) = before
+            .filter(|r| r.end() > guest_address)
+            .or(after.filter(|r| r.guest_address < end))
+        {
+            return Err(Error::OverlappingRegion(
+                guest_address,
+                size as usize,
+                other.guest_address,
+                other.size as usize,
+            ));
+        }
+
+        self.regions.insert(index, region);
+        Ok(region)
+    }
+
+    /// Place ROM ending at 1MiB, the usual spot being a 64KiB image at [`ROM_BASE`].
+    pub fn add_rom(&mut self, size: u64) -> Result<LayoutRegion> {
+        let guest_address = (ROM_BASE + 0x10000)
+            .checked_sub(size)
+            .ok_or(Error::GpaOutOfRange(ROM_BASE, size as usize))?;
+        self.insert(RegionKind::Rom, guest_address, size)
+    }
+
+    /// Place an MMIO window of `size` bytes in the hole, aligned to `alignment`.
+    pub fn add_mmio(&mut self, size: u64, alignment: u64) -> Result<LayoutRegion> {
+        check_aligned(0, size)?;
+        if alignment != 0 && !alignment.is_power_of_two() {
+            return Err(Error::InvalidAlignment(alignment));
+        }
+        let alignment = alignment.max(PAGE_SIZE as u64);
+
+        let guest_address = self
+            .free_ranges(self.hole_start, FOUR_GIB)
+            .into_iter()
+            .find_map(|(start, end)| {
+                let start = start.checked_next_multiple_of(alignment)?;
+                (end.checked_sub(start)? >= size).then_some(start)
+            })
+            .ok_or(Error::GpaOutOfRange(self.hole_start, size as usize))?;
+        self.insert(RegionKind::Mmio, guest_address, size)
+    }
+
+    /// Place `size` bytes of RAM in the free space below the hole, spilling over to above 4GiB.
+    ///
+    /// Returns every piece placed, RAM is split around the hole and any region already in the way.
+    pub fn add_ram(&mut self, size: u64) -> Result<Vec<LayoutRegion>> {
+        check_aligned(0, size)?;
+
+        let mut free = self.free_ranges(0, self.hole_start);
+        free.extend(self.free_ranges(FOUR_GIB, MAX_GPA));
+        if free.iter().map(|(start, end)| end - start).sum::<u64>() < size {
+            return Err(Error::GpaOutOfRange(0, size as usize));
+        }
+
+        let mut placed = Vec::new();
+        let mut remaining = size;
+        for (start, end) in free {
+            if remaining == 0 {
+                break;
+            }
+            let chunk = remaining.min(end - start);
+            placed.push(self.insert(RegionKind::Ram, start, chunk)?);
+            remaining -= chunk;
+        }
+        Ok(placed)
+    }
+
+    /// The gaps between regions within `start..end`.
+    fn free_ranges(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let mut ranges = Vec::new();
+        let mut cur = start;
+        for region in &self.regions {
+            if region.end() <= cur {
+                continue;
+            }
+            if region.guest_address >= end {
+                break;
+            }
+            if region.guest_address > cur {
+                ranges.push((cur, region.guest_address));
+            }
+            cur = region.end();
+        }
+        if cur < end {
+            ranges.push((cur, end));
+        }
+        ranges
+    }
+}
+
+fn check_aligned(guest_address: u64, size: u64) -> Result<()> {
+    let page = PAGE_SIZE as u64;
+    if !guest_address.is_multiple_of(page) || size == 0 || !size.is_multiple_of(page) {
+        return Err(Error::UnalignedRegion(guest_address, size as usize));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Error;
+
+    use super::{GpaLayout, LayoutRegion, RegionKind, FOUR_GIB};
+
+    fn region(kind: RegionKind, guest_address: u64, size: u64) -> LayoutRegion {
+        LayoutRegion {
+            kind,
+            guest_address,
+            size,
+        }
+    }
+
+    #[test]
+    fn pc_layout() {
+        let mut layout = GpaLayout::new(0xE000_0000).unwrap();
+        let rom = layout.add_rom(0x10000).unwrap();
+        assert_eq!(rom, region(RegionKind::Rom, 0xF0000, 0x10000));
+
+        let mmio = layout.add_mmio(0x1000_0000, 0x1000_0000).unwrap();
+        assert_eq!(mmio, region(RegionKind::Mmio, 0xE000_0000, 0x1000_0000));
+        let mmio = layout.add_mmio(0x1000, 0).unwrap();
+        assert_eq!(mmio.guest_address, 0xF000_0000);
+
+        let ram = layout.add_ram(0x1_0000_0000).unwrap();
+        assert_eq!(
+            ram,
+            [
+                region(RegionKind::Ram, 0, 0xF0000),
+                region(RegionKind::Ram, 0x10_0000, 0xDFF0_0000),
+                region(RegionKind::Ram, FOUR_GIB, 0x2001_0000),
+            ]
+        );
+
+        assert_eq!(layout.region(0xF1234).unwrap().kind, RegionKind::Rom);
+        assert_eq!(layout.region(0xE000_0000).unwrap().kind, RegionKind::Mmio);
+        assert_eq!(layout.region(0x1_2000_ffff).unwrap().kind, RegionKind::Ram);
+        assert_eq!(layout.region(0xF000_1000), None);
+        assert_eq!(layout.region(0x1_2001_0000), None);
+    }
+
+    #[test]
+    fn invalid_regions() {
+        let mut layout = GpaLayout::default();
+        layout.insert(RegionKind::Ram, 0x1000, 0x2000).unwrap();
+
+        assert!(matches!(
+            layout.insert(RegionKind::Mmio, 0x2000, 0x1000),
+            Err(Error::OverlappingRegion(0x2000, 0x1000, 0x1000, 0x2000))
+        ));
+        assert!(matches!(
+            layout.insert(RegionKind::Mmio, 0, 0x2000),
+            Err(Error::OverlappingRegion(0, 0x2000, 0x1000, 0x2000))
+        ));
+        assert!(matches!(
+            layout.insert(RegionKind::Mmio, 0x3800, 0x1000),
+            Err(Error::UnalignedRegion(0x3800, 0x1000))
+        ));
+        assert!(matches!(
+            layout.add_mmio(0x8000_0000, 0),
+            Err(Error::GpaOutOfRange(0xC000_0000, 0x8000_0000))
+        ));
+        assert!(matches!(
+            layout.add_mmio(0x1000, 0x3000),
+            Err(Error::InvalidAlignment(0x3000))
+        ));
+    }
+
+    #[test]
+    fn invalid_hole() {
+        assert!(matches!(
+            GpaLayout::new(0xE000_0800),
+            Err(Error::InvalidHoleStart(0xE000_0800))
+        ));
+        assert!(matches!(
+            GpaLayout::new(FOUR_GIB + 0x1000),
+            Err(Error::InvalidHoleStart(0x1_0000_1000))
+        ));
+        assert_eq!(GpaLayout::new(FOUR_GIB).unwrap().hole_start(), FOUR_GIB);
+    }
+}
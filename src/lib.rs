@@ -1,38 +1,107 @@
-#![feature(strict_provenance)]
-#![feature(seek_stream_len)]
-
 use std::fmt::Debug;
 
-use partition::PartitionProperty;
+use partition::{PartitionProperty, PartitionPropertyCode};
 use thiserror::Error;
+#[cfg(windows)]
 use windows::Win32::System::Hypervisor::{
     WHvGetCapability, WHV_CAPABILITY, WHV_CAPABILITY_CODE, WHV_PROCESSOR_VENDOR,
 };
 
 use flags::{CapabilityFeatures, ExtendedVmExits, ProcessorFeatures, ProcessorXsaveFeatures};
 
+pub mod apic;
+pub mod backend;
+pub mod decode;
+pub mod emulator;
+pub mod exit;
 pub mod fields;
 pub mod flags;
+pub mod layout;
 pub mod memory;
+pub mod mmio;
+pub mod paging;
 pub mod partition;
+pub mod pio;
 pub mod processor;
+pub mod state;
+pub mod xsave;
 
-// TODO: Require windows target.
 // TODO: Move architecture specific stuff behind flags? I.e. `WHV_X64_*`.
 
 // TODO: Rename all these errors to more idiomatic names.
 #[derive(Error, Debug)]
 pub enum Error {
+    #[cfg(windows)]
     #[error("a windows function returned: {0}")]
     Windows(#[from] windows::core::Error),
     #[error("failed int conversion: {0}")]
     TryFromIntError(#[from] std::num::TryFromIntError),
+    #[error("an io operation failed: {0}")]
+    Io(#[from] std::io::Error),
     #[error(
         "incompatible partition property availability, property ({0:?}) unable to be set after setup"
     )]
     IncompatiblePropertyAvailibility(PartitionProperty),
     #[error("index {0} is greater than the partition's processor count ({1})")]
     InvalidVpIndex(u32, u32),
+    #[error("guest physical address {0:#x} is not mapped")]
+    GpaUnmapped(u64),
+    #[error("guest physical range of {1} bytes at {0:#x} exceeds the address space")]
+    GpaOutOfRange(u64, usize),
+    #[error("memory region at {0:#x} of {1:#x} bytes is not page aligned")]
+    UnalignedRegion(u64, usize),
+    #[error(
+        "memory region at {0:#x} of {1:#x} bytes overlaps the region at {2:#x} of {3:#x} bytes"
+    )]
+    OverlappingRegion(u64, usize, u64, usize),
+    #[error("memory region at {:#x} was not mapped ({1})", .0.guest_address)]
+    RegionRejected(Box<memory::MemoryRegion>, Box<Error>),
+    #[error(
+        "memory region at {:#x} could not be mapped again and is no longer mapped ({1}, restoring \
+         the previous mapping failed with {2})",
+        .0.guest_address
+    )]
+    RegionUnmapped(Box<memory::MemoryRegion>, Box<Error>, Box<Error>),
+    #[error("MMIO hole starting at {0:#x} is not page aligned or lies above 4GiB")]
+    InvalidHoleStart(u64),
+    #[error("MMIO alignment of {0:#x} bytes is not a power of two")]
+    InvalidAlignment(u64),
+    #[error("the region containing {0:#x} is not mapped with dirty page tracking")]
+    DirtyPagesUntracked(u64),
+    #[error("page fault at {:#x} ({:?})", .0.address, .0.error_code)]
+    PageFault(paging::PageFault),
+    #[error("virtual address {0:#x} is not canonical")]
+    NonCanonicalAddress(u64),
+    #[error("no MMIO handler claims guest physical address {0:#x}")]
+    MmioUnclaimed(u64),
+    #[error("no port I/O handler claims port {0:#x}")]
+    PortUnclaimed(u16),
+    #[error("{1} ports starting at {0:#x} do not fit the I/O port space")]
+    InvalidPortRange(u16, u16),
+    #[error("{1} ports starting at {0:#x} overlap the {3} ports starting at {2:#x}")]
+    OverlappingPorts(u16, u16, u16, u16),
+    #[error("string I/O stopped with {} iterations left ({1})", .0.rcx)]
+    StringIoInterrupted(pio::StringIoRegisters, Box<Error>),
+    #[error("unable to decode the instruction {0:02x?}")]
+    InvalidInstruction(Vec<u8>),
+    #[error("unable to emulate the instruction {0:02x?}")]
+    UnsupportedInstruction(Vec<u8>),
+    #[error("partition property ({0:?}) has not been set")]
+    UnsetProperty(PartitionPropertyCode),
+    #[error("XSAVE area of {0} bytes is too small, {1} bytes are needed")]
+    XsaveAreaTruncated(usize, usize),
+    #[error("compacted XSAVE area holds components of unknown size ({0:?})")]
+    UnknownXsaveComponents(flags::XsaveComponents),
+    #[error("XSAVE area does not hold the components ({0:?})")]
+    MissingXsaveComponents(flags::XsaveComponents),
+    #[error("local APIC page of {0} bytes, expected 4096 bytes")]
+    InvalidLapicPageSize(usize),
+    #[error("unhandled virtual processor exit ({0:?})")]
+    UnhandledExit(processor::RunExitReason),
+    #[error("no scripted exit left for virtual processor {0}")]
+    NoScriptedExit(u32),
+    #[error("mapping of {0:#x} failed as scripted")]
+    ScriptedMapFailure(u64),
 }
 
 /// A specialized [`Result`] type that provides Windows Hypervisor error information.
@@ -46,12 +115,14 @@ pub enum ProcessorVendor {
     Hygon = 0x2,
 }
 
+#[cfg(windows)]
 impl From<ProcessorVendor> for WHV_PROCESSOR_VENDOR {
     fn from(value: ProcessorVendor) -> Self {
         Self(value as i32)
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_PROCESSOR_VENDOR> for ProcessorVendor {
     fn from(value: WHV_PROCESSOR_VENDOR) -> Self {
         // TODO: Can we enforce this differently?
@@ -76,12 +147,14 @@ pub enum CapabilityCode {
     ProcessorXsaveFeatures = 0x1003,
 }
 
+#[cfg(windows)]
 impl From<CapabilityCode> for WHV_CAPABILITY_CODE {
     fn from(value: CapabilityCode) -> Self {
         Self(value as i32)
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_CAPABILITY_CODE> for CapabilityCode {
     fn from(value: WHV_CAPABILITY_CODE) -> Self {
         // TODO: Can we enforce this differently?
@@ -110,6 +183,7 @@ pub enum Capability {
 }
 
 impl Capability {
+    #[cfg(windows)]
     fn from_union(code: CapabilityCode, cap: WHV_CAPABILITY) -> Self {
         // SAFETY: The code corresponds to the union variant.
         unsafe {
@@ -139,12 +213,13 @@ impl Capability {
 }
 
 // TODO: Move this to [CapabilityCode]?
+#[cfg(windows)]
 pub fn query_capability(code: CapabilityCode) -> Result<Capability> {
     let mut cap: WHV_CAPABILITY = Default::default();
     unsafe {
         WHvGetCapability(
             code.into(),
-            std::mem::transmute(&mut cap),
+            (&mut cap as *mut WHV_CAPABILITY).cast(),
             std::mem::size_of::<WHV_CAPABILITY>().try_into()?,
             None,
         )?;
@@ -1,15 +1,21 @@
-use std::{fs::File, io::Seek, os::windows::io::AsRawHandle};
+#[cfg(windows)]
+use std::{fs::File, os::windows::io::AsRawHandle};
 
+#[cfg(windows)]
 use windows::Win32::{
     Foundation::{CloseHandle, HANDLE},
     System::Memory::{
         CreateFileMappingA, MapViewOfFile, UnmapViewOfFile, VirtualAlloc, VirtualFree,
-        FILE_MAP_ALL_ACCESS, MEMORY_MAPPED_VIEW_ADDRESS, MEM_COMMIT, MEM_RELEASE, MEM_RESERVE,
-        PAGE_READWRITE,
+        VirtualQuery, FILE_MAP, FILE_MAP_ALL_ACCESS, FILE_MAP_COPY, MEMORY_BASIC_INFORMATION,
+        MEMORY_MAPPED_VIEW_ADDRESS, MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_PROTECTION_FLAGS,
+        PAGE_READWRITE, PAGE_WRITECOPY,
     },
 };
 
-use crate::{flags::MapGpaRangeFlags, Result};
+use crate::{flags::MapGpaRangeFlags, Error, Result};
+
+/// The granularity guest memory is mapped at.
+pub const PAGE_SIZE: usize = 0x1000;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RegionBacking {
@@ -17,6 +23,9 @@ pub enum RegionBacking {
     Volatile,
     /// Backed by a file on the filesystem.
     File,
+    /// Backed by a file on the filesystem that is never written to, written pages are privately
+    /// copied instead. See [`MemoryRegion::copied_pages`].
+    CopyOnWrite,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -29,21 +38,24 @@ pub struct MemoryRegion {
     pub backing: RegionBacking,
 }
 
-// TODO: Add a way to detect dirty pages (probably requires file backing?).
 // TODO: Add a way to search the region and manipulate it.
 // TODO: Showcase how to map from a file and not bloat the memory... (i.e. not paged in until a page is accessed)
 impl MemoryRegion {
+    /// Whether the guest physical address `gpa` falls within this region.
+    pub fn contains(&self, gpa: u64) -> bool {
+        let start = self.guest_address as u64;
+        gpa >= start && gpa - start < self.size as u64
+    }
+
     pub fn from_bytes(guest_address: usize, flags: MapGpaRangeFlags, bytes: &[u8]) -> Self {
-        // TODO: Should we use MEM_RESERVE?
-        let address =
-            unsafe { VirtualAlloc(None, bytes.len(), MEM_RESERVE | MEM_COMMIT, PAGE_READWRITE) };
+        let address = alloc_volatile(bytes.len());
 
         unsafe {
-            address.copy_from(bytes.as_ptr() as *const _, bytes.len());
+            (address as *mut u8).copy_from(bytes.as_ptr(), bytes.len());
         }
 
         Self {
-            address: address.addr(),
+            address,
             guest_address,
             flags,
             size: bytes.len(),
@@ -51,68 +63,404 @@ impl MemoryRegion {
         }
     }
 
-    pub fn from_file(
+    #[cfg(windows)]
+    pub fn from_file(guest_address: usize, flags: MapGpaRangeFlags, file: File) -> Result<Self> {
+        map_file(
+            guest_address,
+            flags,
+            &file,
+            PAGE_READWRITE,
+            FILE_MAP_ALL_ACCESS,
+            RegionBacking::File,
+        )
+    }
+
+    /// Map `file` so the guest sees its contents but its writes land in private pages, leaving the
+    /// file untouched. Many partitions can share one image this way.
+    #[cfg(windows)]
+    pub fn from_file_copy_on_write(
         guest_address: usize,
         flags: MapGpaRangeFlags,
-        mut file: File,
+        file: File,
     ) -> Result<Self> {
-        // TODO: Error handling.
-        let file_len = file.stream_len().unwrap();
-
-        let raw_file_handle = file.as_raw_handle();
-        // TODO: Check make sure its valid handle.
-
-        let mapping_handle = unsafe {
-            CreateFileMappingA(
-                HANDLE(raw_file_handle as _),
-                None,
-                PAGE_READWRITE,
-                0,
-                0,
-                None,
-            )?
+        map_file(
+            guest_address,
+            flags,
+            &file,
+            PAGE_WRITECOPY,
+            FILE_MAP_COPY,
+            RegionBacking::CopyOnWrite,
+        )
+    }
+
+    /// The number of pages that have been written to and privately copied, always zero unless the
+    /// region is backed by [`RegionBacking::CopyOnWrite`].
+    pub fn copied_pages(&self) -> usize {
+        match self.backing {
+            #[cfg(windows)]
+            RegionBacking::CopyOnWrite => count_copied_pages(self.address, self.size),
+            _ => 0,
+        }
+    }
+}
+
+/// Types that can be safely read from and written to guest memory as raw bytes.
+///
+/// # Safety
+///
+/// Every bit pattern of `size_of::<Self>()` bytes must be a valid value of the type.
+pub unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for u128 {}
+unsafe impl Pod for usize {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl Pod for i128 {}
+unsafe impl Pod for isize {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A view of guest physical memory over the regions mapped into a partition.
+///
+/// Accesses may span multiple adjacent regions, an access touching any unmapped byte fails as a
+/// whole without reading or writing anything.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestMemory<'a> {
+    regions: &'a [MemoryRegion],
+}
+
+impl<'a> GuestMemory<'a> {
+    pub fn new(regions: &'a [MemoryRegion]) -> Self {
+        Self { regions }
+    }
+
+    /// The region containing the guest physical address `gpa`.
+    pub fn region(&self, gpa: u64) -> Option<&'a MemoryRegion> {
+        self.regions.iter().find(|r| r.contains(gpa))
+    }
+
+    /// Splits the range into host address and length pairs, one per region touched.
+    fn host_ranges(&self, gpa: u64, len: usize) -> Result<Vec<(usize, usize)>> {
+        if gpa.checked_add(len as u64).is_none() {
+            return Err(Error::GpaOutOfRange(gpa, len));
+        }
+
+        let mut ranges = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let cur = gpa + done as u64;
+            let region = self.region(cur).ok_or(Error::GpaUnmapped(cur))?;
+            let offset = (cur - region.guest_address as u64) as usize;
+            let chunk = (len - done).min(region.size - offset);
+            ranges.push((region.address + offset, chunk));
+            done += chunk;
+        }
+        Ok(ranges)
+    }
+
+    pub fn read(&self, gpa: u64, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        for (host, len) in self.host_ranges(gpa, buf.len())? {
+            // SAFETY: The host range lies within a region allocation borrowed for `'a`.
+            unsafe {
+                std::ptr::copy_nonoverlapping(host as *const u8, buf[done..].as_mut_ptr(), len)
+            };
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, gpa: u64, buf: &[u8]) -> Result<()> {
+        let mut done = 0;
+        for (host, len) in self.host_ranges(gpa, buf.len())? {
+            // SAFETY: The host range lies within a region allocation borrowed for `'a`.
+            unsafe { std::ptr::copy_nonoverlapping(buf[done..].as_ptr(), host as *mut u8, len) };
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub fn read_obj<T: Pod>(&self, gpa: u64) -> Result<T> {
+        let mut val = std::mem::MaybeUninit::<T>::uninit();
+        // SAFETY: The bytes are fully initialized by `read` and any bit pattern is a valid `T`.
+        unsafe {
+            let bytes = std::slice::from_raw_parts_mut(
+                val.as_mut_ptr() as *mut u8,
+                std::mem::size_of::<T>(),
+            );
+            self.read(gpa, bytes)?;
+            Ok(val.assume_init())
+        }
+    }
+
+    pub fn write_obj<T: Pod>(&self, gpa: u64, val: T) -> Result<()> {
+        // SAFETY: `T` is plain data, so viewing it as bytes is sound.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(&val as *const T as *const u8, std::mem::size_of::<T>())
         };
+        self.write(gpa, bytes)
+    }
+}
+
+/// Read access to guest physical memory, implemented by anything that can resolve a guest
+/// physical address to its backing bytes.
+pub trait GuestMemoryRead {
+    fn read_physical(&self, gpa: u64, buf: &mut [u8]) -> Result<()>;
+}
+
+impl GuestMemoryRead for GuestMemory<'_> {
+    fn read_physical(&self, gpa: u64, buf: &mut [u8]) -> Result<()> {
+        self.read(gpa, buf)
+    }
+}
 
-        // TODO: Make sure this is a valid address.
-        let address = unsafe { MapViewOfFile(mapping_handle, FILE_MAP_ALL_ACCESS, 0, 0, 0) };
+/// Write access to guest physical memory, the counterpart of [`GuestMemoryRead`].
+pub trait GuestMemoryWrite {
+    fn write_physical(&self, gpa: u64, buf: &[u8]) -> Result<()>;
+}
 
-        unsafe { CloseHandle(mapping_handle)? };
+impl GuestMemoryWrite for GuestMemory<'_> {
+    fn write_physical(&self, gpa: u64, buf: &[u8]) -> Result<()> {
+        self.write(gpa, buf)
+    }
+}
 
-        Ok(Self {
-            address: address.Value.addr(),
+/// The dirty pages of a guest physical range, one bit per page.
+///
+/// Returned by [`crate::partition::Partition::query_dirty_bitmap`], querying resets the dirty state
+/// of the range so every bitmap only holds the pages written since the previous query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirtyBitmap {
+    guest_address: u64,
+    pages: usize,
+    bits: Vec<u64>,
+}
+
+impl DirtyBitmap {
+    /// An empty bitmap covering `size` bytes starting at `guest_address`.
+    pub fn new(guest_address: u64, size: u64) -> Self {
+        let pages = (size as usize).div_ceil(PAGE_SIZE);
+        Self {
             guest_address,
-            flags,
-            size: file_len.try_into()?,
-            backing: RegionBacking::File,
+            pages,
+            bits: vec![0; pages.div_ceil(u64::BITS as usize)],
+        }
+    }
+
+    /// A bitmap from the raw words reported by the hypervisor, bit `n` of word `n / 64` is page `n`.
+    pub fn from_words(guest_address: u64, size: u64, words: &[u64]) -> Self {
+        let mut bitmap = Self::new(guest_address, size);
+        for (dst, src) in bitmap.bits.iter_mut().zip(words) {
+            *dst = *src;
+        }
+        bitmap.clear_tail();
+        bitmap
+    }
+
+    /// Drops any bits past the last page of the range.
+    fn clear_tail(&mut self) {
+        let used = self.pages % u64::BITS as usize;
+        if let (Some(last), true) = (self.bits.last_mut(), used != 0) {
+            *last &= (1 << used) - 1;
+        }
+    }
+
+    pub fn guest_address(&self) -> u64 {
+        self.guest_address
+    }
+
+    /// The number of pages covered by the bitmap.
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    pub fn words(&self) -> &[u64] {
+        &self.bits
+    }
+
+    pub fn is_dirty(&self, page: usize) -> bool {
+        page < self.pages && self.bits[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    pub fn set_dirty(&mut self, page: usize) {
+        assert!(page < self.pages, "page {page} is outside the bitmap");
+        self.bits[page / 64] |= 1 << (page % 64);
+    }
+
+    /// The number of dirty pages.
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.bits.iter().all(|&w| w == 0)
+    }
+
+    pub fn clear(&mut self) {
+        self.bits.fill(0);
+    }
+
+    /// Marks every page dirty in `other` as dirty in `self`, i.e. to accumulate successive queries.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bitmaps cover different ranges.
+    pub fn union_with(&mut self, other: &DirtyBitmap) {
+        assert!(
+            self.guest_address == other.guest_address && self.pages == other.pages,
+            "dirty bitmaps cover different ranges"
+        );
+        for (dst, src) in self.bits.iter_mut().zip(&other.bits) {
+            *dst |= src;
+        }
+    }
+
+    /// The page frame numbers of the dirty pages, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        let first_pfn = self.guest_address / PAGE_SIZE as u64;
+        self.bits.iter().enumerate().flat_map(move |(i, &word)| {
+            let mut word = word;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = word.trailing_zeros() as u64;
+                word &= word - 1;
+                Some(first_pfn + i as u64 * 64 + bit)
+            })
         })
     }
 }
 
+#[cfg(windows)]
+fn alloc_volatile(size: usize) -> usize {
+    // TODO: Should we use MEM_RESERVE?
+    let address = unsafe { VirtualAlloc(None, size, MEM_RESERVE | MEM_COMMIT, PAGE_READWRITE) };
+    address.addr()
+}
+
+#[cfg(windows)]
+fn map_file(
+    guest_address: usize,
+    flags: MapGpaRangeFlags,
+    file: &File,
+    protection: PAGE_PROTECTION_FLAGS,
+    access: FILE_MAP,
+    backing: RegionBacking,
+) -> Result<MemoryRegion> {
+    let file_len = file.metadata()?.len();
+
+    let raw_file_handle = file.as_raw_handle();
+    // TODO: Check make sure its valid handle.
+
+    let mapping_handle =
+        unsafe { CreateFileMappingA(HANDLE(raw_file_handle as _), None, protection, 0, 0, None)? };
+
+    let address = unsafe { MapViewOfFile(mapping_handle, access, 0, 0, 0) };
+    // Capture the error before closing the mapping can overwrite it.
+    let view_error = address
+        .Value
+        .is_null()
+        .then(windows::core::Error::from_win32);
+
+    unsafe { CloseHandle(mapping_handle)? };
+    if let Some(error) = view_error {
+        return Err(error.into());
+    }
+
+    Ok(MemoryRegion {
+        address: address.Value.addr(),
+        guest_address,
+        flags,
+        size: file_len.try_into()?,
+        backing,
+    })
+}
+
+/// Copied pages of a copy-on-write view turn from `PAGE_WRITECOPY` into `PAGE_READWRITE`.
+#[cfg(windows)]
+fn count_copied_pages(address: usize, size: usize) -> usize {
+    let end = address + size.next_multiple_of(PAGE_SIZE);
+    let mut copied = 0;
+    let mut cur = address;
+    while cur < end {
+        let mut info = MEMORY_BASIC_INFORMATION::default();
+        let written = unsafe {
+            VirtualQuery(
+                Some(cur as *const _),
+                &mut info,
+                std::mem::size_of::<MEMORY_BASIC_INFORMATION>(),
+            )
+        };
+        if written == 0 {
+            break;
+        }
+
+        let region_end = (info.BaseAddress.addr() + info.RegionSize).min(end);
+        if info.Protect == PAGE_READWRITE {
+            copied += (region_end - cur) / PAGE_SIZE;
+        }
+        cur = region_end;
+    }
+    copied
+}
+
+#[cfg(windows)]
+fn free_volatile(address: usize, _size: usize) {
+    unsafe { VirtualFree(address as *mut _, 0, MEM_RELEASE).unwrap() }
+}
+
+#[cfg(not(windows))]
+fn volatile_layout(size: usize) -> std::alloc::Layout {
+    // Match `VirtualAlloc`, which hands out whole zeroed pages.
+    let size = size.max(1).next_multiple_of(PAGE_SIZE);
+    std::alloc::Layout::from_size_align(size, PAGE_SIZE).unwrap()
+}
+
+#[cfg(not(windows))]
+fn alloc_volatile(size: usize) -> usize {
+    let layout = volatile_layout(size);
+    let address = unsafe { std::alloc::alloc_zeroed(layout) };
+    if address.is_null() {
+        std::alloc::handle_alloc_error(layout);
+    }
+    address.addr()
+}
+
+#[cfg(not(windows))]
+fn free_volatile(address: usize, size: usize) {
+    unsafe { std::alloc::dealloc(address as *mut u8, volatile_layout(size)) }
+}
+
 impl Drop for MemoryRegion {
     fn drop(&mut self) {
         match self.backing {
-            RegionBacking::Volatile => unsafe {
-                VirtualFree(self.address as *mut _, 0, MEM_RELEASE).unwrap()
-            },
-            RegionBacking::File => unsafe {
+            RegionBacking::Volatile => free_volatile(self.address, self.size),
+            #[cfg(windows)]
+            RegionBacking::File | RegionBacking::CopyOnWrite => unsafe {
                 UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS {
                     Value: self.address as _,
                 })
                 .unwrap()
             },
+            #[cfg(not(windows))]
+            RegionBacking::File | RegionBacking::CopyOnWrite => {
+                unreachable!("file backed regions are only created on windows")
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
-
-    use tempfile::tempfile;
-
-    use crate::flags::MapGpaRangeFlags;
+    use crate::{flags::MapGpaRangeFlags, Error};
 
-    use super::MemoryRegion;
+    use super::{DirtyBitmap, GuestMemory, MemoryRegion};
 
     #[test]
     fn map_bytes() {
@@ -124,8 +472,13 @@ mod tests {
         assert_ne!(mr.address, 0);
     }
 
+    #[cfg(windows)]
     #[test]
     fn map_file() {
+        use std::io::Write;
+
+        use tempfile::tempfile;
+
         let mut file = tempfile().unwrap();
         writeln!(file, "Hello world").unwrap();
 
@@ -137,4 +490,119 @@ mod tests {
         .unwrap();
         assert_eq!(mr.size, 12);
     }
+
+    #[cfg(windows)]
+    #[test]
+    fn map_file_read_only() {
+        use std::{fs::File, io::Write};
+
+        use tempfile::NamedTempFile;
+
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&[0xaa; 0x1000]).unwrap();
+
+        // A writable view of a read-only handle fails instead of mapping address zero.
+        let read_only = File::open(file.path()).unwrap();
+        assert!(MemoryRegion::from_file(0x1000, MapGpaRangeFlags::Read, read_only).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn map_file_copy_on_write() {
+        use std::io::{Read, Seek, Write};
+
+        use tempfile::tempfile;
+
+        use super::RegionBacking;
+
+        let mut file = tempfile().unwrap();
+        file.write_all(&[0xaa; 0x3000]).unwrap();
+
+        let regions = [MemoryRegion::from_file_copy_on_write(
+            0x1000,
+            MapGpaRangeFlags::Read | MapGpaRangeFlags::Write,
+            file.try_clone().unwrap(),
+        )
+        .unwrap()];
+        assert_eq!(regions[0].backing, RegionBacking::CopyOnWrite);
+        let mem = GuestMemory::new(&regions);
+        assert_eq!(mem.read_obj::<u8>(0x2000).unwrap(), 0xaa);
+        assert_eq!(regions[0].copied_pages(), 0);
+
+        mem.write_obj(0x2000, 0x55_u8).unwrap();
+        assert_eq!(mem.read_obj::<u8>(0x2000).unwrap(), 0x55);
+        assert_eq!(regions[0].copied_pages(), 1);
+
+        let mut contents = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert!(contents.iter().all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn guest_memory_spanning() {
+        let regions = [
+            MemoryRegion::from_bytes(0x1000, MapGpaRangeFlags::Read, &[0xaa; 0x1000]),
+            MemoryRegion::from_bytes(0x2000, MapGpaRangeFlags::Read, &[0xbb; 0x1000]),
+        ];
+        let mem = GuestMemory::new(&regions);
+
+        let mut buf = [0; 4];
+        mem.read(0x1ffe, &mut buf).unwrap();
+        assert_eq!(buf, [0xaa, 0xaa, 0xbb, 0xbb]);
+
+        mem.write_obj(0x1ffc, 0x1122334455667788_u64).unwrap();
+        assert_eq!(mem.read_obj::<u64>(0x1ffc).unwrap(), 0x1122334455667788);
+        assert_eq!(mem.read_obj::<u32>(0x2000).unwrap(), 0x11223344);
+        assert_eq!(mem.read_obj::<[u8; 2]>(0x1ffc).unwrap(), [0x88, 0x77]);
+        assert_eq!(mem.region(0x2fff).unwrap().guest_address, 0x2000);
+    }
+
+    #[test]
+    fn guest_memory_unmapped() {
+        let regions = [
+            MemoryRegion::from_bytes(0x1000, MapGpaRangeFlags::Read, &[0xaa; 0x1000]),
+            MemoryRegion::from_bytes(0x3000, MapGpaRangeFlags::Read, &[0xbb; 0x1000]),
+        ];
+        let mem = GuestMemory::new(&regions);
+
+        assert!(matches!(
+            mem.read_obj::<u32>(0x500),
+            Err(Error::GpaUnmapped(0x500))
+        ));
+        // A failed write across the hole must not touch the mapped part.
+        assert!(matches!(
+            mem.write(0x1ffe, &[0; 4]),
+            Err(Error::GpaUnmapped(0x2000))
+        ));
+        assert_eq!(mem.read_obj::<u16>(0x1ffe).unwrap(), 0xaaaa);
+        assert!(matches!(
+            mem.read_obj::<u32>(u64::MAX - 1),
+            Err(Error::GpaOutOfRange(_, 4))
+        ));
+    }
+
+    #[test]
+    fn dirty_bitmap() {
+        let mut a = DirtyBitmap::from_words(0x10000, 0x42000, &[1 << 3 | 1 << 63, u64::MAX]);
+        assert_eq!(a.pages(), 0x42);
+        // Only the two pages past the first word are covered by the range.
+        assert_eq!(a.words(), &[1 << 3 | 1 << 63, 0b11]);
+        assert_eq!(a.count(), 4);
+        assert_eq!(a.iter().collect::<Vec<_>>(), [0x13, 0x4f, 0x50, 0x51]);
+        assert!(a.is_dirty(0x41));
+        assert!(!a.is_dirty(0x42));
+
+        let mut b = DirtyBitmap::new(0x10000, 0x42000);
+        assert!(b.is_clean());
+        b.set_dirty(0);
+        b.set_dirty(3);
+        a.union_with(&b);
+        assert_eq!(a.count(), 5);
+        assert_eq!(a.iter().next(), Some(0x10));
+
+        a.clear();
+        assert!(a.is_clean());
+        assert_eq!(a.iter().count(), 0);
+    }
 }
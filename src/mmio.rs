@@ -0,0 +1,713 @@
+use crate::{
+    decode::{size_mask, Instruction, OpcodeMap, Prefixes, RegisterOperand},
+    processor::{
+        ExitContext, MemoryAccessContext, MemoryAccessType, Register, RegisterFile,
+        VirtualProcessor,
+    },
+    Error, Result,
+};
+
+const RFLAGS_CF: u64 = 1 << 0;
+const RFLAGS_PF: u64 = 1 << 2;
+const RFLAGS_AF: u64 = 1 << 4;
+const RFLAGS_ZF: u64 = 1 << 6;
+const RFLAGS_SF: u64 = 1 << 7;
+const RFLAGS_DF: u64 = 1 << 10;
+const RFLAGS_OF: u64 = 1 << 11;
+
+/// A device claiming a range of guest physical addresses that are not backed by memory.
+///
+/// `offset` is relative to the start of the range the handler was registered with.
+pub trait MmioHandler: Send {
+    fn read(&mut self, offset: u64, data: &mut [u8]);
+
+    fn write(&mut self, offset: u64, data: &[u8]);
+}
+
+/// Where [`emulate`] sends the accesses of the instruction it completes.
+pub trait MmioMemory {
+    fn read_mmio(&mut self, gpa: u64, data: &mut [u8]) -> Result<()>;
+
+    fn write_mmio(&mut self, gpa: u64, data: &[u8]) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogicOp {
+    And,
+    Or,
+    /// `and` without storing the result.
+    Test,
+}
+
+impl LogicOp {
+    fn apply(&self, a: u64, b: u64) -> u64 {
+        match self {
+            LogicOp::And | LogicOp::Test => a & b,
+            LogicOp::Or => a | b,
+        }
+    }
+}
+
+/// Complete the instruction behind a memory access exit against `mmio`.
+///
+/// The forms compilers emit for device memory are understood: `mov` to and from registers and of
+/// immediates including the `moffs` forms, `movzx`/`movsx`, `stos`, and `and`, `or` and `test` with
+/// a register or an immediate, which update the arithmetic flags. Destination registers, RFLAGS
+/// and RIP are written back through `registers`.
+///
+/// The direction of the exit has to agree with the instruction, a read exit only completes loads
+/// and `test`, a write exit only stores and the read-modify-write logic ops. Execute exits are
+/// never completed.
+///
+/// The access always goes to the `gpa` of the exit. A `rep stos` therefore stores one element per
+/// exit and leaves RIP on the instruction until RCX runs out, the guest exits again for the next
+/// element.
+pub fn emulate(
+    instruction: &Instruction,
+    context: &ExitContext,
+    access: &MemoryAccessContext,
+    registers: &mut impl RegisterFile,
+    mmio: &mut impl MmioMemory,
+) -> Result<()> {
+    let unsupported =
+        || Error::UnsupportedInstruction(access.instruction_bytes[..instruction.len].to_vec());
+    let gpa = access.gpa;
+    let access_type = access
+        .access_info
+        .access_type()
+        .filter(|ty| *ty != MemoryAccessType::Execute)
+        .ok_or_else(unsupported)?;
+    let expect = |ty: MemoryAccessType| match access_type == ty {
+        true => Ok(()),
+        false => Err(unsupported()),
+    };
+    let size = instruction
+        .memory()
+        .map_or(instruction.operand_size, |memory| memory.size);
+    let mask = size_mask(size);
+    let register = || instruction.register.ok_or_else(unsupported);
+    let immediate = || {
+        instruction
+            .immediate
+            .map(|imm| imm.sign_extended() & mask)
+            .ok_or_else(unsupported)
+    };
+    let accumulator = RegisterOperand {
+        register: Register::Rax,
+        size,
+        high_byte: false,
+    };
+    let opcode = instruction.opcode;
+    let logic_op = |or: bool| if or { LogicOp::Or } else { LogicOp::And };
+
+    let mut rip = context.rip + instruction.len as u64;
+    // The result of a logic op, to compute the flags from.
+    let mut result = None;
+    match (instruction.map, opcode) {
+        (OpcodeMap::OneByte, 0xaa | 0xab) => {
+            expect(MemoryAccessType::Write)?;
+            let rep = instruction
+                .prefixes
+                .intersects(Prefixes::Rep | Prefixes::Repne);
+            // RCX and RDI are only updated at the address size.
+            let counter = |register| RegisterOperand {
+                register,
+                size: instruction.address_size,
+                high_byte: false,
+            };
+            let rcx = registers.read_register(Register::Rcx)?;
+            if !rep || counter(Register::Rcx).read(rcx) != 0 {
+                let value = accumulator.read(registers.read_register(Register::Rax)?);
+                store(mmio, gpa, value, size)?;
+
+                let rdi = registers.read_register(Register::Rdi)?;
+                let step = if context.rflags & RFLAGS_DF != 0 {
+                    (size as u64).wrapping_neg()
+                } else {
+                    size as u64
+                };
+                let rdi = counter(Register::Rdi).merge(rdi, rdi.wrapping_add(step));
+                registers.write_register(Register::Rdi, rdi)?;
+                if rep {
+                    let rcx = counter(Register::Rcx).merge(rcx, rcx.wrapping_sub(1));
+                    registers.write_register(Register::Rcx, rcx)?;
+                    if counter(Register::Rcx).read(rcx) != 0 {
+                        rip = context.rip;
+                    }
+                }
+            }
+        }
+        _ if instruction.memory().is_none() => return Err(unsupported()),
+        (OpcodeMap::OneByte, 0x88 | 0x89) => {
+            expect(MemoryAccessType::Write)?;
+            let source = register()?;
+            let value = source.read(registers.read_register(source.register)?);
+            store(mmio, gpa, value, size)?;
+        }
+        (OpcodeMap::OneByte, 0x8a | 0x8b) => {
+            expect(MemoryAccessType::Read)?;
+            let value = load(mmio, gpa, size)?;
+            write_operand(registers, register()?, value)?;
+        }
+        (OpcodeMap::OneByte, 0xc6 | 0xc7) if instruction.extension() == Some(0) => {
+            expect(MemoryAccessType::Write)?;
+            store(mmio, gpa, immediate()?, size)?;
+        }
+        (OpcodeMap::OneByte, 0xa0 | 0xa1) => {
+            expect(MemoryAccessType::Read)?;
+            let value = load(mmio, gpa, size)?;
+            write_operand(registers, accumulator, value)?;
+        }
+        (OpcodeMap::OneByte, 0xa2 | 0xa3) => {
+            expect(MemoryAccessType::Write)?;
+            let value = accumulator.read(registers.read_register(Register::Rax)?);
+            store(mmio, gpa, value, size)?;
+        }
+        (OpcodeMap::TwoByte, 0xb6 | 0xb7 | 0xbe | 0xbf) => {
+            expect(MemoryAccessType::Read)?;
+            let mut value = load(mmio, gpa, size)?;
+            if opcode & 0x8 != 0 {
+                let shift = 64 - 8 * size;
+                value = ((value << shift) as i64 >> shift) as u64;
+            }
+            write_operand(registers, register()?, value)?;
+        }
+        // and/or r/m, reg
+        (OpcodeMap::OneByte, 0x08 | 0x09 | 0x20 | 0x21) => {
+            expect(MemoryAccessType::Write)?;
+            let source = register()?;
+            let value = source.read(registers.read_register(source.register)?);
+            let value = logic_op(opcode < 0x20).apply(load(mmio, gpa, size)?, value);
+            store(mmio, gpa, value, size)?;
+            result = Some(value);
+        }
+        // and/or reg, r/m
+        (OpcodeMap::OneByte, 0x0a | 0x0b | 0x22 | 0x23) => {
+            expect(MemoryAccessType::Read)?;
+            let destination = register()?;
+            let value = destination.read(registers.read_register(destination.register)?);
+            let value = logic_op(opcode < 0x20).apply(value, load(mmio, gpa, size)?);
+            write_operand(registers, destination, value)?;
+            result = Some(value);
+        }
+        // and/or r/m, imm
+        (OpcodeMap::OneByte, 0x80 | 0x81 | 0x83)
+            if matches!(instruction.extension(), Some(1 | 4)) =>
+        {
+            expect(MemoryAccessType::Write)?;
+            let op = logic_op(instruction.extension() == Some(1));
+            let value = op.apply(load(mmio, gpa, size)?, immediate()?);
+            store(mmio, gpa, value, size)?;
+            result = Some(value);
+        }
+        (OpcodeMap::OneByte, 0x84 | 0x85) => {
+            expect(MemoryAccessType::Read)?;
+            let source = register()?;
+            let value = source.read(registers.read_register(source.register)?);
+            result = Some(LogicOp::Test.apply(load(mmio, gpa, size)?, value));
+        }
+        (OpcodeMap::OneByte, 0xf6 | 0xf7) if instruction.extension() == Some(0) => {
+            expect(MemoryAccessType::Read)?;
+            result = Some(LogicOp::Test.apply(load(mmio, gpa, size)?, immediate()?));
+        }
+        _ => return Err(unsupported()),
+    }
+
+    if let Some(result) = result {
+        let rflags = logic_flags(context.rflags, result, size);
+        registers.write_register(Register::Rflags, rflags)?;
+    }
+    registers.write_register(Register::Rip, rip)
+}
+
+fn load(mmio: &mut impl MmioMemory, gpa: u64, size: usize) -> Result<u64> {
+    let mut data = [0; 8];
+    mmio.read_mmio(gpa, &mut data[..size])?;
+    Ok(u64::from_le_bytes(data))
+}
+
+fn store(mmio: &mut impl MmioMemory, gpa: u64, value: u64, size: usize) -> Result<()> {
+    mmio.write_mmio(gpa, &value.to_le_bytes()[..size])
+}
+
+fn write_operand(
+    registers: &mut impl RegisterFile,
+    operand: RegisterOperand,
+    value: u64,
+) -> Result<()> {
+    let old = registers.read_register(operand.register)?;
+    registers.write_register(operand.register, operand.merge(old, value))
+}
+
+/// The flags after a logic op, CF and OF are cleared and AF is left undefined as zero.
+fn logic_flags(rflags: u64, result: u64, size: usize) -> u64 {
+    let result = result & size_mask(size);
+    let mut rflags =
+        rflags & !(RFLAGS_CF | RFLAGS_PF | RFLAGS_AF | RFLAGS_ZF | RFLAGS_SF | RFLAGS_OF);
+    if result == 0 {
+        rflags |= RFLAGS_ZF;
+    }
+    if result >> (8 * size - 1) != 0 {
+        rflags |= RFLAGS_SF;
+    }
+    // PF only looks at the low byte.
+    if (result as u8).count_ones().is_multiple_of(2) {
+        rflags |= RFLAGS_PF;
+    }
+    rflags
+}
+
+struct MmioDevice {
+    guest_address: u64,
+    size: u64,
+    handler: Box<dyn MmioHandler>,
+}
+
+impl MmioDevice {
+    fn contains(&self, gpa: u64) -> bool {
+        gpa >= self.guest_address && gpa - self.guest_address < self.size
+    }
+}
+
+/// Routes memory access exits to the [`MmioHandler`] registered for the guest physical address.
+#[derive(Default)]
+pub struct MmioBus {
+    devices: Vec<MmioDevice>,
+}
+
+impl std::fmt::Debug for MmioBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(
+                self.devices
+                    .iter()
+                    .map(|d| d.guest_address..d.guest_address + d.size),
+            )
+            .finish()
+    }
+}
+
+impl MmioBus {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Claim `size` bytes starting at `guest_address` for `handler`, ranges must not overlap.
+    pub fn register(
+        &mut self,
+        guest_address: u64,
+        size: u64,
+        handler: Box<dyn MmioHandler>,
+    ) -> Result<()> {
+        let end = guest_address
+            .checked_add(size)
+            .ok_or(Error::GpaOutOfRange(guest_address, size as usize))?;
+        if let Some(other) = self
+            .devices
+            .iter()
+            .find(|d| guest_address < d.guest_address + d.size && d.guest_address < end)
+        {
+            return Err(Error::OverlappingRegion(
+                guest_address,
+                size as usize,
+                other.guest_address,
+                other.size as usize,
+            ));
+        }
+
+        self.devices.push(MmioDevice {
+            guest_address,
+            size,
+            handler,
+        });
+        Ok(())
+    }
+
+    /// Remove the handler registered for the range containing `gpa`.
+    pub fn unregister(&mut self, gpa: u64) -> Option<Box<dyn MmioHandler>> {
+        let index = self.devices.iter().position(|d| d.contains(gpa))?;
+        Some(self.devices.remove(index).handler)
+    }
+
+    fn device(&mut self, gpa: u64) -> Result<&mut MmioDevice> {
+        self.devices
+            .iter_mut()
+            .find(|d| d.contains(gpa))
+            .ok_or(Error::MmioUnclaimed(gpa))
+    }
+
+    pub fn read(&mut self, gpa: u64, data: &mut [u8]) -> Result<()> {
+        let device = self.device(gpa)?;
+        device.handler.read(gpa - device.guest_address, data);
+        Ok(())
+    }
+
+    pub fn write(&mut self, gpa: u64, data: &[u8]) -> Result<()> {
+        let device = self.device(gpa)?;
+        device.handler.write(gpa - device.guest_address, data);
+        Ok(())
+    }
+
+    /// Complete a memory access exit with [`emulate`], moving the data between the handlers and
+    /// the guest's registers.
+    pub fn dispatch(
+        &mut self,
+        vcpu: &mut VirtualProcessor,
+        context: &ExitContext,
+        access: &MemoryAccessContext,
+    ) -> Result<Instruction> {
+        let instruction = access.instruction(context)?;
+        emulate(&instruction, context, access, vcpu, self)?;
+        Ok(instruction)
+    }
+}
+
+impl MmioMemory for MmioBus {
+    fn read_mmio(&mut self, gpa: u64, data: &mut [u8]) -> Result<()> {
+        self.read(gpa, data)
+    }
+
+    fn write_mmio(&mut self, gpa: u64, data: &[u8]) -> Result<()> {
+        self.write(gpa, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{
+        backend::MockBackend,
+        flags::{MemoryAccessInfo, X64ExecutionState, X64SegmentRegisterAttributes},
+        partition::{PartitionBuilder, PartitionProperty},
+        processor::{ExitContext, MemoryAccessContext, Register, RegisterVal, SegmentRegister},
+        Error, Result,
+    };
+
+    use super::{emulate, MmioBus, MmioHandler, MmioMemory};
+
+    fn long_mode() -> ExitContext {
+        let mut context = ExitContext::default();
+        context.execution_state = X64ExecutionState::Cr0Pe | X64ExecutionState::EferLma;
+        context.cs = SegmentRegister {
+            attributes: X64SegmentRegisterAttributes::Long,
+            ..Default::default()
+        };
+        context.rip = 0x1000;
+        context
+    }
+
+    fn access(bytes: &[u8], write: bool) -> MemoryAccessContext {
+        let mut instruction_bytes = [0; 16];
+        instruction_bytes[..bytes.len()].copy_from_slice(bytes);
+        MemoryAccessContext {
+            instruction_byte_count: 16,
+            instruction_bytes,
+            access_info: MemoryAccessInfo::from_bits_retain(write as u32),
+            gpa: 0xfee0_0010,
+            gva: 0xfee0_0010,
+        }
+    }
+
+    type Registers = HashMap<Register, u64>;
+
+    /// Device memory that reads back as `0x80 | offset` and records every write.
+    #[derive(Default)]
+    struct Device {
+        writes: Vec<(u64, Vec<u8>)>,
+    }
+
+    impl MmioMemory for Device {
+        fn read_mmio(&mut self, gpa: u64, data: &mut [u8]) -> Result<()> {
+            for (i, b) in data.iter_mut().enumerate() {
+                *b = 0x80 | (gpa as u8 + i as u8);
+            }
+            Ok(())
+        }
+
+        fn write_mmio(&mut self, gpa: u64, data: &[u8]) -> Result<()> {
+            self.writes.push((gpa, data.to_vec()));
+            Ok(())
+        }
+    }
+
+    fn run(
+        context: &ExitContext,
+        bytes: &[u8],
+        write: bool,
+        registers: &mut Registers,
+        device: &mut Device,
+    ) -> Result<()> {
+        let access = access(bytes, write);
+        let instruction = access.instruction(context)?;
+        emulate(&instruction, context, &access, registers, device)
+    }
+
+    #[test]
+    fn emulate_moves() {
+        let ctx = long_mode();
+        let mut device = Device::default();
+        let mut regs = Registers::from([
+            (Register::Rsi, 0x1122_3344_5566_7788),
+            (Register::Rax, 0xaabb_ccdd_eeff_0011),
+        ]);
+
+        // mov dword [rdi+0x10], esi
+        run(&ctx, &[0x89, 0x77, 0x10], true, &mut regs, &mut device).unwrap();
+        assert_eq!(device.writes, [(0xfee0_0010, vec![0x88, 0x77, 0x66, 0x55])]);
+        assert_eq!(regs[&Register::Rip], 0x1003);
+
+        // mov r9, qword [rax+rbx*4+0x12345678]
+        let bytes = [0x4c, 0x8b, 0x8c, 0x98, 0x78, 0x56, 0x34, 0x12];
+        run(&ctx, &bytes, false, &mut regs, &mut device).unwrap();
+        assert_eq!(regs[&Register::R9], 0x9796_9594_9392_9190);
+        assert_eq!(regs[&Register::Rip], 0x1008);
+
+        // mov word [rip+0x100], 0xbeef
+        let bytes = [0x66, 0xc7, 0x05, 0, 1, 0, 0, 0xef, 0xbe];
+        run(&ctx, &bytes, true, &mut regs, &mut device).unwrap();
+        assert_eq!(device.writes[1], (0xfee0_0010, vec![0xef, 0xbe]));
+
+        // mov byte [rbx], ah
+        run(&ctx, &[0x88, 0x23], true, &mut regs, &mut device).unwrap();
+        assert_eq!(device.writes[2], (0xfee0_0010, vec![0x00]));
+
+        // movsx rcx, word [rcx] and movzx edx, byte [rcx]
+        run(
+            &ctx,
+            &[0x48, 0x0f, 0xbf, 0x09],
+            false,
+            &mut regs,
+            &mut device,
+        )
+        .unwrap();
+        assert_eq!(regs[&Register::Rcx], 0xffff_ffff_ffff_9190);
+        regs.insert(Register::Rdx, u64::MAX);
+        run(&ctx, &[0x0f, 0xb6, 0x11], false, &mut regs, &mut device).unwrap();
+        assert_eq!(regs[&Register::Rdx], 0x90);
+
+        // 16-bit real mode: mov ax, [0x1234] keeps the upper bits.
+        let mut real_mode = ExitContext::default();
+        real_mode.rip = 0x7c00;
+        run(
+            &real_mode,
+            &[0xa1, 0x34, 0x12],
+            false,
+            &mut regs,
+            &mut device,
+        )
+        .unwrap();
+        assert_eq!(regs[&Register::Rax], 0xaabb_ccdd_eeff_9190);
+        assert_eq!(regs[&Register::Rip], 0x7c03);
+
+        // The direction has to agree with the exit.
+        assert!(matches!(
+            run(&ctx, &[0x89, 0x77, 0x10], false, &mut regs, &mut device),
+            Err(Error::UnsupportedInstruction(_))
+        ));
+        assert!(matches!(
+            run(&ctx, &[0x0f, 0xb6, 0x11], true, &mut regs, &mut device),
+            Err(Error::UnsupportedInstruction(_))
+        ));
+        // Neither an execute exit nor the undefined access type are data accesses.
+        for ty in [2, 3] {
+            let mut access = access(&[0x8b, 0x00], false);
+            access.access_info = MemoryAccessInfo::from_bits_retain(ty);
+            let instruction = access.instruction(&ctx).unwrap();
+            assert!(matches!(
+                emulate(&instruction, &ctx, &access, &mut regs, &mut device),
+                Err(Error::UnsupportedInstruction(_))
+            ));
+        }
+        assert_eq!(device.writes.len(), 3);
+
+        // add [rax], eax
+        assert!(matches!(
+            run(&ctx, &[0x01, 0x00], true, &mut regs, &mut device),
+            Err(Error::UnsupportedInstruction(_))
+        ));
+    }
+
+    #[test]
+    fn emulate_logic() {
+        let mut ctx = long_mode();
+        ctx.rflags = 0x2 | super::RFLAGS_CF | super::RFLAGS_OF;
+        let mut device = Device::default();
+        let mut regs = Registers::from([(Register::Rcx, 0x0f00)]);
+
+        // or dword [rax], ecx
+        run(&ctx, &[0x09, 0x08], true, &mut regs, &mut device).unwrap();
+        assert_eq!(device.writes, [(0xfee0_0010, vec![0x90, 0x9f, 0x92, 0x93])]);
+        // PF only looks at the low byte, CF and OF are cleared.
+        assert_eq!(
+            regs[&Register::Rflags],
+            0x2 | super::RFLAGS_SF | super::RFLAGS_PF
+        );
+
+        // and ecx, dword [rax]
+        run(&ctx, &[0x23, 0x08], false, &mut regs, &mut device).unwrap();
+        assert_eq!(regs[&Register::Rcx], 0x100);
+        assert_eq!(regs[&Register::Rflags], 0x2 | super::RFLAGS_PF);
+
+        // and byte [rax], 0x40
+        run(&ctx, &[0x80, 0x20, 0x40], true, &mut regs, &mut device).unwrap();
+        assert_eq!(device.writes[1], (0xfee0_0010, vec![0]));
+        assert_eq!(
+            regs[&Register::Rflags],
+            0x2 | super::RFLAGS_ZF | super::RFLAGS_PF
+        );
+
+        // test qword [rax], -1 only updates the flags.
+        run(
+            &ctx,
+            &[0x48, 0xf7, 0x00, 0xff, 0xff, 0xff, 0xff],
+            false,
+            &mut regs,
+            &mut device,
+        )
+        .unwrap();
+        assert_eq!(device.writes.len(), 2);
+        assert_eq!(
+            regs[&Register::Rflags],
+            0x2 | super::RFLAGS_SF | super::RFLAGS_PF
+        );
+        assert_eq!(regs[&Register::Rip], 0x1007);
+
+        // A read-modify-write on a read exit and a test on a write exit.
+        assert!(matches!(
+            run(&ctx, &[0x09, 0x08], false, &mut regs, &mut device),
+            Err(Error::UnsupportedInstruction(_))
+        ));
+        assert!(matches!(
+            run(&ctx, &[0xf6, 0x00, 0x01], true, &mut regs, &mut device),
+            Err(Error::UnsupportedInstruction(_))
+        ));
+        assert_eq!(device.writes.len(), 2);
+    }
+
+    #[test]
+    fn emulate_rep_stos() {
+        let mut ctx = long_mode();
+        ctx.rflags = super::RFLAGS_DF;
+        let mut device = Device::default();
+        let mut regs = Registers::from([
+            (Register::Rax, 0x1234_5678),
+            (Register::Rcx, 2),
+            (Register::Rdi, 0x1000),
+        ]);
+
+        // rep stosd, the first element leaves RIP on the instruction.
+        run(&ctx, &[0xf3, 0xab], true, &mut regs, &mut device).unwrap();
+        assert_eq!(
+            (
+                regs[&Register::Rcx],
+                regs[&Register::Rdi],
+                regs[&Register::Rip]
+            ),
+            (1, 0xffc, 0x1000)
+        );
+        run(&ctx, &[0xf3, 0xab], true, &mut regs, &mut device).unwrap();
+        assert_eq!(
+            (
+                regs[&Register::Rcx],
+                regs[&Register::Rdi],
+                regs[&Register::Rip]
+            ),
+            (0, 0xff8, 0x1002)
+        );
+        assert_eq!(
+            device.writes,
+            vec![(0xfee0_0010, vec![0x78, 0x56, 0x34, 0x12]); 2]
+        );
+
+        // stosb with a 32-bit address size zero extends RDI.
+        regs.insert(Register::Rdi, 0xffff_ffff_0000_0000);
+        run(&ctx, &[0x67, 0xaa], true, &mut regs, &mut device).unwrap();
+        assert_eq!(regs[&Register::Rdi], 0xffff_ffff);
+    }
+
+    type Writes = Arc<Mutex<Vec<(u64, Vec<u8>)>>>;
+
+    #[derive(Default)]
+    struct Scratch(Writes);
+
+    impl MmioHandler for Scratch {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            for (i, b) in data.iter_mut().enumerate() {
+                *b = 0x80 | (offset as u8 + i as u8);
+            }
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) {
+            self.0.lock().unwrap().push((offset, data.to_vec()));
+        }
+    }
+
+    #[test]
+    fn dispatch() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(1))
+            .unwrap()
+            .setup()
+            .unwrap();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+
+        let writes = Writes::default();
+        let mut bus = MmioBus::new();
+        bus.register(0xfee0_0000, 0x1000, Box::new(Scratch(writes.clone())))
+            .unwrap();
+        assert!(matches!(
+            bus.register(0xfee0_0800, 0x1000, Box::<Scratch>::default()),
+            Err(Error::OverlappingRegion(..))
+        ));
+
+        let ctx = long_mode();
+        vcpu.set_register(Register::Rsi, 0x1122_3344_5566_7788_u64.into())
+            .unwrap();
+        bus.dispatch(&mut vcpu, &ctx, &access(&[0x89, 0x77, 0x10], true))
+            .unwrap();
+        assert_eq!(
+            *writes.lock().unwrap(),
+            [(0x10, vec![0x88, 0x77, 0x66, 0x55])]
+        );
+        assert_eq!(
+            mock.register(0, Register::Rip),
+            Some(RegisterVal::Reg64(0x1003))
+        );
+
+        // A 32-bit load clears the upper half, a 16-bit one leaves it alone.
+        vcpu.set_register(Register::Rax, u64::MAX.into()).unwrap();
+        bus.dispatch(&mut vcpu, &ctx, &access(&[0x8b, 0x00], false))
+            .unwrap();
+        assert_eq!(
+            mock.register(0, Register::Rax),
+            Some(RegisterVal::Reg64(0x9392_9190))
+        );
+        vcpu.set_register(Register::Rax, u64::MAX.into()).unwrap();
+        bus.dispatch(&mut vcpu, &ctx, &access(&[0x66, 0x8b, 0x00], false))
+            .unwrap();
+        assert_eq!(
+            mock.register(0, Register::Rax),
+            Some(RegisterVal::Reg64(0xffff_ffff_ffff_9190))
+        );
+
+        // movsx rax, byte [rax]
+        bus.dispatch(&mut vcpu, &ctx, &access(&[0x48, 0x0f, 0xbe, 0x00], false))
+            .unwrap();
+        assert_eq!(
+            mock.register(0, Register::Rax),
+            Some(RegisterVal::Reg64(0xffff_ffff_ffff_ff90))
+        );
+
+        let mut unclaimed = access(&[0x8b, 0x00], false);
+        unclaimed.gpa = 0x1000;
+        assert!(matches!(
+            bus.dispatch(&mut vcpu, &ctx, &unclaimed),
+            Err(Error::MmioUnclaimed(0x1000))
+        ));
+    }
+}
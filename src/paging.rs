@@ -0,0 +1,523 @@
+use bitflags::bitflags;
+
+use crate::{memory::GuestMemoryRead, Error, Result};
+
+const CR0_WP: u64 = 1 << 16;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PSE: u64 = 1 << 4;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+const EFER_LMA: u64 = 1 << 10;
+const EFER_NXE: u64 = 1 << 11;
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_LARGE: u64 = 1 << 7;
+const PTE_NX: u64 = 1 << 63;
+const PTE_ADDRESS: u64 = 0x000f_ffff_ffff_f000;
+/// Bits 62:52 are reserved in PAE entries, they hold protection keys and software bits otherwise.
+const PAE_RESERVED: u64 = 0x7ff0_0000_0000_0000;
+/// Bits 2:1 and 8:5 of a PAE page directory pointer table entry are reserved.
+const PDPTE_RESERVED: u64 = 0x1e6;
+
+bitflags! {
+    /// The kind of access being translated, the bits line up with [`PageFaultErrorCode`].
+    ///
+    /// A supervisor data read is the empty set.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct PageAccess: u32 {
+        const Write = 1 << 1;
+        const User = 1 << 2;
+        const Execute = 1 << 4;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct PageFaultErrorCode: u32 {
+        const Present = 1 << 0;
+        const Write = 1 << 1;
+        const User = 1 << 2;
+        const ReservedBit = 1 << 3;
+        const InstructionFetch = 1 << 4;
+    }
+}
+
+/// A #PF the guest would take for the translation, `address` is the value CR2 would hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFault {
+    pub address: u64,
+    pub error_code: PageFaultErrorCode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PagingMode {
+    Disabled,
+    Bits32,
+    Pae,
+    Level4,
+    Level5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub gpa: u64,
+    /// The size of the page mapping the address, i.e. 4KiB, 2MiB, 4MiB or 1GiB.
+    pub page_size: u64,
+}
+
+/// The control registers that select and configure paging, i.e. the values of [`crate::processor::Register::Cr0`],
+/// [`crate::processor::Register::Cr3`], [`crate::processor::Register::Cr4`] and [`crate::processor::Register::Efer`].
+///
+/// Walks are done in software against guest memory, accessed and dirty bits are never set and
+/// SMEP, SMAP and protection keys are not enforced.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PagingState {
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+}
+
+impl PagingState {
+    pub fn mode(&self) -> PagingMode {
+        if self.cr0 & CR0_PG == 0 {
+            PagingMode::Disabled
+        } else if self.cr4 & CR4_PAE == 0 {
+            PagingMode::Bits32
+        } else if self.efer & EFER_LMA == 0 {
+            PagingMode::Pae
+        } else if self.cr4 & CR4_LA57 == 0 {
+            PagingMode::Level4
+        } else {
+            PagingMode::Level5
+        }
+    }
+
+    /// Translate the guest virtual address `gva` for `access`, reading the page tables from `memory`.
+    ///
+    /// Fails with [`Error::PageFault`] when the guest would fault on the access and with
+    /// [`Error::NonCanonicalAddress`] when `gva` is not canonical in long mode.
+    pub fn translate(
+        &self,
+        memory: &impl GuestMemoryRead,
+        gva: u64,
+        access: PageAccess,
+    ) -> Result<Translation> {
+        match self.mode() {
+            PagingMode::Disabled => Ok(Translation {
+                gpa: gva & 0xffff_ffff,
+                page_size: 0x1000,
+            }),
+            PagingMode::Bits32 => self.walk_32bit(memory, gva & 0xffff_ffff, access),
+            PagingMode::Pae => {
+                let gva = gva & 0xffff_ffff;
+                let pdpte_address = (self.cr3 & 0xffff_ffe0) + ((gva >> 30) & 0x3) * 8;
+                let pdpte = read_entry::<u64>(memory, pdpte_address)?;
+                let fault = |error_code| self.fault(gva, access, error_code);
+                if pdpte & PTE_PRESENT == 0 {
+                    return Err(fault(PageFaultErrorCode::empty()));
+                }
+                if pdpte & (PDPTE_RESERVED | PAE_RESERVED | PTE_NX) != 0 {
+                    return Err(fault(
+                        PageFaultErrorCode::Present | PageFaultErrorCode::ReservedBit,
+                    ));
+                }
+                self.walk_pae(memory, gva, access, pdpte & PTE_ADDRESS, 2)
+            }
+            PagingMode::Level4 => {
+                check_canonical(gva, 48)?;
+                self.walk_pae(memory, gva, access, self.cr3 & PTE_ADDRESS, 4)
+            }
+            PagingMode::Level5 => {
+                check_canonical(gva, 57)?;
+                self.walk_pae(memory, gva, access, self.cr3 & PTE_ADDRESS, 5)
+            }
+        }
+    }
+
+    fn walk_32bit(
+        &self,
+        memory: &impl GuestMemoryRead,
+        gva: u64,
+        access: PageAccess,
+    ) -> Result<Translation> {
+        let fault = |error_code| self.fault(gva, access, error_code);
+
+        let pde = read_entry::<u32>(memory, (self.cr3 & 0xffff_f000) + (gva >> 22) * 4)?;
+        if pde & PTE_PRESENT == 0 {
+            return Err(fault(PageFaultErrorCode::empty()));
+        }
+
+        let (entry, translation) = if self.cr4 & CR4_PSE != 0 && pde & PTE_LARGE != 0 {
+            // Bits 20:13 hold physical address bits 39:32, bit 21 is reserved.
+            if pde & (1 << 21) != 0 {
+                return Err(fault(
+                    PageFaultErrorCode::Present | PageFaultErrorCode::ReservedBit,
+                ));
+            }
+            let base = (pde & 0xffc0_0000) | ((pde >> 13) & 0xff) << 32;
+            let translation = Translation {
+                gpa: base | (gva & 0x3f_ffff),
+                page_size: 0x40_0000,
+            };
+            (pde, translation)
+        } else {
+            let pte_address = (pde & 0xffff_f000) + ((gva >> 12) & 0x3ff) * 4;
+            let pte = read_entry::<u32>(memory, pte_address)?;
+            if pte & PTE_PRESENT == 0 {
+                return Err(fault(PageFaultErrorCode::empty()));
+            }
+            let translation = Translation {
+                gpa: (pte & 0xffff_f000) | (gva & 0xfff),
+                page_size: 0x1000,
+            };
+            (pde & pte, translation)
+        };
+
+        self.check_access(gva, access, entry, false)?;
+        Ok(translation)
+    }
+
+    /// Walk the PAE style tables starting at `level`, where the page table is level 1.
+    fn walk_pae(
+        &self,
+        memory: &impl GuestMemoryRead,
+        gva: u64,
+        access: PageAccess,
+        mut table: u64,
+        mut level: u32,
+    ) -> Result<Translation> {
+        let fault = |error_code| self.fault(gva, access, error_code);
+        let nxe = self.efer & EFER_NXE != 0;
+        let mut reserved = if self.mode() == PagingMode::Pae {
+            PAE_RESERVED
+        } else {
+            0
+        };
+        if !nxe {
+            reserved |= PTE_NX;
+        }
+
+        // The permissions of every level are combined, writable and user only if all levels allow it.
+        let mut permissions = PTE_WRITE | PTE_USER;
+        let mut nx = false;
+        loop {
+            let shift = 12 + 9 * (level - 1);
+            let entry = read_entry::<u64>(memory, table + ((gva >> shift) & 0x1ff) * 8)?;
+            if entry & PTE_PRESENT == 0 {
+                return Err(fault(PageFaultErrorCode::empty()));
+            }
+
+            let is_large = entry & PTE_LARGE != 0;
+            // Large pages exist at the page directory and page directory pointer levels only, the
+            // bits between the PAT bit and the page frame must be clear.
+            let large_reserved = match level {
+                2 | 3 if is_large => ((1 << shift) - 1) & !0x1fff,
+                4 | 5 if is_large => PTE_LARGE,
+                _ => 0,
+            };
+            if entry & (reserved | large_reserved) != 0 {
+                return Err(fault(
+                    PageFaultErrorCode::Present | PageFaultErrorCode::ReservedBit,
+                ));
+            }
+
+            permissions &= entry;
+            nx |= nxe && entry & PTE_NX != 0;
+
+            if level == 1 || is_large {
+                let page_size = 1 << shift;
+                self.check_access(gva, access, permissions, nx)?;
+                return Ok(Translation {
+                    gpa: (entry & PTE_ADDRESS & !(page_size - 1)) | (gva & (page_size - 1)),
+                    page_size,
+                });
+            }
+
+            table = entry & PTE_ADDRESS;
+            level -= 1;
+        }
+    }
+
+    /// Check `access` against the combined permissions of the walk.
+    fn check_access(&self, gva: u64, access: PageAccess, permissions: u64, nx: bool) -> Result<()> {
+        let user = access.contains(PageAccess::User);
+        let denied = (user && permissions & PTE_USER == 0)
+            || (access.contains(PageAccess::Write)
+                && permissions & PTE_WRITE == 0
+                && (user || self.cr0 & CR0_WP != 0))
+            || (access.contains(PageAccess::Execute) && nx);
+        if denied {
+            return Err(self.fault(gva, access, PageFaultErrorCode::Present));
+        }
+        Ok(())
+    }
+
+    fn fault(&self, gva: u64, access: PageAccess, error_code: PageFaultErrorCode) -> Error {
+        let mut error_code = error_code
+            | PageFaultErrorCode::from_bits_truncate(
+                (access & (PageAccess::Write | PageAccess::User)).bits(),
+            );
+        // Instruction fetches are only reported when execute protection can be in effect.
+        let nx_enabled = self.mode() != PagingMode::Bits32 && self.efer & EFER_NXE != 0;
+        if access.contains(PageAccess::Execute) && nx_enabled {
+            error_code |= PageFaultErrorCode::InstructionFetch;
+        }
+        Error::PageFault(PageFault {
+            address: gva,
+            error_code,
+        })
+    }
+}
+
+trait Entry: Copy {
+    const SIZE: usize;
+    fn from_le(bytes: [u8; 8]) -> u64;
+}
+
+impl Entry for u32 {
+    const SIZE: usize = 4;
+    fn from_le(bytes: [u8; 8]) -> u64 {
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64
+    }
+}
+
+impl Entry for u64 {
+    const SIZE: usize = 8;
+    fn from_le(bytes: [u8; 8]) -> u64 {
+        u64::from_le_bytes(bytes)
+    }
+}
+
+fn read_entry<T: Entry>(memory: &impl GuestMemoryRead, gpa: u64) -> Result<u64> {
+    let mut bytes = [0; 8];
+    memory.read_physical(gpa, &mut bytes[..T::SIZE])?;
+    Ok(T::from_le(bytes))
+}
+
+/// Check that the bits above `bits` are a sign extension of the top implemented bit.
+fn check_canonical(gva: u64, bits: u32) -> Result<()> {
+    let shift = 64 - bits;
+    if ((gva << shift) as i64 >> shift) as u64 != gva {
+        return Err(Error::NonCanonicalAddress(gva));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        flags::MapGpaRangeFlags,
+        memory::{GuestMemory, MemoryRegion},
+        Error,
+    };
+
+    use super::{PageAccess, PageFault, PageFaultErrorCode, PagingMode, PagingState};
+
+    const P: u64 = 1;
+    const W: u64 = 1 << 1;
+    const U: u64 = 1 << 2;
+    const PS: u64 = 1 << 7;
+    const NX: u64 = 1 << 63;
+
+    fn ram() -> MemoryRegion {
+        MemoryRegion::from_bytes(
+            0,
+            MapGpaRangeFlags::Read | MapGpaRangeFlags::Write,
+            &[0; 0x10000],
+        )
+    }
+
+    fn long_mode(cr3: u64) -> PagingState {
+        PagingState {
+            cr0: (1 << 31) | (1 << 16) | 1,
+            cr3,
+            cr4: 1 << 5,
+            efer: (1 << 8) | (1 << 10) | (1 << 11),
+        }
+    }
+
+    fn fault(address: u64, error_code: PageFaultErrorCode) -> PageFault {
+        PageFault {
+            address,
+            error_code,
+        }
+    }
+
+    #[test]
+    fn four_level() {
+        let region = ram();
+        let regions = [region];
+        let memory = GuestMemory::new(&regions);
+        // PML4 at 0x1000, PDPT at 0x2000, PD at 0x3000, PT at 0x4000.
+        memory.write_obj(0x1000, 0x2000 | P | W | U).unwrap();
+        memory.write_obj(0x2000, 0x3000 | P | W | U).unwrap();
+        memory.write_obj(0x2008, 0x4000_0000 | P | W | PS).unwrap();
+        memory.write_obj(0x3000, 0x4000 | P | W | U).unwrap();
+        memory
+            .write_obj(0x3008, 0x20_0000 | P | U | PS | NX)
+            .unwrap();
+        memory.write_obj(0x4000 + 5 * 8, 0x9000 | P | U).unwrap();
+
+        let state = long_mode(0x1000);
+        assert_eq!(state.mode(), PagingMode::Level4);
+
+        let t = state.translate(&memory, 0x5123, PageAccess::User).unwrap();
+        assert_eq!((t.gpa, t.page_size), (0x9123, 0x1000));
+
+        let t = state
+            .translate(&memory, 0x4012_3456, PageAccess::Write)
+            .unwrap();
+        assert_eq!((t.gpa, t.page_size), (0x4012_3456, 0x4000_0000));
+
+        let t = state
+            .translate(&memory, 0x20_0010, PageAccess::User)
+            .unwrap();
+        assert_eq!((t.gpa, t.page_size), (0x20_0010, 0x20_0000));
+
+        // Read-only for everyone since CR0.WP is set.
+        assert!(matches!(
+            state.translate(&memory, 0x5000, PageAccess::Write),
+            Err(Error::PageFault(f)) if f == fault(0x5000, PageFaultErrorCode::Present | PageFaultErrorCode::Write)
+        ));
+        let no_wp = PagingState {
+            cr0: state.cr0 & !(1 << 16),
+            ..state
+        };
+        assert!(no_wp.translate(&memory, 0x5000, PageAccess::Write).is_ok());
+
+        // Supervisor only.
+        assert!(matches!(
+            state.translate(&memory, 0x4000_0000, PageAccess::User),
+            Err(Error::PageFault(f)) if f == fault(0x4000_0000, PageFaultErrorCode::Present | PageFaultErrorCode::User)
+        ));
+
+        assert!(matches!(
+            state.translate(&memory, 0x20_0000, PageAccess::Execute),
+            Err(Error::PageFault(f)) if f == fault(0x20_0000, PageFaultErrorCode::Present | PageFaultErrorCode::InstructionFetch)
+        ));
+
+        assert!(matches!(
+            state.translate(&memory, 0x6000, PageAccess::User | PageAccess::Write),
+            Err(Error::PageFault(f)) if f == fault(0x6000, PageFaultErrorCode::User | PageFaultErrorCode::Write)
+        ));
+
+        // NX is reserved without EFER.NXE.
+        let no_nxe = PagingState {
+            efer: state.efer & !(1 << 11),
+            ..state
+        };
+        assert!(matches!(
+            no_nxe.translate(&memory, 0x20_0000, PageAccess::empty()),
+            Err(Error::PageFault(f)) if f == fault(0x20_0000, PageFaultErrorCode::Present | PageFaultErrorCode::ReservedBit)
+        ));
+
+        assert!(matches!(
+            state.translate(&memory, 0x0000_8000_0000_0000, PageAccess::empty()),
+            Err(Error::NonCanonicalAddress(0x0000_8000_0000_0000))
+        ));
+    }
+
+    #[test]
+    fn five_level() {
+        let regions = [ram()];
+        let memory = GuestMemory::new(&regions);
+        let gva = 0xff00_0000_0000_1234_u64;
+        // Index 256 in the PML5, the lower half of the tables after that.
+        memory.write_obj(0x1000 + 256 * 8, 0x2000 | P | W).unwrap();
+        memory.write_obj(0x2000, 0x3000 | P | W).unwrap();
+        memory.write_obj(0x3000, 0x4000 | P | W).unwrap();
+        memory.write_obj(0x4000, 0x5000 | P | W).unwrap();
+        memory.write_obj(0x5008, 0x7000 | P | W).unwrap();
+
+        let state = PagingState {
+            cr4: (1 << 5) | (1 << 12),
+            ..long_mode(0x1000)
+        };
+        assert_eq!(state.mode(), PagingMode::Level5);
+        let t = state.translate(&memory, gva, PageAccess::Write).unwrap();
+        assert_eq!(t.gpa, 0x7234);
+    }
+
+    #[test]
+    fn pae() {
+        let regions = [ram()];
+        let memory = GuestMemory::new(&regions);
+        // PDPT at 0x1020, PD at 0x2000, PT at 0x3000.
+        memory.write_obj(0x1020 + 3 * 8, 0x2000 | P).unwrap();
+        memory.write_obj(0x2000, 0x3000 | P | W | U).unwrap();
+        memory
+            .write_obj(0x2008, 0x60_0000 | P | W | U | PS)
+            .unwrap();
+        memory.write_obj(0x3000 + 8, 0xa000 | P | W | U).unwrap();
+
+        let state = PagingState {
+            cr0: (1 << 31) | 1,
+            cr3: 0x1020,
+            cr4: 1 << 5,
+            efer: 0,
+        };
+        assert_eq!(state.mode(), PagingMode::Pae);
+
+        let t = state
+            .translate(&memory, 0xc000_1abc, PageAccess::User | PageAccess::Write)
+            .unwrap();
+        assert_eq!(t.gpa, 0xaabc);
+        let t = state
+            .translate(&memory, 0xc020_0001, PageAccess::empty())
+            .unwrap();
+        assert_eq!((t.gpa, t.page_size), (0x60_0001, 0x20_0000));
+
+        assert!(matches!(
+            state.translate(&memory, 0x8000_0000, PageAccess::Execute),
+            Err(Error::PageFault(f)) if f == fault(0x8000_0000, PageFaultErrorCode::empty())
+        ));
+    }
+
+    #[test]
+    fn bits_32() {
+        let regions = [ram()];
+        let memory = GuestMemory::new(&regions);
+        // PD at 0x1000, PT at 0x2000.
+        memory
+            .write_obj(0x1000_u64, 0x2000_u32 | (P | U) as u32)
+            .unwrap();
+        memory
+            .write_obj(
+                0x1004_u64,
+                0x0040_0000_u32 | (1 << 13) | (P | W | PS) as u32,
+            )
+            .unwrap();
+        memory.write_obj(0x2004_u64, 0x8000_u32 | P as u32).unwrap();
+
+        let state = PagingState {
+            cr0: (1 << 31) | 1,
+            cr3: 0x1000,
+            cr4: 1 << 4,
+            efer: 0,
+        };
+        assert_eq!(state.mode(), PagingMode::Bits32);
+
+        let t = state.translate(&memory, 0x1010, PageAccess::Write).unwrap();
+        assert_eq!(t.gpa, 0x8010);
+        let t = state
+            .translate(&memory, 0x0041_2345, PageAccess::Write)
+            .unwrap();
+        assert_eq!((t.gpa, t.page_size), (0x1_0041_2345, 0x40_0000));
+
+        // No instruction fetch bit without NX.
+        assert!(matches!(
+            state.translate(&memory, 0x1000, PageAccess::User | PageAccess::Execute),
+            Err(Error::PageFault(f)) if f == fault(0x1000, PageFaultErrorCode::Present | PageFaultErrorCode::User)
+        ));
+
+        let unpaged = PagingState::default();
+        assert_eq!(unpaged.mode(), PagingMode::Disabled);
+        let t = unpaged
+            .translate(&memory, 0x1234_5678, PageAccess::empty())
+            .unwrap();
+        assert_eq!(t.gpa, 0x1234_5678);
+    }
+}
@@ -1,9 +1,8 @@
 use std::sync::Arc;
 
+#[cfg(windows)]
 use windows::Win32::System::Hypervisor::{
-    WHvCreatePartition, WHvCreateVirtualProcessor, WHvDeletePartition, WHvGetPartitionProperty,
-    WHvMapGpaRange, WHvSetPartitionProperty, WHvSetupPartition, WHV_CPUID_OUTPUT, WHV_MSR_ACTION,
-    WHV_MSR_ACTION_ENTRY, WHV_PARTITION_HANDLE, WHV_PARTITION_PROPERTY,
+    WHV_CPUID_OUTPUT, WHV_MSR_ACTION, WHV_MSR_ACTION_ENTRY, WHV_PARTITION_PROPERTY,
     WHV_PARTITION_PROPERTY_CODE, WHV_PROCESSOR_FEATURES_BANKS, WHV_PROCESSOR_FEATURES_BANKS_0,
     WHV_PROCESSOR_FEATURES_BANKS_0_0, WHV_SYNTHETIC_PROCESSOR_FEATURES_BANKS,
     WHV_SYNTHETIC_PROCESSOR_FEATURES_BANKS_0, WHV_SYNTHETIC_PROCESSOR_FEATURES_BANKS_0_0,
@@ -11,11 +10,14 @@ use windows::Win32::System::Hypervisor::{
 };
 
 use crate::{
+    apic::InterruptControl,
+    backend::Backend,
     flags::{
-        ExtendedVmExits, ProcessorFeatures, ProcessorFeatures1, ProcessorPerfmonFeatures,
-        ProcessorXsaveFeatures, SyntheticProcessorFeatures, X64CpuidResult2Flags, X64MsrExitBitmap,
+        ExtendedVmExits, MapGpaRangeFlags, ProcessorFeatures, ProcessorFeatures1,
+        ProcessorPerfmonFeatures, ProcessorXsaveFeatures, SyntheticProcessorFeatures,
+        X64CpuidResult2Flags, X64MsrExitBitmap,
     },
-    memory::MemoryRegion,
+    memory::{DirtyBitmap, GuestMemory, MemoryRegion, PAGE_SIZE},
     processor::VirtualProcessor,
     Error, Result,
 };
@@ -26,7 +28,7 @@ pub enum PartitionPropertyAvailability {
     AfterSetup,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(i32)]
 pub enum PartitionPropertyCode {
     ExtendedVmExits = 0x1,
@@ -61,6 +63,7 @@ pub enum PartitionPropertyCode {
     ProcessorCount = 0x1fff,
 }
 
+#[cfg(windows)]
 impl From<PartitionPropertyCode> for WHV_PARTITION_PROPERTY_CODE {
     fn from(value: PartitionPropertyCode) -> Self {
         // TODO: Why cant the repr(i32) expose the implicit conversion?
@@ -74,6 +77,7 @@ pub struct SyntheticProcessorFeaturesBanks {
     pub bank_0: SyntheticProcessorFeatures,
 }
 
+#[cfg(windows)]
 impl From<WHV_SYNTHETIC_PROCESSOR_FEATURES_BANKS> for SyntheticProcessorFeaturesBanks {
     fn from(value: WHV_SYNTHETIC_PROCESSOR_FEATURES_BANKS) -> Self {
         Self {
@@ -83,6 +87,7 @@ impl From<WHV_SYNTHETIC_PROCESSOR_FEATURES_BANKS> for SyntheticProcessorFeatures
     }
 }
 
+#[cfg(windows)]
 impl From<SyntheticProcessorFeaturesBanks> for WHV_SYNTHETIC_PROCESSOR_FEATURES_BANKS {
     fn from(value: SyntheticProcessorFeaturesBanks) -> Self {
         Self {
@@ -106,6 +111,7 @@ pub struct X64CpuidResult {
     pub edx: u32,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_CPUID_RESULT> for X64CpuidResult {
     fn from(value: WHV_X64_CPUID_RESULT) -> Self {
         Self {
@@ -118,6 +124,7 @@ impl From<WHV_X64_CPUID_RESULT> for X64CpuidResult {
     }
 }
 
+#[cfg(windows)]
 impl From<X64CpuidResult> for WHV_X64_CPUID_RESULT {
     fn from(value: X64CpuidResult) -> Self {
         Self {
@@ -141,6 +148,7 @@ pub struct X64CpuidResult2 {
     pub mask: CpuidOutput,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_CPUID_RESULT2> for X64CpuidResult2 {
     fn from(value: WHV_X64_CPUID_RESULT2) -> Self {
         Self {
@@ -154,6 +162,7 @@ impl From<WHV_X64_CPUID_RESULT2> for X64CpuidResult2 {
     }
 }
 
+#[cfg(windows)]
 impl From<X64CpuidResult2> for WHV_X64_CPUID_RESULT2 {
     fn from(value: X64CpuidResult2) -> Self {
         Self {
@@ -175,6 +184,7 @@ pub struct CpuidOutput {
     pub edx: u32,
 }
 
+#[cfg(windows)]
 impl From<WHV_CPUID_OUTPUT> for CpuidOutput {
     fn from(value: WHV_CPUID_OUTPUT) -> Self {
         Self {
@@ -186,6 +196,7 @@ impl From<WHV_CPUID_OUTPUT> for CpuidOutput {
     }
 }
 
+#[cfg(windows)]
 impl From<CpuidOutput> for WHV_CPUID_OUTPUT {
     fn from(value: CpuidOutput) -> Self {
         Self {
@@ -204,6 +215,7 @@ pub struct MsrActionEntry {
     pub write_action: u8,
 }
 
+#[cfg(windows)]
 impl From<WHV_MSR_ACTION_ENTRY> for MsrActionEntry {
     fn from(value: WHV_MSR_ACTION_ENTRY) -> Self {
         Self {
@@ -214,6 +226,7 @@ impl From<WHV_MSR_ACTION_ENTRY> for MsrActionEntry {
     }
 }
 
+#[cfg(windows)]
 impl From<MsrActionEntry> for WHV_MSR_ACTION_ENTRY {
     fn from(value: MsrActionEntry) -> Self {
         Self {
@@ -233,6 +246,7 @@ pub enum MsrAction {
     Exit = 2,
 }
 
+#[cfg(windows)]
 impl From<WHV_MSR_ACTION> for MsrAction {
     fn from(value: WHV_MSR_ACTION) -> Self {
         // TODO: Can we enforce this differently?
@@ -245,6 +259,7 @@ impl From<WHV_MSR_ACTION> for MsrAction {
     }
 }
 
+#[cfg(windows)]
 impl From<MsrAction> for WHV_MSR_ACTION {
     fn from(value: MsrAction) -> Self {
         // TODO: Why cant the repr(i32) expose the implicit conversion?
@@ -260,6 +275,7 @@ pub enum X64LocalApicEmulationMode {
     X2Apic,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_LOCAL_APIC_EMULATION_MODE> for X64LocalApicEmulationMode {
     fn from(value: WHV_X64_LOCAL_APIC_EMULATION_MODE) -> Self {
         // TODO: Can we enforce this differently?
@@ -272,6 +288,7 @@ impl From<WHV_X64_LOCAL_APIC_EMULATION_MODE> for X64LocalApicEmulationMode {
     }
 }
 
+#[cfg(windows)]
 impl From<X64LocalApicEmulationMode> for WHV_X64_LOCAL_APIC_EMULATION_MODE {
     fn from(value: X64LocalApicEmulationMode) -> Self {
         // TODO: Why cant the repr(i32) expose the implicit conversion?
@@ -286,6 +303,7 @@ pub struct ProcessorFeaturesBanks {
     pub bank_1: ProcessorFeatures1,
 }
 
+#[cfg(windows)]
 impl From<WHV_PROCESSOR_FEATURES_BANKS> for ProcessorFeaturesBanks {
     fn from(value: WHV_PROCESSOR_FEATURES_BANKS) -> Self {
         Self {
@@ -296,6 +314,7 @@ impl From<WHV_PROCESSOR_FEATURES_BANKS> for ProcessorFeaturesBanks {
     }
 }
 
+#[cfg(windows)]
 impl From<ProcessorFeaturesBanks> for WHV_PROCESSOR_FEATURES_BANKS {
     fn from(value: ProcessorFeaturesBanks) -> Self {
         Self {
@@ -311,7 +330,7 @@ impl From<ProcessorFeaturesBanks> for WHV_PROCESSOR_FEATURES_BANKS {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum PartitionProperty {
     ExtendedVmExits(ExtendedVmExits),
     ProcessorFeatures(ProcessorFeatures),
@@ -346,6 +365,7 @@ pub enum PartitionProperty {
 }
 
 impl PartitionProperty {
+    #[cfg(windows)]
     pub fn from_union(code: PartitionPropertyCode, raw_val: WHV_PARTITION_PROPERTY) -> Self {
         // SAFETY: The code corresponds to the union variant.
         unsafe {
@@ -530,6 +550,7 @@ impl PartitionProperty {
     }
 }
 
+#[cfg(windows)]
 impl From<PartitionProperty> for WHV_PARTITION_PROPERTY {
     fn from(value: PartitionProperty) -> Self {
         match value {
@@ -612,67 +633,37 @@ impl From<PartitionProperty> for WHV_PARTITION_PROPERTY {
     }
 }
 
-#[derive(Debug)]
-pub struct PartitionHandle(pub WHV_PARTITION_HANDLE);
-
-impl PartitionHandle {
-    pub fn new() -> Result<Self> {
-        let raw_handle = unsafe { WHvCreatePartition()? };
-        Ok(Self(raw_handle))
-    }
-}
-
-impl From<PartitionHandle> for WHV_PARTITION_HANDLE {
-    fn from(handle: PartitionHandle) -> Self {
-        handle.0
-    }
-}
-
-impl From<WHV_PARTITION_HANDLE> for PartitionHandle {
-    fn from(raw_handle: WHV_PARTITION_HANDLE) -> Self {
-        Self(raw_handle)
-    }
-}
-
-impl Drop for PartitionHandle {
-    fn drop(&mut self) {
-        // TODO: Error handling... in drop?
-        let _ = unsafe { WHvDeletePartition(self.0) };
-    }
-}
-
 pub struct PartitionBuilder {
-    arc_handle: Arc<PartitionHandle>,
+    backend: Arc<dyn Backend>,
 }
 
 impl PartitionBuilder {
+    /// Create a new partition using the Windows Hypervisor Platform.
+    #[cfg(windows)]
     pub fn new() -> Result<Self> {
-        Ok(Self {
-            arc_handle: Arc::new(PartitionHandle::new()?),
-        })
+        Ok(Self::with_backend(Arc::new(
+            crate::backend::WhpBackend::new()?,
+        )))
+    }
+
+    /// Create a new partition driven by `backend`, see [`crate::backend`] for the available backends.
+    pub fn with_backend(backend: Arc<dyn Backend>) -> Self {
+        Self { backend }
     }
 
     // TODO: Be able to query properties? Or should we assume nothing about the default state of the partition.
     // TODO: Should we set processor count to 1 by default so that immediately calling [PartitionBuilder::setup] without setting processor count works?
 
     pub fn property(self, prop: PartitionProperty) -> Result<Self> {
-        unsafe {
-            WHvSetPartitionProperty(
-                WHV_PARTITION_HANDLE::from(self.arc_handle.0),
-                prop.code().into(),
-                std::mem::transmute(&WHV_PARTITION_PROPERTY::from(prop)),
-                std::mem::size_of::<WHV_PARTITION_PROPERTY>().try_into()?,
-            )?;
-        };
+        self.backend.set_property(prop)?;
         Ok(self)
     }
 
     pub fn setup(self) -> Result<Partition> {
-        // TODO: Error handling.
         // TODO: Should we check processor count here?
-        let _ = unsafe { WHvSetupPartition(self.arc_handle.0)? };
+        self.backend.setup()?;
         Ok(Partition {
-            arc_handle: self.arc_handle.clone(),
+            backend: self.backend,
             memory_regions: Vec::new(),
         })
     }
@@ -681,7 +672,7 @@ impl PartitionBuilder {
 /// A setup partition, ready to create virtual cpu's.
 #[derive(Debug)]
 pub struct Partition {
-    arc_handle: Arc<PartitionHandle>,
+    backend: Arc<dyn Backend>,
     memory_regions: Vec<MemoryRegion>,
 }
 
@@ -689,19 +680,7 @@ impl Partition {
     // TODO: Add a `from_` or `new` function, disallow struct initialization (i.e. [PartitionBuilder::setup]).
 
     pub fn query_property(&self, prop_code: PartitionPropertyCode) -> Result<PartitionProperty> {
-        let raw_property: WHV_PARTITION_PROPERTY = Default::default();
-
-        unsafe {
-            WHvGetPartitionProperty(
-                self.arc_handle.0,
-                prop_code.into(),
-                std::mem::transmute(&raw_property),
-                std::mem::size_of::<WHV_PARTITION_PROPERTY>().try_into()?,
-                None,
-            )?;
-        }
-
-        Ok(PartitionProperty::from_union(prop_code, raw_property))
+        self.backend.get_property(prop_code)
     }
 
     pub fn set_property(&mut self, prop: PartitionProperty) -> Result<()> {
@@ -709,36 +688,124 @@ impl Partition {
             PartitionPropertyAvailability::BeforeSetup => {
                 Err(crate::Error::IncompatiblePropertyAvailibility(prop))
             }
-            PartitionPropertyAvailability::AfterSetup => {
-                unsafe {
-                    WHvSetPartitionProperty(
-                        self.arc_handle.0,
-                        prop.code().into(),
-                        std::mem::transmute(&WHV_PARTITION_PROPERTY::from(prop)),
-                        std::mem::size_of::<WHV_PARTITION_PROPERTY>().try_into()?,
-                    )?;
-                }
-                Ok(())
-            }
+            PartitionPropertyAvailability::AfterSetup => self.backend.set_property(prop),
         }
     }
 
+    /// Map `memory_region` into the guest, it must be page aligned and must not overlap any region
+    /// already mapped.
+    ///
+    /// A region that is not mapped is handed back in [`Error::RegionRejected`].
     pub fn map_memory_region(&mut self, memory_region: MemoryRegion) -> Result<()> {
-        unsafe {
-            WHvMapGpaRange(
-                self.arc_handle.0,
-                memory_region.address as *const _,
-                memory_region.guest_address.try_into()?,
-                memory_region.size.try_into()?,
-                memory_region.flags.into(),
-            )?;
+        if let Err(err) = self
+            .check_region(&memory_region)
+            .and_then(|()| self.backend.map_gpa_range(&memory_region))
+        {
+            return Err(Error::RegionRejected(
+                Box::new(memory_region),
+                Box::new(err),
+            ));
         }
-
         self.memory_regions.push(memory_region);
+        Ok(())
+    }
+
+    fn check_region(&self, memory_region: &MemoryRegion) -> Result<()> {
+        let start = memory_region.guest_address as u64;
+        let size = memory_region.size;
+        if !start.is_multiple_of(PAGE_SIZE as u64) || size == 0 || !size.is_multiple_of(PAGE_SIZE) {
+            return Err(Error::UnalignedRegion(start, size));
+        }
+        let end = start
+            .checked_add(size as u64)
+            .ok_or(Error::GpaOutOfRange(start, size))?;
+        if let Some(other) = self.memory_regions.iter().find(|r| {
+            let other_start = r.guest_address as u64;
+            start < other_start + r.size as u64 && other_start < end
+        }) {
+            return Err(Error::OverlappingRegion(
+                start,
+                size,
+                other.guest_address as u64,
+                other.size,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Unmap the region containing `gpa` from the guest, handing ownership of it back.
+    pub fn unmap_memory_region(&mut self, gpa: u64) -> Result<MemoryRegion> {
+        let index = self.region_index(gpa)?;
+        let region = &self.memory_regions[index];
+        self.backend
+            .unmap_gpa_range(region.guest_address as u64, region.size as u64)?;
+        Ok(self.memory_regions.remove(index))
+    }
+
+    /// Map the region containing `gpa` again with different `flags`, i.e. to make it read-only after loading.
+    ///
+    /// If the new mapping fails the region is mapped with its previous flags again. Should that
+    /// fail too the region is no longer mapped or tracked by the partition, it is handed back in
+    /// [`Error::RegionUnmapped`] along with the errors of the new mapping and of the restore.
+    pub fn remap_memory_region(&mut self, gpa: u64, flags: MapGpaRangeFlags) -> Result<()> {
+        let index = self.region_index(gpa)?;
+        let region = &mut self.memory_regions[index];
+        self.backend
+            .unmap_gpa_range(region.guest_address as u64, region.size as u64)?;
+
+        let old_flags = std::mem::replace(&mut region.flags, flags);
+        if let Err(err) = self.backend.map_gpa_range(region) {
+            // Restore the previous mapping, failing that the region is no longer mapped so stop tracking it.
+            region.flags = old_flags;
+            if let Err(restore_err) = self.backend.map_gpa_range(region) {
+                let region = self.memory_regions.remove(index);
+                return Err(Error::RegionUnmapped(
+                    Box::new(region),
+                    Box::new(err),
+                    Box::new(restore_err),
+                ));
+            }
+            return Err(err);
+        }
 
         Ok(())
     }
 
+    /// Query the pages written since the last query of the region containing `gpa`, the region must
+    /// be mapped with [`MapGpaRangeFlags::TrackDirtyPages`].
+    pub fn query_dirty_bitmap(&self, gpa: u64) -> Result<DirtyBitmap> {
+        let region = &self.memory_regions[self.region_index(gpa)?];
+        if !region.flags.contains(MapGpaRangeFlags::TrackDirtyPages) {
+            return Err(Error::DirtyPagesUntracked(gpa));
+        }
+        self.backend
+            .query_dirty_bitmap(region.guest_address as u64, region.size as u64)
+    }
+
+    fn region_index(&self, gpa: u64) -> Result<usize> {
+        self.memory_regions
+            .iter()
+            .position(|r| r.contains(gpa))
+            .ok_or(Error::GpaUnmapped(gpa))
+    }
+
+    /// A view of the guest physical memory mapped by [`Partition::map_memory_region`].
+    pub fn memory(&self) -> GuestMemory<'_> {
+        GuestMemory::new(&self.memory_regions)
+    }
+
+    /// Deliver `interrupt` to the local APICs it is addressed to.
+    pub fn request_interrupt(&self, interrupt: InterruptControl) -> Result<()> {
+        self.backend.request_interrupt(&interrupt)
+    }
+
+    /// Send an INIT followed by a SIPI to the virtual processor `vp_index`, starting it in real mode
+    /// at `start_page << 12`.
+    pub fn request_init_sipi(&self, vp_index: u32, start_page: u8) -> Result<()> {
+        self.request_interrupt(InterruptControl::init(vp_index))?;
+        self.request_interrupt(InterruptControl::sipi(vp_index, start_page))
+    }
+
     pub fn create_virtual_processor(&mut self, index: u32) -> Result<VirtualProcessor> {
         // Check to make sure we have processor count at or larger than index.
         match self.query_property(PartitionPropertyCode::ProcessorCount)? {
@@ -748,8 +815,173 @@ impl Partition {
             _ => Ok(()),
         }?;
 
-        // TODO: Safety docs.
-        unsafe { WHvCreateVirtualProcessor(self.arc_handle.0, index, 0)? };
-        Ok(VirtualProcessor::new(self.arc_handle.clone(), index))
+        self.backend.create_virtual_processor(index)?;
+        Ok(VirtualProcessor::new(self.backend.clone(), index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        backend::MockBackend, flags::MapGpaRangeFlags, memory::MemoryRegion, Error, Result,
+    };
+
+    use super::PartitionBuilder;
+
+    #[test]
+    fn unmap_and_remap() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .setup()
+            .unwrap();
+        let rw = MapGpaRangeFlags::Read | MapGpaRangeFlags::Write;
+        partition
+            .map_memory_region(MemoryRegion::from_bytes(0x1000, rw, &[0x90; 0x1000]))
+            .unwrap();
+        partition
+            .map_memory_region(MemoryRegion::from_bytes(0x2000, rw, &[0xf4; 0x1000]))
+            .unwrap();
+
+        partition
+            .remap_memory_region(0x1800, MapGpaRangeFlags::Read)
+            .unwrap();
+        let mapping = mock
+            .mappings()
+            .into_iter()
+            .find(|m| m.guest_address == 0x1000);
+        assert_eq!(mapping.unwrap().flags, MapGpaRangeFlags::Read);
+        assert_eq!(partition.memory().read_obj::<u8>(0x1000).unwrap(), 0x90);
+
+        let region = partition.unmap_memory_region(0x2fff).unwrap();
+        assert_eq!(region.guest_address, 0x2000);
+        assert_eq!(mock.mappings().len(), 1);
+        assert!(matches!(
+            partition.memory().read_obj::<u8>(0x2000),
+            Err(Error::GpaUnmapped(0x2000))
+        ));
+        assert!(matches!(
+            partition.unmap_memory_region(0x2000),
+            Err(Error::GpaUnmapped(0x2000))
+        ));
+
+        // The returned region can be mapped again as is.
+        partition.map_memory_region(region).unwrap();
+        assert_eq!(partition.memory().read_obj::<u8>(0x2000).unwrap(), 0xf4);
+
+        // A failed remap restores the previous mapping.
+        mock.fail_maps(1);
+        assert!(matches!(
+            partition.remap_memory_region(0x2000, MapGpaRangeFlags::Read),
+            Err(Error::ScriptedMapFailure(0x2000))
+        ));
+        assert_eq!(partition.memory().read_obj::<u8>(0x2000).unwrap(), 0xf4);
+
+        // Failing that as well, the region is handed back unmapped.
+        mock.fail_maps(2);
+        let Err(Error::RegionUnmapped(region, err, restore_err)) =
+            partition.remap_memory_region(0x2000, MapGpaRangeFlags::Read)
+        else {
+            panic!("the region should have been unmapped");
+        };
+        assert_eq!((region.guest_address, region.flags), (0x2000, rw));
+        assert!(matches!(*err, Error::ScriptedMapFailure(0x2000)));
+        assert!(matches!(*restore_err, Error::ScriptedMapFailure(0x2000)));
+        assert!(partition.memory().read_obj::<u8>(0x2000).is_err());
+        assert_eq!(mock.mappings().len(), 1);
+    }
+
+    #[test]
+    fn dirty_bitmap() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .setup()
+            .unwrap();
+        let rw = MapGpaRangeFlags::Read | MapGpaRangeFlags::Write;
+        partition
+            .map_memory_region(MemoryRegion::from_bytes(
+                0x10000,
+                rw | MapGpaRangeFlags::TrackDirtyPages,
+                &[0; 0x4000],
+            ))
+            .unwrap();
+        partition
+            .map_memory_region(MemoryRegion::from_bytes(0x20000, rw, &[0; 0x1000]))
+            .unwrap();
+
+        mock.write_memory(0x10ffe, &[1; 4]).unwrap();
+        mock.write_memory(0x13000, &[1]).unwrap();
+        let bitmap = partition.query_dirty_bitmap(0x12000).unwrap();
+        assert_eq!(bitmap.guest_address(), 0x10000);
+        assert_eq!(bitmap.iter().collect::<Vec<_>>(), [0x10, 0x11, 0x13]);
+
+        // Querying resets the dirty state.
+        assert!(partition.query_dirty_bitmap(0x10000).unwrap().is_clean());
+
+        assert!(matches!(
+            partition.query_dirty_bitmap(0x20000),
+            Err(Error::DirtyPagesUntracked(0x20000))
+        ));
+    }
+
+    #[test]
+    fn region_validation() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .setup()
+            .unwrap();
+        let rw = MapGpaRangeFlags::Read | MapGpaRangeFlags::Write;
+        partition
+            .map_memory_region(MemoryRegion::from_bytes(0x2000, rw, &[0; 0x2000]))
+            .unwrap();
+
+        let rejected = |result: Result<()>| match result {
+            Err(Error::RegionRejected(region, err)) => (region, *err),
+            result => panic!("the region should have been rejected, got {result:?}"),
+        };
+        assert!(matches!(
+            rejected(partition.map_memory_region(MemoryRegion::from_bytes(
+                0x4800,
+                rw,
+                &[0; 0x1000]
+            )))
+            .1,
+            Error::UnalignedRegion(0x4800, 0x1000)
+        ));
+        assert!(matches!(
+            rejected(partition.map_memory_region(MemoryRegion::from_bytes(0x4000, rw, &[0; 0x10])))
+                .1,
+            Error::UnalignedRegion(0x4000, 0x10)
+        ));
+        assert!(matches!(
+            rejected(partition.map_memory_region(MemoryRegion::from_bytes(
+                0x1000,
+                rw,
+                &[0; 0x2000]
+            )))
+            .1,
+            Error::OverlappingRegion(0x1000, 0x2000, 0x2000, 0x2000)
+        ));
+        assert_eq!(mock.mappings().len(), 1);
+
+        // A region the hypervisor refuses is handed back as well and can be mapped again.
+        mock.fail_maps(1);
+        let (region, err) = rejected(partition.map_memory_region(MemoryRegion::from_bytes(
+            0x8000,
+            rw,
+            &[0; 0x1000],
+        )));
+        assert!(matches!(err, Error::ScriptedMapFailure(0x8000)));
+        partition.map_memory_region(*region).unwrap();
+        assert_eq!(mock.mappings().len(), 2);
+
+        // Adjacent regions are fine.
+        partition
+            .map_memory_region(MemoryRegion::from_bytes(0x1000, rw, &[0; 0x1000]))
+            .unwrap();
+        partition
+            .map_memory_region(MemoryRegion::from_bytes(0x4000, rw, &[0; 0x1000]))
+            .unwrap();
     }
 }
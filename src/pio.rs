@@ -0,0 +1,618 @@
+use crate::{
+    decode::{ExecutionMode, OpcodeMap, Segment},
+    flags::{IoPortAccessInfo, X64ExecutionState},
+    memory::{GuestMemoryRead, GuestMemoryWrite},
+    paging::{PageAccess, PagingState},
+    processor::{
+        ExitContext, IoPortAccessContext, Register, RegisterVal, SegmentRegister, VirtualProcessor,
+    },
+    Error, Result,
+};
+
+const RFLAGS_DF: u64 = 1 << 10;
+
+/// Iterations of a `rep` string instruction performed per exit, the guest re-executes the
+/// instruction for the rest.
+pub const MAX_STRING_ITERATIONS: u64 = 4096;
+
+/// A device claiming a range of I/O ports.
+///
+/// `port` is relative to the first port of the range the handler was registered with, `data` is
+/// 1, 2 or 4 bytes long.
+pub trait PortIoHandler: Send {
+    fn io_read(&mut self, port: u16, data: &mut [u8]);
+
+    fn io_write(&mut self, port: u16, data: &[u8]);
+}
+
+/// What happens to accesses no handler claims.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnclaimedPorts {
+    /// Reads return all ones like a floating bus and writes are dropped.
+    #[default]
+    Ignore,
+    /// Fail with [`Error::PortUnclaimed`].
+    Error,
+}
+
+/// The registers left behind by a string I/O instruction, see [`PortIoBus::complete_string`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StringIoRegisters {
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rcx: u64,
+    pub rip: u64,
+}
+
+impl StringIoRegisters {
+    pub fn register_vals(&self) -> [(Register, RegisterVal); 4] {
+        [
+            (Register::Rsi, self.rsi.into()),
+            (Register::Rdi, self.rdi.into()),
+            (Register::Rcx, self.rcx.into()),
+            (Register::Rip, self.rip.into()),
+        ]
+    }
+}
+
+struct PortIoDevice {
+    first_port: u16,
+    count: u16,
+    handler: Box<dyn PortIoHandler>,
+}
+
+impl PortIoDevice {
+    fn contains(&self, port: u16) -> bool {
+        port >= self.first_port && port - self.first_port < self.count
+    }
+}
+
+/// Routes I/O port exits to the [`PortIoHandler`] registered for the port.
+#[derive(Default)]
+pub struct PortIoBus {
+    devices: Vec<PortIoDevice>,
+    unclaimed: UnclaimedPorts,
+}
+
+impl std::fmt::Debug for PortIoBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PortIoBus")
+            .field(
+                "devices",
+                &self
+                    .devices
+                    .iter()
+                    .map(|d| d.first_port as u32..d.first_port as u32 + d.count as u32)
+                    .collect::<Vec<_>>(),
+            )
+            .field("unclaimed", &self.unclaimed)
+            .finish()
+    }
+}
+
+impl PortIoBus {
+    pub fn new(unclaimed: UnclaimedPorts) -> Self {
+        Self {
+            devices: Vec::new(),
+            unclaimed,
+        }
+    }
+
+    /// Claim `count` ports starting at `first_port` for `handler`, ranges must not overlap.
+    pub fn register(
+        &mut self,
+        first_port: u16,
+        count: u16,
+        handler: Box<dyn PortIoHandler>,
+    ) -> Result<()> {
+        let end = first_port as u32 + count as u32;
+        if count == 0 || end > 0x10000 {
+            return Err(Error::InvalidPortRange(first_port, count));
+        }
+        if let Some(other) = self.devices.iter().find(|d| {
+            (first_port as u32) < d.first_port as u32 + d.count as u32
+                && (d.first_port as u32) < end
+        }) {
+            return Err(Error::OverlappingPorts(
+                first_port,
+                count,
+                other.first_port,
+                other.count,
+            ));
+        }
+
+        self.devices.push(PortIoDevice {
+            first_port,
+            count,
+            handler,
+        });
+        Ok(())
+    }
+
+    /// Remove the handler registered for the range containing `port`.
+    pub fn unregister(&mut self, port: u16) -> Option<Box<dyn PortIoHandler>> {
+        let index = self.devices.iter().position(|d| d.contains(port))?;
+        Some(self.devices.remove(index).handler)
+    }
+
+    fn device(&mut self, port: u16) -> Result<Option<&mut PortIoDevice>> {
+        match self.devices.iter_mut().find(|d| d.contains(port)) {
+            None if self.unclaimed == UnclaimedPorts::Error => Err(Error::PortUnclaimed(port)),
+            device => Ok(device),
+        }
+    }
+
+    pub fn io_read(&mut self, port: u16, data: &mut [u8]) -> Result<()> {
+        match self.device(port)? {
+            Some(device) => device.handler.io_read(port - device.first_port, data),
+            None => data.fill(0xff),
+        }
+        Ok(())
+    }
+
+    pub fn io_write(&mut self, port: u16, data: &[u8]) -> Result<()> {
+        if let Some(device) = self.device(port)? {
+            device.handler.io_write(port - device.first_port, data);
+        }
+        Ok(())
+    }
+
+    /// Perform a single `in` or `out`, returning the new value of RAX.
+    ///
+    /// Reads only replace the low `access_size` bytes of RAX, except for 4-byte reads which zero
+    /// extend like any other 32-bit register write. String instructions go through
+    /// [`PortIoBus::complete_string`] instead.
+    pub fn complete(&mut self, access: &IoPortAccessContext) -> Result<u64> {
+        if access.access_info.contains(IoPortAccessInfo::StringOp) {
+            let count = access.instruction_byte_count as usize;
+            return Err(Error::UnsupportedInstruction(
+                access.instruction_bytes[..count.min(16)].to_vec(),
+            ));
+        }
+
+        let size = access.access_info.access_size();
+        let mut data = [0; 4];
+        if access.access_info.contains(IoPortAccessInfo::IsWrite) {
+            data.copy_from_slice(&(access.rax as u32).to_le_bytes());
+            self.io_write(access.port_number, &data[..size])?;
+            return Ok(access.rax);
+        }
+
+        self.io_read(access.port_number, &mut data[..size])?;
+        let value = u32::from_le_bytes(data) as u64;
+        Ok(match size {
+            1 => (access.rax & !0xff) | value,
+            2 => (access.rax & !0xffff) | value,
+            _ => value,
+        })
+    }
+
+    /// Complete an I/O port exit, updating RAX for reads and advancing RIP by the
+    /// `instruction_byte_count` of the exit.
+    pub fn dispatch(
+        &mut self,
+        vcpu: &mut VirtualProcessor,
+        context: &ExitContext,
+        access: &IoPortAccessContext,
+    ) -> Result<()> {
+        let rax = self.complete(access)?;
+        let rip = context.rip + access.instruction_byte_count as u64;
+        if access.access_info.contains(IoPortAccessInfo::IsWrite) {
+            vcpu.set_register(Register::Rip, rip.into())
+        } else {
+            vcpu.set_registers(&[(Register::Rax, rax.into()), (Register::Rip, rip.into())])
+        }
+    }
+
+    /// Perform an `ins` or `outs` against `memory` and return the registers to write back.
+    ///
+    /// A `rep` prefix performs up to [`MAX_STRING_ITERATIONS`] iterations, if any are left RIP is
+    /// not advanced so the guest executes the instruction again. An error partway through is
+    /// returned as [`Error::StringIoInterrupted`] with the registers after the last completed
+    /// iteration.
+    ///
+    /// Addresses are formed from the segment base and RSI or RDI truncated to the address size,
+    /// stepping backwards when RFLAGS.DF is set, then translated with `paging`. Only the DS, ES
+    /// and CS segment overrides are understood since the exit carries no other segment.
+    pub fn complete_string(
+        &mut self,
+        context: &ExitContext,
+        access: &IoPortAccessContext,
+        paging: &PagingState,
+        memory: &(impl GuestMemoryRead + GuestMemoryWrite),
+    ) -> Result<StringIoRegisters> {
+        let instruction = access.instruction(context)?;
+        let unsupported =
+            || Error::UnsupportedInstruction(access.instruction_bytes[..instruction.len].to_vec());
+        if instruction.map != OpcodeMap::OneByte || !(0x6c..=0x6f).contains(&instruction.opcode) {
+            return Err(unsupported());
+        }
+        let segment = match instruction.segment_override {
+            None | Some(Segment::Ds) => &access.ds,
+            Some(Segment::Es) => &access.es,
+            Some(Segment::Cs) => &context.cs,
+            Some(_) => return Err(unsupported()),
+        };
+
+        let long_mode = instruction.mode == ExecutionMode::Bits64;
+        let mask = match instruction.address_size {
+            8 => u64::MAX,
+            4 => 0xffff_ffff,
+            _ => 0xffff,
+        };
+        let size = access.access_info.access_size();
+        let step = if context.rflags & RFLAGS_DF != 0 {
+            (size as u64).wrapping_neg()
+        } else {
+            size as u64
+        };
+        let rep = access.access_info.contains(IoPortAccessInfo::RepPrefix);
+        let iterations = if rep { access.rcx & mask } else { 1 };
+        let user = context.execution_state.bits() & X64ExecutionState::Cpl.bits() == 3;
+        // Segment bases other than FS and GS are ignored in long mode.
+        let base = |segment: &SegmentRegister| if long_mode { 0 } else { segment.base };
+
+        let mut registers = StringIoRegisters {
+            rsi: access.rsi,
+            rdi: access.rdi,
+            rcx: access.rcx,
+            rip: context.rip,
+        };
+        for done in 0..iterations.min(MAX_STRING_ITERATIONS) {
+            let mut data = [0; 4];
+            let data = &mut data[..size];
+            let result = if access.access_info.contains(IoPortAccessInfo::IsWrite) {
+                let linear = base(segment).wrapping_add(registers.rsi & mask);
+                copy_linear(paging, memory, linear, data, false, user)
+                    .and_then(|()| self.io_write(access.port_number, data))
+            } else {
+                let linear = base(&access.es).wrapping_add(registers.rdi & mask);
+                self.io_read(access.port_number, data)
+                    .and_then(|()| copy_linear(paging, memory, linear, data, true, user))
+            };
+            result.map_err(|error| Error::StringIoInterrupted(registers, Box::new(error)))?;
+
+            if access.access_info.contains(IoPortAccessInfo::IsWrite) {
+                registers.rsi = truncate(registers.rsi, registers.rsi.wrapping_add(step), mask);
+            } else {
+                registers.rdi = truncate(registers.rdi, registers.rdi.wrapping_add(step), mask);
+            }
+            if rep {
+                registers.rcx = truncate(registers.rcx, iterations - done - 1, mask);
+            }
+        }
+
+        if !rep || registers.rcx & mask == 0 {
+            registers.rip += instruction.len as u64;
+        }
+        Ok(registers)
+    }
+
+    /// Complete a string I/O port exit against `memory`, walking the guest page tables with the
+    /// current paging state of `vcpu`.
+    ///
+    /// The registers are written back even if the instruction was interrupted, so the guest sees
+    /// the iterations that did complete.
+    pub fn dispatch_string(
+        &mut self,
+        vcpu: &mut VirtualProcessor,
+        context: &ExitContext,
+        access: &IoPortAccessContext,
+        memory: &(impl GuestMemoryRead + GuestMemoryWrite),
+    ) -> Result<StringIoRegisters> {
+        let paging = vcpu.paging_state()?;
+        match self.complete_string(context, access, &paging, memory) {
+            Ok(registers) => {
+                vcpu.set_registers(&registers.register_vals())?;
+                Ok(registers)
+            }
+            Err(Error::StringIoInterrupted(registers, error)) => {
+                vcpu.set_registers(&registers.register_vals())?;
+                Err(Error::StringIoInterrupted(registers, error))
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// Write `new` to a register of `mask` width, 16-bit writes keep the upper bits of `old` and
+/// 32-bit writes zero extend.
+fn truncate(old: u64, new: u64, mask: u64) -> u64 {
+    match mask {
+        0xffff => (old & !mask) | (new & mask),
+        _ => new & mask,
+    }
+}
+
+/// Copy between `data` and guest memory at a linear address, page by page.
+fn copy_linear(
+    paging: &PagingState,
+    memory: &(impl GuestMemoryRead + GuestMemoryWrite),
+    linear: u64,
+    data: &mut [u8],
+    write: bool,
+    user: bool,
+) -> Result<()> {
+    let mut access = PageAccess::empty();
+    access.set(PageAccess::Write, write);
+    access.set(PageAccess::User, user);
+
+    let mut done = 0;
+    while done < data.len() {
+        let translation = paging.translate(memory, linear.wrapping_add(done as u64), access)?;
+        let page_left = translation.page_size - (translation.gpa & (translation.page_size - 1));
+        let chunk = (data.len() - done).min(page_left as usize);
+        let data = &mut data[done..done + chunk];
+        if write {
+            memory.write_physical(translation.gpa, data)?;
+        } else {
+            memory.read_physical(translation.gpa, data)?;
+        }
+        done += chunk;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        backend::MockBackend,
+        flags::{IoPortAccessInfo, MapGpaRangeFlags, X64SegmentRegisterAttributes},
+        memory::{GuestMemory, MemoryRegion},
+        paging::PagingState,
+        partition::{PartitionBuilder, PartitionProperty},
+        processor::{ExitContext, IoPortAccessContext, Register, RegisterVal},
+        Error,
+    };
+
+    use super::{
+        PortIoBus, PortIoHandler, StringIoRegisters, UnclaimedPorts, MAX_STRING_ITERATIONS,
+    };
+
+    type Writes = Arc<Mutex<Vec<(u16, Vec<u8>)>>>;
+
+    #[derive(Default)]
+    struct Uart(Writes);
+
+    impl PortIoHandler for Uart {
+        fn io_read(&mut self, port: u16, data: &mut [u8]) {
+            data.fill(0x60 | port as u8);
+        }
+
+        fn io_write(&mut self, port: u16, data: &[u8]) {
+            self.0.lock().unwrap().push((port, data.to_vec()));
+        }
+    }
+
+    fn access(port_number: u16, size: u32, write: bool, rax: u64) -> IoPortAccessContext {
+        IoPortAccessContext {
+            instruction_byte_count: 1,
+            instruction_bytes: [0; 16],
+            access_info: IoPortAccessInfo::from_bits_retain(size << 1 | write as u32),
+            port_number,
+            rax,
+            rcx: 0,
+            rsi: 0,
+            rdi: 0,
+            ds: Default::default(),
+            es: Default::default(),
+        }
+    }
+
+    #[test]
+    fn complete() {
+        let writes = Writes::default();
+        let mut bus = PortIoBus::new(UnclaimedPorts::Ignore);
+        bus.register(0x3f8, 8, Box::new(Uart(writes.clone())))
+            .unwrap();
+        assert!(matches!(
+            bus.register(0x3f0, 9, Box::<Uart>::default()),
+            Err(Error::OverlappingPorts(0x3f0, 9, 0x3f8, 8))
+        ));
+        assert!(matches!(
+            bus.register(0xfff0, 0x20, Box::<Uart>::default()),
+            Err(Error::InvalidPortRange(0xfff0, 0x20))
+        ));
+
+        assert_eq!(
+            bus.complete(&access(0x3f8, 1, true, 0x1234_5641)).unwrap(),
+            0x1234_5641
+        );
+        assert_eq!(*writes.lock().unwrap(), [(0, vec![0x41])]);
+
+        let rax = u64::MAX;
+        assert_eq!(
+            bus.complete(&access(0x3fd, 1, false, rax)).unwrap(),
+            0xffff_ffff_ffff_ff65
+        );
+        assert_eq!(
+            bus.complete(&access(0x3fd, 2, false, rax)).unwrap(),
+            0xffff_ffff_ffff_6565
+        );
+        assert_eq!(
+            bus.complete(&access(0x3fd, 4, false, rax)).unwrap(),
+            0x6565_6565
+        );
+        assert_eq!(bus.complete(&access(0x80, 2, false, 0)).unwrap(), 0xffff);
+        bus.complete(&access(0x80, 1, true, 0)).unwrap();
+
+        let mut strict = PortIoBus::new(UnclaimedPorts::Error);
+        assert!(matches!(
+            strict.complete(&access(0x80, 1, true, 0)),
+            Err(Error::PortUnclaimed(0x80))
+        ));
+    }
+
+    #[test]
+    fn dispatch() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(1))
+            .unwrap()
+            .setup()
+            .unwrap();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+
+        let mut bus = PortIoBus::default();
+        bus.register(0x3f8, 8, Box::<Uart>::default()).unwrap();
+
+        let mut context = ExitContext::default();
+        context.rip = 0xfff0;
+        let mut read = access(0x3f9, 1, false, 0xaa00);
+        read.instruction_byte_count = 2;
+        bus.dispatch(&mut vcpu, &context, &read).unwrap();
+        assert_eq!(
+            mock.register(0, Register::Rax),
+            Some(RegisterVal::Reg64(0xaa61))
+        );
+        assert_eq!(
+            mock.register(0, Register::Rip),
+            Some(RegisterVal::Reg64(0xfff2))
+        );
+    }
+
+    #[test]
+    fn rep_outsb_real_mode() {
+        let regions = [MemoryRegion::from_bytes(
+            0x10000,
+            MapGpaRangeFlags::Read | MapGpaRangeFlags::Write,
+            &[0; 0x10000],
+        )];
+        let memory = GuestMemory::new(&regions);
+        memory.write(0x1fffe, b"hi").unwrap();
+        memory.write(0x10000, b"!\n").unwrap();
+
+        let writes = Writes::default();
+        let mut bus = PortIoBus::default();
+        bus.register(0x3f8, 8, Box::new(Uart(writes.clone())))
+            .unwrap();
+
+        let mut context = ExitContext::default();
+        context.rip = 0x7c00;
+        // rep outsb
+        let mut outs = access(0x3f8, 1, true, 0);
+        outs.access_info |= IoPortAccessInfo::StringOp | IoPortAccessInfo::RepPrefix;
+        outs.instruction_bytes[..2].copy_from_slice(&[0xf3, 0x6e]);
+        outs.instruction_byte_count = 2;
+        outs.rcx = 0xdead_0000_0004;
+        outs.rsi = 0xfffe;
+        outs.ds.base = 0x10000;
+
+        let registers = bus
+            .complete_string(&context, &outs, &PagingState::default(), &memory)
+            .unwrap();
+        let written: Vec<u8> = writes.lock().unwrap().iter().map(|w| w.1[0]).collect();
+        // SI wraps around within the segment.
+        assert_eq!(written, b"hi!\n");
+        assert_eq!(
+            registers,
+            StringIoRegisters {
+                rsi: 0x2,
+                rdi: 0,
+                rcx: 0xdead_0000_0000,
+                rip: 0x7c02,
+            }
+        );
+    }
+
+    #[test]
+    fn rep_outsb_partial() {
+        let regions = [MemoryRegion::from_bytes(
+            0x10000,
+            MapGpaRangeFlags::Read | MapGpaRangeFlags::Write,
+            &[0; 0x10000],
+        )];
+        let memory = GuestMemory::new(&regions);
+        let writes = Writes::default();
+        let mut bus = PortIoBus::default();
+        bus.register(0x3f8, 8, Box::new(Uart(writes.clone())))
+            .unwrap();
+
+        let mut context = ExitContext::default();
+        context.rip = 0x7c00;
+        // rep outsb
+        let mut outs = access(0x3f8, 1, true, 0);
+        outs.access_info |= IoPortAccessInfo::StringOp | IoPortAccessInfo::RepPrefix;
+        outs.instruction_bytes[..2].copy_from_slice(&[0xf3, 0x6e]);
+        outs.instruction_byte_count = 2;
+        outs.rcx = 4;
+        outs.ds.base = 0x1fffe;
+
+        // The third byte lies past the end of guest memory.
+        let paging = PagingState::default();
+        match bus.complete_string(&context, &outs, &paging, &memory) {
+            Err(Error::StringIoInterrupted(registers, error)) => {
+                assert!(matches!(*error, Error::GpaUnmapped(0x20000)));
+                assert_eq!(
+                    registers,
+                    StringIoRegisters {
+                        rsi: 2,
+                        rdi: 0,
+                        rcx: 2,
+                        rip: 0x7c00,
+                    }
+                );
+            }
+            result => panic!("unexpected result {result:?}"),
+        }
+        assert_eq!(writes.lock().unwrap().len(), 2);
+
+        // Long counts stop after a batch and leave RIP on the instruction.
+        outs.ds.base = 0x10000;
+        outs.rcx = MAX_STRING_ITERATIONS + 1;
+        let registers = bus
+            .complete_string(&context, &outs, &paging, &memory)
+            .unwrap();
+        assert_eq!(
+            (registers.rsi, registers.rcx, registers.rip),
+            (MAX_STRING_ITERATIONS, 1, 0x7c00)
+        );
+        outs.rsi = registers.rsi;
+        outs.rcx = registers.rcx;
+        let registers = bus
+            .complete_string(&context, &outs, &paging, &memory)
+            .unwrap();
+        assert_eq!(
+            (registers.rsi, registers.rcx, registers.rip),
+            (MAX_STRING_ITERATIONS + 1, 0, 0x7c02)
+        );
+    }
+
+    #[test]
+    fn rep_insw_backwards() {
+        let regions = [MemoryRegion::from_bytes(
+            0,
+            MapGpaRangeFlags::Read | MapGpaRangeFlags::Write,
+            &[0; 0x2000],
+        )];
+        let memory = GuestMemory::new(&regions);
+        let mut bus = PortIoBus::default();
+        bus.register(0x1f0, 8, Box::<Uart>::default()).unwrap();
+
+        let mut context = ExitContext::default();
+        context.cs.attributes = X64SegmentRegisterAttributes::Default;
+        context.rflags = 1 << 10;
+        context.rip = 0x1000;
+        // rep insw
+        let mut ins = access(0x1f0, 2, false, 0);
+        ins.access_info |= IoPortAccessInfo::StringOp | IoPortAccessInfo::RepPrefix;
+        ins.instruction_bytes[..3].copy_from_slice(&[0x66, 0xf3, 0x6d]);
+        ins.instruction_byte_count = 3;
+        ins.rcx = 3;
+        ins.rdi = 0x1104;
+
+        let registers = bus
+            .complete_string(&context, &ins, &PagingState::default(), &memory)
+            .unwrap();
+        assert_eq!(
+            memory.read_obj::<[u16; 4]>(0x1100).unwrap(),
+            [0x6060, 0x6060, 0x6060, 0]
+        );
+        assert_eq!(
+            (registers.rdi, registers.rcx, registers.rip),
+            (0x10fe, 0, 0x1003)
+        );
+    }
+}
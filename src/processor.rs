@@ -1,13 +1,21 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
 use c2rust_bitfields::BitfieldStruct;
+#[cfg(windows)]
 use windows::Win32::System::Hypervisor::{
-    WHvDeleteVirtualProcessor, WHvGetVirtualProcessorRegisters, WHvRunVirtualProcessor,
-    WHvSetVirtualProcessorRegisters, WHV_HYPERCALL_CONTEXT,
-    WHV_HYPERCALL_CONTEXT_MAX_XMM_REGISTERS, WHV_MEMORY_ACCESS_CONTEXT, WHV_REGISTER_NAME,
-    WHV_REGISTER_VALUE, WHV_RUN_VP_CANCELED_CONTEXT, WHV_RUN_VP_CANCEL_REASON,
-    WHV_RUN_VP_EXIT_CONTEXT, WHV_RUN_VP_EXIT_CONTEXT_0, WHV_RUN_VP_EXIT_REASON,
-    WHV_SYNIC_SINT_DELIVERABLE_CONTEXT, WHV_VP_EXCEPTION_CONTEXT, WHV_VP_EXIT_CONTEXT,
+    WHV_HYPERCALL_CONTEXT, WHV_MEMORY_ACCESS_CONTEXT, WHV_REGISTER_NAME, WHV_REGISTER_VALUE,
+    WHV_RUN_VP_CANCELED_CONTEXT, WHV_RUN_VP_CANCEL_REASON, WHV_RUN_VP_EXIT_CONTEXT,
+    WHV_RUN_VP_EXIT_CONTEXT_0, WHV_RUN_VP_EXIT_REASON, WHV_SYNIC_SINT_DELIVERABLE_CONTEXT,
+    WHV_TRANSLATE_GVA_RESULT_CODE, WHV_UINT128, WHV_VP_EXCEPTION_CONTEXT, WHV_VP_EXIT_CONTEXT,
     WHV_X64_APIC_EOI_CONTEXT, WHV_X64_APIC_INIT_SIPI_CONTEXT, WHV_X64_APIC_SMI_CONTEXT,
     WHV_X64_APIC_WRITE_CONTEXT, WHV_X64_APIC_WRITE_TYPE, WHV_X64_CPUID_ACCESS_CONTEXT,
     WHV_X64_FP_CONTROL_STATUS_REGISTER, WHV_X64_FP_CONTROL_STATUS_REGISTER_0,
@@ -20,15 +28,20 @@ use windows::Win32::System::Hypervisor::{
 };
 
 use crate::{
+    apic::LapicState,
+    backend::Backend,
+    decode::{Instruction, RegisterOperand},
+    exit::{ExitAction, ExitHandler, RunBudget, RunOutcome},
     fields::{
         DeliverabilityNotificationsRegister, FpRegister, PendingExceptionEvent, PendingExtIntEvent,
         PendingInterruptionRegister,
     },
     flags::{
         InterruptStateRegister, IoPortAccessInfo, MemoryAccessInfo, MsrAccessInfo, RdtscInfo,
-        VpExceptionInfo, X64ExecutionState, X64SegmentRegisterAttributes,
+        TranslateGvaFlags, VpExceptionInfo, X64ExecutionState, X64SegmentRegisterAttributes,
     },
-    partition::PartitionHandle,
+    paging::PagingState,
+    state::{X64FpuState, X64Registers, X64SpecialRegisters},
     Result,
 };
 
@@ -55,6 +68,7 @@ pub enum RunExitReason {
     Canceled = 0x2001,
 }
 
+#[cfg(windows)]
 impl From<WHV_RUN_VP_EXIT_REASON> for RunExitReason {
     fn from(value: WHV_RUN_VP_EXIT_REASON) -> Self {
         // TODO: Can we enforce this differently?
@@ -83,7 +97,7 @@ impl From<WHV_RUN_VP_EXIT_REASON> for RunExitReason {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct SegmentRegister {
     pub base: u64,
     pub limit: u32,
@@ -91,6 +105,7 @@ pub struct SegmentRegister {
     pub attributes: X64SegmentRegisterAttributes,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_SEGMENT_REGISTER> for SegmentRegister {
     fn from(value: WHV_X64_SEGMENT_REGISTER) -> Self {
         Self {
@@ -102,6 +117,7 @@ impl From<WHV_X64_SEGMENT_REGISTER> for SegmentRegister {
     }
 }
 
+#[cfg(windows)]
 impl From<SegmentRegister> for WHV_X64_SEGMENT_REGISTER {
     fn from(value: SegmentRegister) -> Self {
         Self {
@@ -115,7 +131,7 @@ impl From<SegmentRegister> for WHV_X64_SEGMENT_REGISTER {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct X64FpControlStatusRegister {
     pub control: u16,
     pub status: u16,
@@ -125,6 +141,7 @@ pub struct X64FpControlStatusRegister {
     pub last_rip: u64,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_FP_CONTROL_STATUS_REGISTER> for X64FpControlStatusRegister {
     fn from(value: WHV_X64_FP_CONTROL_STATUS_REGISTER) -> Self {
         // SAFETY: The value only has one real "value" and then a reinterpreted raw value.
@@ -140,6 +157,7 @@ impl From<WHV_X64_FP_CONTROL_STATUS_REGISTER> for X64FpControlStatusRegister {
     }
 }
 
+#[cfg(windows)]
 impl From<X64FpControlStatusRegister> for WHV_X64_FP_CONTROL_STATUS_REGISTER {
     fn from(value: X64FpControlStatusRegister) -> Self {
         Self {
@@ -157,7 +175,7 @@ impl From<X64FpControlStatusRegister> for WHV_X64_FP_CONTROL_STATUS_REGISTER {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct X64XmmControlStatusRegister {
     // TODO: So this is technically a union (rdp, dp then ds)
     pub last_rdp: u64,
@@ -165,6 +183,7 @@ pub struct X64XmmControlStatusRegister {
     pub status_control_mask: u32,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_XMM_CONTROL_STATUS_REGISTER> for X64XmmControlStatusRegister {
     fn from(value: WHV_X64_XMM_CONTROL_STATUS_REGISTER) -> Self {
         // SAFETY: The value only has one real "value" and then a reinterpreted raw value.
@@ -178,6 +197,7 @@ impl From<WHV_X64_XMM_CONTROL_STATUS_REGISTER> for X64XmmControlStatusRegister {
     }
 }
 
+#[cfg(windows)]
 impl From<X64XmmControlStatusRegister> for WHV_X64_XMM_CONTROL_STATUS_REGISTER {
     fn from(value: X64XmmControlStatusRegister) -> Self {
         Self {
@@ -192,13 +212,24 @@ impl From<X64XmmControlStatusRegister> for WHV_X64_XMM_CONTROL_STATUS_REGISTER {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct TableRegister {
     pad: [u16; 3],
-    limit: u16,
-    base: u64,
+    pub limit: u16,
+    pub base: u64,
+}
+
+impl TableRegister {
+    pub fn new(base: u64, limit: u16) -> Self {
+        Self {
+            pad: [0; 3],
+            limit,
+            base,
+        }
+    }
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_TABLE_REGISTER> for TableRegister {
     fn from(value: WHV_X64_TABLE_REGISTER) -> Self {
         Self {
@@ -209,6 +240,7 @@ impl From<WHV_X64_TABLE_REGISTER> for TableRegister {
     }
 }
 
+#[cfg(windows)]
 impl From<TableRegister> for WHV_X64_TABLE_REGISTER {
     fn from(value: TableRegister) -> Self {
         Self {
@@ -219,7 +251,7 @@ impl From<TableRegister> for WHV_X64_TABLE_REGISTER {
     }
 }
 
-#[derive(BitfieldStruct, Clone, Copy)]
+#[derive(BitfieldStruct, Default, Clone, Copy)]
 pub struct ExitContext {
     pub execution_state: X64ExecutionState,
     #[bitfield(name = "instruction_len", ty = "u8", bits = "0..=3")]
@@ -244,6 +276,7 @@ impl Debug for ExitContext {
     }
 }
 
+#[cfg(windows)]
 impl From<WHV_VP_EXIT_CONTEXT> for ExitContext {
     fn from(value: WHV_VP_EXIT_CONTEXT) -> Self {
         Self {
@@ -255,37 +288,71 @@ impl From<WHV_VP_EXIT_CONTEXT> for ExitContext {
         }
     }
 }
+
+#[cfg(windows)]
+impl From<ExitContext> for WHV_VP_EXIT_CONTEXT {
+    fn from(value: ExitContext) -> Self {
+        Self {
+            ExecutionState: value.execution_state.into(),
+            _bitfield: value._bitfield[0],
+            Reserved: 0,
+            Reserved2: 0,
+            Cs: value.cs.into(),
+            Rip: value.rip,
+            Rflags: value.rflags,
+        }
+    }
+}
 // TODO: Terrible name microsoft...
 // TODO: Rename to ExitContextEvent?
 #[derive(Debug, Clone, Copy)]
 pub struct RunExitContext {
-    pub exit_reason: RunExitReason,
     pub context: ExitContext,
-    // TODO: Do we need this as an option? Or do all reasons have this... (See [RunExitContextExt::from_union] for more info)
-    pub ext: Option<RunExitContextExt>,
+    pub exit: VmExit,
+}
+
+impl RunExitContext {
+    pub fn exit_reason(&self) -> RunExitReason {
+        self.exit.reason()
+    }
 }
 
+#[cfg(windows)]
 impl From<WHV_RUN_VP_EXIT_CONTEXT> for RunExitContext {
     fn from(value: WHV_RUN_VP_EXIT_CONTEXT) -> Self {
-        let exit_reason = RunExitReason::from(value.ExitReason);
         Self {
-            exit_reason,
             context: ExitContext::from(value.VpContext),
-            ext: RunExitContextExt::from_union(exit_reason, value.Anonymous),
+            exit: VmExit::from_union(value.ExitReason.into(), value.Anonymous),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(i32)]
+pub enum MemoryAccessType {
+    Read = 0x0,
+    Write = 0x1,
+    Execute = 0x2,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MemoryAccessContext {
     pub instruction_byte_count: u8,
-    // TODO: Type this better, maybe these should be [Instruction]'s?
     pub instruction_bytes: [u8; 16],
     pub access_info: MemoryAccessInfo,
     pub gpa: u64,
     pub gva: u64,
 }
 
+impl MemoryAccessContext {
+    /// Decode the instruction that caused the exit.
+    pub fn instruction(&self, context: &ExitContext) -> Result<Instruction> {
+        let count = (self.instruction_byte_count as usize).min(self.instruction_bytes.len());
+        Instruction::decode(&self.instruction_bytes[..count], context.into())
+    }
+}
+
+#[cfg(windows)]
 impl From<WHV_MEMORY_ACCESS_CONTEXT> for MemoryAccessContext {
     fn from(value: WHV_MEMORY_ACCESS_CONTEXT) -> Self {
         Self {
@@ -298,10 +365,23 @@ impl From<WHV_MEMORY_ACCESS_CONTEXT> for MemoryAccessContext {
     }
 }
 
+#[cfg(windows)]
+impl From<MemoryAccessContext> for WHV_MEMORY_ACCESS_CONTEXT {
+    fn from(value: MemoryAccessContext) -> Self {
+        Self {
+            InstructionByteCount: value.instruction_byte_count,
+            Reserved: [0; 3],
+            InstructionBytes: value.instruction_bytes,
+            AccessInfo: value.access_info.into(),
+            Gpa: value.gpa,
+            Gva: value.gva,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct IoPortAccessContext {
     pub instruction_byte_count: u8,
-    // TODO: Type this better, maybe these should be [Instruction]'s?
     pub instruction_bytes: [u8; 16],
     pub access_info: IoPortAccessInfo,
     pub port_number: u16,
@@ -313,6 +393,15 @@ pub struct IoPortAccessContext {
     pub es: SegmentRegister,
 }
 
+impl IoPortAccessContext {
+    /// Decode the instruction that caused the exit.
+    pub fn instruction(&self, context: &ExitContext) -> Result<Instruction> {
+        let count = (self.instruction_byte_count as usize).min(self.instruction_bytes.len());
+        Instruction::decode(&self.instruction_bytes[..count], context.into())
+    }
+}
+
+#[cfg(windows)]
 impl From<WHV_X64_IO_PORT_ACCESS_CONTEXT> for IoPortAccessContext {
     fn from(value: WHV_X64_IO_PORT_ACCESS_CONTEXT) -> Self {
         Self {
@@ -330,6 +419,26 @@ impl From<WHV_X64_IO_PORT_ACCESS_CONTEXT> for IoPortAccessContext {
     }
 }
 
+#[cfg(windows)]
+impl From<IoPortAccessContext> for WHV_X64_IO_PORT_ACCESS_CONTEXT {
+    fn from(value: IoPortAccessContext) -> Self {
+        Self {
+            InstructionByteCount: value.instruction_byte_count,
+            Reserved: [0; 3],
+            InstructionBytes: value.instruction_bytes,
+            AccessInfo: value.access_info.into(),
+            PortNumber: value.port_number,
+            Reserved2: [0; 3],
+            Rax: value.rax,
+            Rcx: value.rcx,
+            Rsi: value.rsi,
+            Rdi: value.rdi,
+            Ds: value.ds.into(),
+            Es: value.es.into(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MsrAccessContext {
     pub access_info: MsrAccessInfo,
@@ -338,6 +447,7 @@ pub struct MsrAccessContext {
     pub rdx: u64,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_MSR_ACCESS_CONTEXT> for MsrAccessContext {
     fn from(value: WHV_X64_MSR_ACCESS_CONTEXT) -> Self {
         Self {
@@ -361,6 +471,7 @@ pub struct CpuidAccessContext {
     pub default_result_rbx: u64,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_CPUID_ACCESS_CONTEXT> for CpuidAccessContext {
     fn from(value: WHV_X64_CPUID_ACCESS_CONTEXT) -> Self {
         Self {
@@ -380,7 +491,6 @@ impl From<WHV_X64_CPUID_ACCESS_CONTEXT> for CpuidAccessContext {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct VpExceptionContext {
     pub instruction_byte_count: u8,
-    // TODO: Type this better, maybe these should be [Instruction]'s?
     pub instruction_bytes: [u8; 16],
     pub exception_info: VpExceptionInfo,
     pub exception_type: u8,
@@ -389,6 +499,15 @@ pub struct VpExceptionContext {
     pub exception_param: u64,
 }
 
+impl VpExceptionContext {
+    /// Decode the instruction that caused the exit.
+    pub fn instruction(&self, context: &ExitContext) -> Result<Instruction> {
+        let count = (self.instruction_byte_count as usize).min(self.instruction_bytes.len());
+        Instruction::decode(&self.instruction_bytes[..count], context.into())
+    }
+}
+
+#[cfg(windows)]
 impl From<WHV_VP_EXCEPTION_CONTEXT> for VpExceptionContext {
     fn from(value: WHV_VP_EXCEPTION_CONTEXT) -> Self {
         Self {
@@ -408,6 +527,7 @@ pub enum UnsupportedFeatureCode {
     TaskSwitchTss = 2,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_UNSUPPORTED_FEATURE_CODE> for UnsupportedFeatureCode {
     fn from(value: WHV_X64_UNSUPPORTED_FEATURE_CODE) -> Self {
         // TODO: Can we enforce this differently?
@@ -425,6 +545,7 @@ pub struct UnsupportedFeatureContext {
     pub feature_param: u64,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_UNSUPPORTED_FEATURE_CONTEXT> for UnsupportedFeatureContext {
     fn from(value: WHV_X64_UNSUPPORTED_FEATURE_CONTEXT) -> Self {
         Self {
@@ -439,6 +560,7 @@ pub enum VpCancelReason {
     User = 0,
 }
 
+#[cfg(windows)]
 impl From<WHV_RUN_VP_CANCEL_REASON> for VpCancelReason {
     fn from(value: WHV_RUN_VP_CANCEL_REASON) -> Self {
         // TODO: Can we enforce this differently?
@@ -454,6 +576,7 @@ pub struct VpCanceledContext {
     pub cancel_reason: VpCancelReason,
 }
 
+#[cfg(windows)]
 impl From<WHV_RUN_VP_CANCELED_CONTEXT> for VpCanceledContext {
     fn from(value: WHV_RUN_VP_CANCELED_CONTEXT) -> Self {
         Self {
@@ -467,10 +590,11 @@ pub struct ApicEoiContext {
     pub interrupt_vec: u32,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_APIC_EOI_CONTEXT> for ApicEoiContext {
     fn from(value: WHV_X64_APIC_EOI_CONTEXT) -> Self {
         Self {
-            interrupt_vec: value.InterruptVector.into(),
+            interrupt_vec: value.InterruptVector,
         }
     }
 }
@@ -484,6 +608,7 @@ pub struct RdtscContext {
     pub rdtsc_info: RdtscInfo,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_RDTSC_CONTEXT> for RdtscContext {
     fn from(value: WHV_X64_RDTSC_CONTEXT) -> Self {
         Self {
@@ -501,14 +626,18 @@ pub struct ApicSmiContext {
     pub apic_icr: u64,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_APIC_SMI_CONTEXT> for ApicSmiContext {
     fn from(value: WHV_X64_APIC_SMI_CONTEXT) -> Self {
         Self {
-            apic_icr: value.ApicIcr.into(),
+            apic_icr: value.ApicIcr,
         }
     }
 }
 
+/// The number of XMM registers passed with a [`HypercallContext`].
+pub const HYPERCALL_CONTEXT_MAX_XMM_REGISTERS: usize = 6;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct HypercallContext {
     pub rax: u64,
@@ -519,9 +648,10 @@ pub struct HypercallContext {
     pub rsi: u64,
     pub rdi: u64,
     // TODO: We should really have an adjustable stride for this. Like splitting 128 bits into 4 * 32 bits for processing.
-    pub xmm_registers: [u128; WHV_HYPERCALL_CONTEXT_MAX_XMM_REGISTERS as usize],
+    pub xmm_registers: [u128; HYPERCALL_CONTEXT_MAX_XMM_REGISTERS],
 }
 
+#[cfg(windows)]
 impl From<WHV_HYPERCALL_CONTEXT> for HypercallContext {
     fn from(value: WHV_HYPERCALL_CONTEXT) -> Self {
         Self {
@@ -533,7 +663,12 @@ impl From<WHV_HYPERCALL_CONTEXT> for HypercallContext {
             rsi: value.Rsi,
             rdi: value.Rdi,
             // TODO: This needs to be removed...
-            xmm_registers: unsafe { std::mem::transmute(value.XmmRegisters) },
+            xmm_registers: unsafe {
+                std::mem::transmute::<
+                    [WHV_UINT128; HYPERCALL_CONTEXT_MAX_XMM_REGISTERS],
+                    [u128; HYPERCALL_CONTEXT_MAX_XMM_REGISTERS],
+                >(value.XmmRegisters)
+            },
         }
     }
 }
@@ -543,6 +678,7 @@ pub struct InterruptionDeliverableContext {
     pub deliverable_type: PendingInterruptionType,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_INTERRUPTION_DELIVERABLE_CONTEXT> for InterruptionDeliverableContext {
     fn from(value: WHV_X64_INTERRUPTION_DELIVERABLE_CONTEXT) -> Self {
         Self {
@@ -558,6 +694,7 @@ pub enum PendingInterruptionType {
     Exception = 3,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_PENDING_INTERRUPTION_TYPE> for PendingInterruptionType {
     fn from(value: WHV_X64_PENDING_INTERRUPTION_TYPE) -> Self {
         // TODO: Can we enforce this differently?
@@ -575,6 +712,7 @@ pub struct X64ApicInitSipiContext {
     pub apic_icr: u64,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_APIC_INIT_SIPI_CONTEXT> for X64ApicInitSipiContext {
     fn from(value: WHV_X64_APIC_INIT_SIPI_CONTEXT) -> Self {
         Self {
@@ -589,6 +727,7 @@ pub struct X64ApicWriteContext {
     pub write_value: u64,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_APIC_WRITE_CONTEXT> for X64ApicWriteContext {
     fn from(value: WHV_X64_APIC_WRITE_CONTEXT) -> Self {
         Self {
@@ -607,6 +746,7 @@ pub enum ApicWriteType {
     Lint1 = 0x360,
 }
 
+#[cfg(windows)]
 impl From<WHV_X64_APIC_WRITE_TYPE> for ApicWriteType {
     fn from(value: WHV_X64_APIC_WRITE_TYPE) -> Self {
         // TODO: Can we enforce this differently?
@@ -626,6 +766,7 @@ pub struct SynicSintDeliverableContext {
     pub deliverable_sints: u16,
 }
 
+#[cfg(windows)]
 impl From<WHV_SYNIC_SINT_DELIVERABLE_CONTEXT> for SynicSintDeliverableContext {
     fn from(value: WHV_SYNIC_SINT_DELIVERABLE_CONTEXT) -> Self {
         Self {
@@ -634,127 +775,476 @@ impl From<WHV_SYNIC_SINT_DELIVERABLE_CONTEXT> for SynicSintDeliverableContext {
     }
 }
 
-// TODO: Terrible placeholder name.
-// TODO: Verify all extended contexts are listed.
+/// The reason a virtual processor exited, along with the context specific to that reason.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum RunExitContextExt {
+pub enum VmExit {
+    None,
     MemoryAccess(MemoryAccessContext),
     IoPortAccess(IoPortAccessContext),
-    MsrAccess(MsrAccessContext),
-    CpuidAccess(CpuidAccessContext),
-    // TODO: Ahhh this has Vp prefix...
-    VpException(VpExceptionContext),
-    InterruptWindow(InterruptionDeliverableContext),
+    UnrecoverableException,
+    InvalidVpRegisterValue,
     UnsupportedFeature(UnsupportedFeatureContext),
-    // TODO: Rename to VpCancelReason?
-    CancelReason(VpCanceledContext),
+    InterruptWindow(InterruptionDeliverableContext),
+    Halt,
     ApicEoi(ApicEoiContext),
-    ReadTsc(RdtscContext),
+    SynicSintDeliverable(SynicSintDeliverableContext),
+    MsrAccess(MsrAccessContext),
+    Cpuid(CpuidAccessContext),
+    Exception(VpExceptionContext),
+    Rdtsc(RdtscContext),
     ApicSmi(ApicSmiContext),
     Hypercall(HypercallContext),
     ApicInitSipi(X64ApicInitSipiContext),
     ApicWrite(X64ApicWriteContext),
-    SynicSintDeliverable(SynicSintDeliverableContext),
+    Canceled(VpCancelReason),
 }
 
-impl RunExitContextExt {
-    pub fn from_union(
-        exit_reason: RunExitReason,
-        context_ext: WHV_RUN_VP_EXIT_CONTEXT_0,
-    ) -> Option<Self> {
+impl VmExit {
+    pub fn reason(&self) -> RunExitReason {
+        match self {
+            Self::None => RunExitReason::None,
+            Self::MemoryAccess(_) => RunExitReason::MemoryAccess,
+            Self::IoPortAccess(_) => RunExitReason::X64IoPortAccess,
+            Self::UnrecoverableException => RunExitReason::UnrecoverableException,
+            Self::InvalidVpRegisterValue => RunExitReason::InvalidVpRegisterValue,
+            Self::UnsupportedFeature(_) => RunExitReason::UnsupportedFeature,
+            Self::InterruptWindow(_) => RunExitReason::X64InterruptWindow,
+            Self::Halt => RunExitReason::X64Halt,
+            Self::ApicEoi(_) => RunExitReason::X64ApicEoi,
+            Self::SynicSintDeliverable(_) => RunExitReason::SynicSintDeliverable,
+            Self::MsrAccess(_) => RunExitReason::X64MsrAccess,
+            Self::Cpuid(_) => RunExitReason::X64Cpuid,
+            Self::Exception(_) => RunExitReason::Exception,
+            Self::Rdtsc(_) => RunExitReason::X64Rdtsc,
+            Self::ApicSmi(_) => RunExitReason::X64ApicSmiTrap,
+            Self::Hypercall(_) => RunExitReason::Hypercall,
+            Self::ApicInitSipi(_) => RunExitReason::X64ApicInitSipiTrap,
+            Self::ApicWrite(_) => RunExitReason::X64ApicWriteTrap,
+            Self::Canceled(_) => RunExitReason::Canceled,
+        }
+    }
+
+    #[cfg(windows)]
+    fn from_union(exit_reason: RunExitReason, context_ext: WHV_RUN_VP_EXIT_CONTEXT_0) -> Self {
         // SAFETY: The reason corresponds to the union variant.
         unsafe {
             match exit_reason {
-                RunExitReason::None => None,
-                RunExitReason::MemoryAccess => {
-                    Some(Self::MemoryAccess(context_ext.MemoryAccess.into()))
-                }
+                RunExitReason::None => Self::None,
+                RunExitReason::MemoryAccess => Self::MemoryAccess(context_ext.MemoryAccess.into()),
                 RunExitReason::X64IoPortAccess => {
-                    Some(Self::IoPortAccess(context_ext.IoPortAccess.into()))
+                    Self::IoPortAccess(context_ext.IoPortAccess.into())
+                }
+                RunExitReason::UnrecoverableException => Self::UnrecoverableException,
+                RunExitReason::InvalidVpRegisterValue => Self::InvalidVpRegisterValue,
+                RunExitReason::UnsupportedFeature => {
+                    Self::UnsupportedFeature(context_ext.UnsupportedFeature.into())
                 }
-                RunExitReason::UnrecoverableException => None, // TODO: Validate this is None
-                RunExitReason::InvalidVpRegisterValue => None, // TODO: Validate this is None
-                RunExitReason::UnsupportedFeature => Some(Self::UnsupportedFeature(
-                    context_ext.UnsupportedFeature.into(),
-                )),
                 RunExitReason::X64InterruptWindow => {
-                    Some(Self::InterruptWindow(context_ext.InterruptWindow.into()))
+                    Self::InterruptWindow(context_ext.InterruptWindow.into())
                 }
-                RunExitReason::X64Halt => None,
-                RunExitReason::X64ApicEoi => Some(Self::ApicEoi(context_ext.ApicEoi.into())),
-                RunExitReason::SynicSintDeliverable => Some(Self::SynicSintDeliverable(
-                    context_ext.SynicSintDeliverable.into(),
-                )),
-                RunExitReason::X64MsrAccess => Some(Self::MsrAccess(context_ext.MsrAccess.into())),
-                RunExitReason::X64Cpuid => Some(Self::CpuidAccess(context_ext.CpuidAccess.into())),
-                RunExitReason::Exception => Some(Self::VpException(context_ext.VpException.into())),
-                RunExitReason::X64Rdtsc => Some(Self::ReadTsc(context_ext.ReadTsc.into())),
-                RunExitReason::X64ApicSmiTrap => Some(Self::ApicSmi(context_ext.ApicSmi.into())),
-                RunExitReason::Hypercall => Some(Self::Hypercall(context_ext.Hypercall.into())),
-                RunExitReason::X64ApicInitSipiTrap => {
-                    Some(Self::ApicInitSipi(context_ext.ApicInitSipi.into()))
+                RunExitReason::X64Halt => Self::Halt,
+                RunExitReason::X64ApicEoi => Self::ApicEoi(context_ext.ApicEoi.into()),
+                RunExitReason::SynicSintDeliverable => {
+                    Self::SynicSintDeliverable(context_ext.SynicSintDeliverable.into())
                 }
-                RunExitReason::X64ApicWriteTrap => {
-                    Some(Self::ApicWrite(context_ext.ApicWrite.into()))
+                RunExitReason::X64MsrAccess => Self::MsrAccess(context_ext.MsrAccess.into()),
+                RunExitReason::X64Cpuid => Self::Cpuid(context_ext.CpuidAccess.into()),
+                RunExitReason::Exception => Self::Exception(context_ext.VpException.into()),
+                RunExitReason::X64Rdtsc => Self::Rdtsc(context_ext.ReadTsc.into()),
+                RunExitReason::X64ApicSmiTrap => Self::ApicSmi(context_ext.ApicSmi.into()),
+                RunExitReason::Hypercall => Self::Hypercall(context_ext.Hypercall.into()),
+                RunExitReason::X64ApicInitSipiTrap => {
+                    Self::ApicInitSipi(context_ext.ApicInitSipi.into())
                 }
+                RunExitReason::X64ApicWriteTrap => Self::ApicWrite(context_ext.ApicWrite.into()),
                 RunExitReason::Canceled => {
-                    Some(Self::CancelReason(context_ext.CancelReason.into()))
+                    Self::Canceled(VpCanceledContext::from(context_ext.CancelReason).cancel_reason)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(i32)]
+pub enum TranslateGvaResultCode {
+    Success = 0x0,
+    PageNotPresent = 0x1,
+    PrivilegeViolation = 0x2,
+    InvalidPageTableFlags = 0x3,
+    GpaUnmapped = 0x4,
+    GpaNoReadAccess = 0x5,
+    GpaNoWriteAccess = 0x6,
+    GpaIllegalOverlayAccess = 0x7,
+    Intercept = 0x8,
+}
+
+#[cfg(windows)]
+impl From<WHV_TRANSLATE_GVA_RESULT_CODE> for TranslateGvaResultCode {
+    fn from(value: WHV_TRANSLATE_GVA_RESULT_CODE) -> Self {
+        // TODO: Can we enforce this differently?
+        match value.0 {
+            0x0 => Self::Success,
+            0x1 => Self::PageNotPresent,
+            0x2 => Self::PrivilegeViolation,
+            0x3 => Self::InvalidPageTableFlags,
+            0x4 => Self::GpaUnmapped,
+            0x5 => Self::GpaNoReadAccess,
+            0x6 => Self::GpaNoWriteAccess,
+            0x7 => Self::GpaIllegalOverlayAccess,
+            0x8 => Self::Intercept,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<TranslateGvaResultCode> for WHV_TRANSLATE_GVA_RESULT_CODE {
+    fn from(value: TranslateGvaResultCode) -> Self {
+        Self(value as i32)
+    }
+}
+
+/// The outcome of [`VirtualProcessor::translate_gva`], `gpa` is only meaningful on success.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GvaTranslation {
+    pub result_code: TranslateGvaResultCode,
+    pub gpa: u64,
+}
+
+/// Where `VirtualProcessor::run` is, see `CancelState`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum RunPhase {
+    #[default]
+    Idle,
+    Entering,
+    InGuest,
+}
+
+#[derive(Debug, Default)]
+struct RunState {
+    phase: RunPhase,
+    /// A cancellation was handed to the hypervisor during the current run.
+    delivered: bool,
+    /// A cancellation handed to the hypervisor landed after the guest had already exited, so the
+    /// hypervisor still holds it for the next run.
+    stale: bool,
+}
+
+/// Shared between a virtual processor and its cancel handles.
+///
+/// A cancellation is only ever delivered once, by whoever takes the `pending` flag. The run takes
+/// it before entering the guest and returns a canceled exit itself, the handle only takes it once
+/// the run is in the guest and hands it to the hypervisor instead.
+///
+/// `WHvCancelRunVirtualProcessor` on a virtual processor that is not running is not dropped, the
+/// next `WHvRunVirtualProcessor` returns a canceled exit right away. The handle only cancels while
+/// holding `run`, so a cancellation that loses the race with a regular exit is known to the run
+/// and the canceled exit it leaves behind is swallowed by the next run.
+#[derive(Debug, Default)]
+struct CancelState {
+    run: Mutex<RunState>,
+    pending: AtomicBool,
+}
+
+impl CancelState {
+    fn run(&self) -> MutexGuard<'_, RunState> {
+        self.run.lock().unwrap()
+    }
+
+    fn set_phase(&self, phase: RunPhase) {
+        self.run().phase = phase;
+    }
+
+    /// Account for the exit the hypervisor returned, `false` if it was a leftover cancellation
+    /// and the guest has to be entered again.
+    fn finish(&self, exit: &VmExit) -> bool {
+        let mut run = self.run();
+        let canceled = matches!(exit, VmExit::Canceled(_));
+        let delivered = std::mem::take(&mut run.delivered);
+        if canceled && run.stale && !delivered {
+            run.stale = false;
+            return false;
+        }
+        run.stale = delivered && !canceled;
+        run.phase = RunPhase::Idle;
+        true
+    }
+}
+
+/// Cancels runs of a [`VirtualProcessor`] from any thread.
+///
+/// A cancellation requested while the virtual processor is not running is kept until the next
+/// [`VirtualProcessor::run`], which then returns [`VmExit::Canceled`] without entering the guest.
+#[derive(Debug, Clone)]
+pub struct VpCancelHandle {
+    backend: Arc<dyn Backend>,
+    index: u32,
+    state: Arc<CancelState>,
+}
+
+impl VpCancelHandle {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn cancel(&self) -> Result<()> {
+        self.state.pending.store(true, Ordering::SeqCst);
+        self.deliver()
+    }
+
+    /// Paired with `VirtualProcessor::run`, which checks the pending flag again after committing
+    /// to the guest. Either the run sees the flag or we see it in the guest, if both do only one
+    /// of us takes the flag.
+    fn deliver(&self) -> Result<()> {
+        let mut run = self.state.run();
+        if run.phase == RunPhase::InGuest && self.state.pending.swap(false, Ordering::SeqCst) {
+            self.backend.cancel_run_virtual_processor(self.index)?;
+            run.delivered = true;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct WatchdogState {
+    deadline: Option<Instant>,
+    fired: bool,
+    shutdown: bool,
+}
+
+#[derive(Debug, Default)]
+struct WatchdogShared {
+    state: Mutex<WatchdogState>,
+    wake: Condvar,
+}
+
+/// Cancels a virtual processor once the deadline it is armed with passes.
+///
+/// Each virtual processor spawns one on its first budgeted run and re-arms it for every run after.
+#[derive(Debug)]
+struct Watchdog {
+    handle: VpCancelHandle,
+    shared: Arc<WatchdogShared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    fn spawn(handle: VpCancelHandle) -> Self {
+        let shared = Arc::new(WatchdogShared::default());
+        let thread = thread::spawn({
+            let handle = handle.clone();
+            let shared = shared.clone();
+            move || {
+                let mut state = shared.state.lock().unwrap();
+                while !state.shutdown {
+                    let Some(deadline) = state.deadline else {
+                        state = shared.wake.wait(state).unwrap();
+                        continue;
+                    };
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        state.deadline = None;
+                        state.fired = true;
+                        // Nothing to report to, the run loop will just not see the cancellation.
+                        let _ = handle.cancel();
+                    } else {
+                        state = shared.wake.wait_timeout(state, timeout).unwrap().0;
+                    }
                 }
             }
+        });
+        Self {
+            handle,
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, WatchdogState> {
+        self.shared.state.lock().unwrap()
+    }
+
+    /// Cancel the virtual processor at `deadline`, until the returned guard is dropped.
+    fn arm(self: &Arc<Self>, deadline: Instant) -> Result<ArmedWatchdog> {
+        let armed = ArmedWatchdog(self.clone());
+        let mut state = self.state();
+        state.fired = false;
+        if deadline <= Instant::now() {
+            state.fired = true;
+            self.handle.cancel()?;
+        } else {
+            state.deadline = Some(deadline);
+            self.shared.wake.notify_one();
+        }
+        Ok(armed)
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.state().shutdown = true;
+        self.shared.wake.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A deadline set with [`Watchdog::arm`], cleared again when dropped.
+struct ArmedWatchdog(Arc<Watchdog>);
+
+impl ArmedWatchdog {
+    fn fired(&self) -> bool {
+        self.0.state().fired
+    }
+}
+
+impl Drop for ArmedWatchdog {
+    fn drop(&mut self) {
+        let mut state = self.0.state();
+        state.deadline = None;
+        // Don't leave our cancellation for whoever runs the virtual processor next.
+        if std::mem::take(&mut state.fired) {
+            self.0.handle.state.pending.store(false, Ordering::SeqCst);
         }
     }
 }
 
 #[derive(Debug)]
 pub struct VirtualProcessor {
-    partition_handle: Arc<PartitionHandle>,
+    backend: Arc<dyn Backend>,
     index: u32,
+    cancel: Arc<CancelState>,
+    watchdog: Option<Arc<Watchdog>>,
 }
 
 impl VirtualProcessor {
-    pub fn new(partition_handle: Arc<PartitionHandle>, index: u32) -> Self {
+    pub fn new(backend: Arc<dyn Backend>, index: u32) -> Self {
         // TODO: Sanity checks here. (Check index to make sure its at or below the processor count in partition.)
         Self {
-            partition_handle,
+            backend,
             index,
+            cancel: Default::default(),
+            watchdog: None,
+        }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn cancel_handle(&self) -> VpCancelHandle {
+        VpCancelHandle {
+            backend: self.backend.clone(),
+            index: self.index,
+            state: self.cancel.clone(),
         }
     }
 
-    // TODO: Return exit context.
     pub fn run(&mut self) -> Result<RunExitContext> {
-        let mut raw_exit_context: WHV_RUN_VP_EXIT_CONTEXT = Default::default();
-        unsafe {
-            WHvRunVirtualProcessor(
-                self.partition_handle.0,
-                self.index,
-                std::mem::transmute(&mut raw_exit_context),
-                std::mem::size_of::<WHV_RUN_VP_EXIT_CONTEXT>().try_into()?,
-            )?;
+        let state = self.cancel.clone();
+        state.set_phase(RunPhase::Entering);
+        let canceled = state.pending.swap(false, Ordering::SeqCst) || {
+            state.set_phase(RunPhase::InGuest);
+            state.pending.swap(false, Ordering::SeqCst)
+        };
+        if canceled {
+            state.set_phase(RunPhase::Idle);
+            return self.canceled_exit();
+        }
+
+        loop {
+            let exit = self.backend.run_virtual_processor(self.index);
+            match exit {
+                Ok(exit) if !state.finish(&exit.exit) => continue,
+                Err(_) => state.set_phase(RunPhase::Idle),
+                _ => {}
+            }
+            return exit;
         }
-        Ok(raw_exit_context.into())
     }
 
-    pub fn set_register(&mut self, register: Register, value: RegisterVal) -> Result<()> {
-        self.set_registers(&[(register, value)])
+    fn watchdog(&mut self) -> Arc<Watchdog> {
+        let handle = self.cancel_handle();
+        self.watchdog
+            .get_or_insert_with(|| Arc::new(Watchdog::spawn(handle)))
+            .clone()
     }
 
-    pub fn set_registers(&mut self, register_vals: &[(Register, RegisterVal)]) -> Result<()> {
-        let (raw_registers, raw_values): (Vec<_>, Vec<_>) = register_vals
-            .into_iter()
-            .map(|(r, v)| (WHV_REGISTER_NAME::from(*r), WHV_REGISTER_VALUE::from(*v)))
-            .unzip();
+    /// The exit for a cancellation that arrived before the guest was entered.
+    fn canceled_exit(&mut self) -> Result<RunExitContext> {
+        let values = self
+            .backend
+            .get_registers(self.index, &[Register::Cs, Register::Rip, Register::Rflags])?;
+        let mut context = ExitContext::default();
+        if let RegisterVal::Segment(cs) = values[0] {
+            context.cs = cs;
+        }
+        context.rip = values[1].as_u64().unwrap_or_default();
+        context.rflags = values[2].as_u64().unwrap_or_default();
+        Ok(RunExitContext {
+            context,
+            exit: VmExit::Canceled(VpCancelReason::User),
+        })
+    }
 
-        unsafe {
-            WHvSetVirtualProcessorRegisters(
-                self.partition_handle.0,
-                self.index,
-                raw_registers.as_ptr(),
-                raw_registers.len().try_into()?,
-                raw_values.as_ptr(),
-            )?;
+    /// Run the virtual processor until `handler` stops it, returning what it stopped with.
+    pub fn run_loop<H: ExitHandler>(&mut self, handler: &mut H) -> Result<H::Stop> {
+        loop {
+            let exit = self.run()?;
+            if let ExitAction::Stop(stop) = handler.handle(self, &exit)? {
+                return Ok(stop);
+            }
         }
+    }
 
-        Ok(())
+    /// Run the virtual processor once, canceling it if it has not exited after `timeout`.
+    pub fn run_for(&mut self, timeout: Duration) -> Result<RunOutcome<RunExitContext>> {
+        let watchdog = self.watchdog().arm(Instant::now() + timeout)?;
+        let exit = self.run()?;
+        Ok(match exit.exit {
+            VmExit::Canceled(_) if watchdog.fired() => RunOutcome::BudgetExpired(exit.context),
+            _ => RunOutcome::Finished(exit),
+        })
+    }
+
+    /// Like [`VirtualProcessor::run_loop`], but gives up once `budget` is spent.
+    ///
+    /// Cancellations not caused by the deadline are passed to the handler as usual.
+    pub fn run_loop_with_budget<H: ExitHandler>(
+        &mut self,
+        handler: &mut H,
+        budget: RunBudget,
+    ) -> Result<RunOutcome<H::Stop>> {
+        let watchdog = match budget.deadline {
+            Some(deadline) => Some(self.watchdog().arm(deadline)?),
+            None => None,
+        };
+        let mut exits = 0;
+        loop {
+            let exit = self.run()?;
+            if matches!(exit.exit, VmExit::Canceled(_))
+                && watchdog.as_ref().is_some_and(ArmedWatchdog::fired)
+            {
+                return Ok(RunOutcome::BudgetExpired(exit.context));
+            }
+            if let ExitAction::Stop(stop) = handler.handle(self, &exit)? {
+                return Ok(RunOutcome::Finished(stop));
+            }
+            exits += 1;
+            if budget.max_exits.is_some_and(|max_exits| exits >= max_exits) {
+                return Ok(RunOutcome::BudgetExpired(exit.context));
+            }
+        }
+    }
+
+    pub fn set_register(&mut self, register: Register, value: RegisterVal) -> Result<()> {
+        self.set_registers(&[(register, value)])
+    }
+
+    pub fn set_registers(&mut self, register_vals: &[(Register, RegisterVal)]) -> Result<()> {
+        self.backend.set_registers(self.index, register_vals)
     }
 
     pub fn get_register(&mut self, register: Register) -> Result<RegisterVal> {
@@ -765,41 +1255,348 @@ impl VirtualProcessor {
         &mut self,
         registers: &'a [Register],
     ) -> Result<Vec<(&'a Register, RegisterVal)>> {
-        let raw_registers: Vec<_> = registers
-            .into_iter()
-            .map(|&r| WHV_REGISTER_NAME::from(r))
-            .collect();
-        let mut raw_values: Vec<WHV_REGISTER_VALUE> = vec![Default::default(); registers.len()];
+        let values = self.backend.get_registers(self.index, registers)?;
+        Ok(registers.iter().zip(values).collect())
+    }
 
-        unsafe {
-            WHvGetVirtualProcessorRegisters(
-                self.partition_handle.0,
-                self.index,
-                raw_registers.as_ptr(),
-                raw_registers.len().try_into()?,
-                raw_values.as_mut_ptr(),
-            )?;
-        }
+    pub fn get_regs(&mut self) -> Result<X64Registers> {
+        let values = self
+            .backend
+            .get_registers(self.index, &X64Registers::REGISTERS)?;
+        Ok(X64Registers::from_values(&values))
+    }
 
-        let reg_tup = registers
-            .iter()
-            .zip(raw_values.iter())
-            .map(|(r, v)| (r, RegisterVal::from_union(r.ty(), *v)))
-            .collect::<Vec<(_, _)>>();
+    pub fn set_regs(&mut self, regs: &X64Registers) -> Result<()> {
+        self.set_registers(&regs.to_values())
+    }
+
+    pub fn get_sregs(&mut self) -> Result<X64SpecialRegisters> {
+        let values = self
+            .backend
+            .get_registers(self.index, &X64SpecialRegisters::REGISTERS)?;
+        Ok(X64SpecialRegisters::from_values(&values))
+    }
+
+    pub fn set_sregs(&mut self, sregs: &X64SpecialRegisters) -> Result<()> {
+        self.set_registers(&sregs.to_values())
+    }
+
+    pub fn get_fpu(&mut self) -> Result<X64FpuState> {
+        let values = self
+            .backend
+            .get_registers(self.index, &X64FpuState::REGISTERS)?;
+        Ok(X64FpuState::from_values(&values))
+    }
+
+    pub fn set_fpu(&mut self, fpu: &X64FpuState) -> Result<()> {
+        self.set_registers(&fpu.to_values())
+    }
+
+    /// The raw XSAVE area, see [`crate::xsave::XsaveArea::parse`] for its contents.
+    pub fn get_xsave_state(&mut self) -> Result<Vec<u8>> {
+        self.backend.get_xsave_state(self.index)
+    }
+
+    pub fn set_xsave_state(&mut self, state: &[u8]) -> Result<()> {
+        self.backend.set_xsave_state(self.index, state)
+    }
+
+    /// The local APIC state, only held by partitions with a
+    /// [`crate::partition::PartitionProperty::LocalApicEmulationMode`].
+    pub fn get_lapic_state(&mut self) -> Result<LapicState> {
+        LapicState::from_bytes(&self.backend.get_lapic_state(self.index)?)
+    }
+
+    pub fn set_lapic_state(&mut self, state: &LapicState) -> Result<()> {
+        self.backend.set_lapic_state(self.index, state.as_bytes())
+    }
+
+    /// Translate `gva` with the hypervisor, using the current paging state and privilege level of the
+    /// virtual processor.
+    pub fn translate_gva(&mut self, gva: u64, flags: TranslateGvaFlags) -> Result<GvaTranslation> {
+        self.backend.translate_gva(self.index, gva, flags)
+    }
 
-        Ok(reg_tup)
+    /// Read the control registers needed to walk the guest's page tables.
+    pub fn paging_state(&mut self) -> Result<PagingState> {
+        let values = self.backend.get_registers(
+            self.index,
+            &[Register::Cr0, Register::Cr3, Register::Cr4, Register::Efer],
+        )?;
+        let value = |i: usize| values[i].as_u64().unwrap_or_default();
+        Ok(PagingState {
+            cr0: value(0),
+            cr3: value(1),
+            cr4: value(2),
+            efer: value(3),
+        })
     }
 }
 
 impl Drop for VirtualProcessor {
     fn drop(&mut self) {
-        let _ = unsafe { WHvDeleteVirtualProcessor(self.partition_handle.0, self.index) };
+        let _ = self.backend.delete_virtual_processor(self.index);
+    }
+}
+
+/// Register access for code that emulates instructions, only the 64-bit and narrower registers
+/// are reachable through it.
+pub trait RegisterFile {
+    fn read_register(&mut self, register: Register) -> Result<u64>;
+
+    fn write_register(&mut self, register: Register, value: u64) -> Result<()>;
+
+    fn read_sub_register(&mut self, sub_register: SubRegister) -> Result<u64> {
+        Ok(sub_register.read(self.read_register(sub_register.parent())?))
+    }
+
+    /// Write `value` with the masking and zero extension of [`SubRegister::merge`].
+    fn write_sub_register(&mut self, sub_register: SubRegister, value: u64) -> Result<()> {
+        let parent = sub_register.parent();
+        let old = match sub_register.width() {
+            32 => 0,
+            _ => self.read_register(parent)?,
+        };
+        self.write_register(parent, sub_register.merge(old, value))
+    }
+}
+
+impl RegisterFile for VirtualProcessor {
+    fn read_register(&mut self, register: Register) -> Result<u64> {
+        Ok(self.get_register(register)?.as_u64().unwrap_or_default())
+    }
+
+    fn write_register(&mut self, register: Register, value: u64) -> Result<()> {
+        self.set_register(register, value.into())
+    }
+}
+
+/// A plain register file, registers never written read as zero.
+impl RegisterFile for HashMap<Register, u64> {
+    fn read_register(&mut self, register: Register) -> Result<u64> {
+        Ok(self.get(&register).copied().unwrap_or_default())
+    }
+
+    fn write_register(&mut self, register: Register, value: u64) -> Result<()> {
+        self.insert(register, value);
+        Ok(())
+    }
+}
+
+/// A part of a general purpose register, addressed by its own name in the instruction set.
+///
+/// The hypervisor only knows the full registers, see [`SubRegister::parent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SubRegister {
+    // The low 32 bits, writes zero the upper half.
+    Eax,
+    Ecx,
+    Edx,
+    Ebx,
+    Esp,
+    Ebp,
+    Esi,
+    Edi,
+    R8d,
+    R9d,
+    R10d,
+    R11d,
+    R12d,
+    R13d,
+    R14d,
+    R15d,
+    Eip,
+    Eflags,
+    // The low 16 bits.
+    Ax,
+    Cx,
+    Dx,
+    Bx,
+    Sp,
+    Bp,
+    Si,
+    Di,
+    R8w,
+    R9w,
+    R10w,
+    R11w,
+    R12w,
+    R13w,
+    R14w,
+    R15w,
+    Ip,
+    Flags,
+    // The low 8 bits.
+    Al,
+    Cl,
+    Dl,
+    Bl,
+    Spl,
+    Bpl,
+    Sil,
+    Dil,
+    R8b,
+    R9b,
+    R10b,
+    R11b,
+    R12b,
+    R13b,
+    R14b,
+    R15b,
+    // Bits 8 to 15.
+    Ah,
+    Ch,
+    Dh,
+    Bh,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubRegisterInfo {
+    pub sub_register: SubRegister,
+    pub name: &'static str,
+    pub parent: Register,
+    /// The first bit of the parent register covered.
+    pub offset: u32,
+    pub width: u32,
+}
+
+impl SubRegisterInfo {
+    const fn new(
+        sub_register: SubRegister,
+        name: &'static str,
+        parent: Register,
+        offset: u32,
+        width: u32,
+    ) -> Self {
+        Self {
+            sub_register,
+            name,
+            parent,
+            offset,
+            width,
+        }
+    }
+}
+
+// In the order of the variants, so they index it.
+#[rustfmt::skip]
+const SUB_REGISTER_INFO: &[SubRegisterInfo] = &[
+    SubRegisterInfo::new(SubRegister::Eax, "eax", Register::Rax, 0, 32),
+    SubRegisterInfo::new(SubRegister::Ecx, "ecx", Register::Rcx, 0, 32),
+    SubRegisterInfo::new(SubRegister::Edx, "edx", Register::Rdx, 0, 32),
+    SubRegisterInfo::new(SubRegister::Ebx, "ebx", Register::Rbx, 0, 32),
+    SubRegisterInfo::new(SubRegister::Esp, "esp", Register::Rsp, 0, 32),
+    SubRegisterInfo::new(SubRegister::Ebp, "ebp", Register::Rbp, 0, 32),
+    SubRegisterInfo::new(SubRegister::Esi, "esi", Register::Rsi, 0, 32),
+    SubRegisterInfo::new(SubRegister::Edi, "edi", Register::Rdi, 0, 32),
+    SubRegisterInfo::new(SubRegister::R8d, "r8d", Register::R8, 0, 32),
+    SubRegisterInfo::new(SubRegister::R9d, "r9d", Register::R9, 0, 32),
+    SubRegisterInfo::new(SubRegister::R10d, "r10d", Register::R10, 0, 32),
+    SubRegisterInfo::new(SubRegister::R11d, "r11d", Register::R11, 0, 32),
+    SubRegisterInfo::new(SubRegister::R12d, "r12d", Register::R12, 0, 32),
+    SubRegisterInfo::new(SubRegister::R13d, "r13d", Register::R13, 0, 32),
+    SubRegisterInfo::new(SubRegister::R14d, "r14d", Register::R14, 0, 32),
+    SubRegisterInfo::new(SubRegister::R15d, "r15d", Register::R15, 0, 32),
+    SubRegisterInfo::new(SubRegister::Eip, "eip", Register::Rip, 0, 32),
+    SubRegisterInfo::new(SubRegister::Eflags, "eflags", Register::Rflags, 0, 32),
+    SubRegisterInfo::new(SubRegister::Ax, "ax", Register::Rax, 0, 16),
+    SubRegisterInfo::new(SubRegister::Cx, "cx", Register::Rcx, 0, 16),
+    SubRegisterInfo::new(SubRegister::Dx, "dx", Register::Rdx, 0, 16),
+    SubRegisterInfo::new(SubRegister::Bx, "bx", Register::Rbx, 0, 16),
+    SubRegisterInfo::new(SubRegister::Sp, "sp", Register::Rsp, 0, 16),
+    SubRegisterInfo::new(SubRegister::Bp, "bp", Register::Rbp, 0, 16),
+    SubRegisterInfo::new(SubRegister::Si, "si", Register::Rsi, 0, 16),
+    SubRegisterInfo::new(SubRegister::Di, "di", Register::Rdi, 0, 16),
+    SubRegisterInfo::new(SubRegister::R8w, "r8w", Register::R8, 0, 16),
+    SubRegisterInfo::new(SubRegister::R9w, "r9w", Register::R9, 0, 16),
+    SubRegisterInfo::new(SubRegister::R10w, "r10w", Register::R10, 0, 16),
+    SubRegisterInfo::new(SubRegister::R11w, "r11w", Register::R11, 0, 16),
+    SubRegisterInfo::new(SubRegister::R12w, "r12w", Register::R12, 0, 16),
+    SubRegisterInfo::new(SubRegister::R13w, "r13w", Register::R13, 0, 16),
+    SubRegisterInfo::new(SubRegister::R14w, "r14w", Register::R14, 0, 16),
+    SubRegisterInfo::new(SubRegister::R15w, "r15w", Register::R15, 0, 16),
+    SubRegisterInfo::new(SubRegister::Ip, "ip", Register::Rip, 0, 16),
+    SubRegisterInfo::new(SubRegister::Flags, "flags", Register::Rflags, 0, 16),
+    SubRegisterInfo::new(SubRegister::Al, "al", Register::Rax, 0, 8),
+    SubRegisterInfo::new(SubRegister::Cl, "cl", Register::Rcx, 0, 8),
+    SubRegisterInfo::new(SubRegister::Dl, "dl", Register::Rdx, 0, 8),
+    SubRegisterInfo::new(SubRegister::Bl, "bl", Register::Rbx, 0, 8),
+    SubRegisterInfo::new(SubRegister::Spl, "spl", Register::Rsp, 0, 8),
+    SubRegisterInfo::new(SubRegister::Bpl, "bpl", Register::Rbp, 0, 8),
+    SubRegisterInfo::new(SubRegister::Sil, "sil", Register::Rsi, 0, 8),
+    SubRegisterInfo::new(SubRegister::Dil, "dil", Register::Rdi, 0, 8),
+    SubRegisterInfo::new(SubRegister::R8b, "r8b", Register::R8, 0, 8),
+    SubRegisterInfo::new(SubRegister::R9b, "r9b", Register::R9, 0, 8),
+    SubRegisterInfo::new(SubRegister::R10b, "r10b", Register::R10, 0, 8),
+    SubRegisterInfo::new(SubRegister::R11b, "r11b", Register::R11, 0, 8),
+    SubRegisterInfo::new(SubRegister::R12b, "r12b", Register::R12, 0, 8),
+    SubRegisterInfo::new(SubRegister::R13b, "r13b", Register::R13, 0, 8),
+    SubRegisterInfo::new(SubRegister::R14b, "r14b", Register::R14, 0, 8),
+    SubRegisterInfo::new(SubRegister::R15b, "r15b", Register::R15, 0, 8),
+    SubRegisterInfo::new(SubRegister::Ah, "ah", Register::Rax, 8, 8),
+    SubRegisterInfo::new(SubRegister::Ch, "ch", Register::Rcx, 8, 8),
+    SubRegisterInfo::new(SubRegister::Dh, "dh", Register::Rdx, 8, 8),
+    SubRegisterInfo::new(SubRegister::Bh, "bh", Register::Rbx, 8, 8),
+];
+
+impl SubRegister {
+    pub fn all() -> impl Iterator<Item = SubRegister> {
+        SUB_REGISTER_INFO.iter().map(|info| info.sub_register)
+    }
+
+    /// Look up a sub-register by its name, ignoring case.
+    pub fn from_name(name: &str) -> Option<SubRegister> {
+        SUB_REGISTER_INFO
+            .iter()
+            .find(|info| info.name.eq_ignore_ascii_case(name))
+            .map(|info| info.sub_register)
+    }
+
+    pub const fn info(&self) -> &'static SubRegisterInfo {
+        &SUB_REGISTER_INFO[*self as usize]
+    }
+
+    pub const fn parent(&self) -> Register {
+        self.info().parent
+    }
+
+    pub const fn offset(&self) -> u32 {
+        self.info().offset
+    }
+
+    pub const fn width(&self) -> u32 {
+        self.info().width
+    }
+
+    pub const fn name(&self) -> &'static str {
+        self.info().name
+    }
+
+    /// The sub-register as an instruction operand.
+    pub const fn operand(&self) -> RegisterOperand {
+        RegisterOperand {
+            register: self.parent(),
+            size: self.width() as usize / 8,
+            high_byte: self.offset() == 8,
+        }
+    }
+
+    /// Extract the sub-register from `value`, the full value of the parent register.
+    pub fn read(&self, value: u64) -> u64 {
+        self.operand().read(value)
+    }
+
+    /// Merge `value` into `old`, the full value of the parent register, the way the processor
+    /// writes it in long mode. 8 and 16-bit writes leave the other bits alone while 32-bit writes
+    /// zero extend, outside of long mode the upper half is undefined so zero extending holds there
+    /// too.
+    pub fn merge(&self, old: u64, value: u64) -> u64 {
+        self.operand().merge(old, value)
     }
 }
 
-// TODO: Support real mode registers and other duplicate registers (i.e. eax is rax)?
 #[repr(i32)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Register {
     // X64 General purpose registers
     Rax = 0x00000000,
@@ -1039,244 +1836,367 @@ pub enum Register {
     PendingDebugException = 0x80000006u32 as i32,
 }
 
+/// What a [`Register`] architecturally is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegisterClass {
+    /// The general purpose registers, including the instruction pointer and flags.
+    Gpr,
+    Segment,
+    /// The descriptor table registers.
+    Table,
+    Control,
+    Debug,
+    /// The x87 and MMX registers.
+    Fp,
+    Xmm,
+    /// Architectural and synthetic MSRs.
+    Msr,
+    Apic,
+    /// Interrupt and event state kept by the hypervisor.
+    Event,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RegisterInfo {
+    pub register: Register,
+    /// The canonical lowercase name, see [`Register::from_name`].
+    pub name: &'static str,
+    pub ty: RegisterType,
+    /// The number of significant bits of the value.
+    pub width: u32,
+    pub class: RegisterClass,
+}
+
+impl RegisterInfo {
+    const fn new(
+        register: Register,
+        name: &'static str,
+        ty: RegisterType,
+        width: u32,
+        class: RegisterClass,
+    ) -> Self {
+        Self {
+            register,
+            name,
+            ty,
+            width,
+            class,
+        }
+    }
+}
+
+// Sorted by the hypervisor's register name, so lookups can binary search.
+#[rustfmt::skip]
+const REGISTER_INFO: &[RegisterInfo] = &[
+    RegisterInfo::new(Register::Rax, "rax", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::Rcx, "rcx", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::Rdx, "rdx", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::Rbx, "rbx", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::Rsp, "rsp", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::Rbp, "rbp", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::Rsi, "rsi", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::Rdi, "rdi", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::R8, "r8", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::R9, "r9", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::R10, "r10", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::R11, "r11", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::R12, "r12", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::R13, "r13", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::R14, "r14", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::R15, "r15", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::Rip, "rip", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::Rflags, "rflags", RegisterType::Reg64, 64, RegisterClass::Gpr),
+    RegisterInfo::new(Register::Es, "es", RegisterType::Segment, 128, RegisterClass::Segment),
+    RegisterInfo::new(Register::Cs, "cs", RegisterType::Segment, 128, RegisterClass::Segment),
+    RegisterInfo::new(Register::Ss, "ss", RegisterType::Segment, 128, RegisterClass::Segment),
+    RegisterInfo::new(Register::Ds, "ds", RegisterType::Segment, 128, RegisterClass::Segment),
+    RegisterInfo::new(Register::Fs, "fs", RegisterType::Segment, 128, RegisterClass::Segment),
+    RegisterInfo::new(Register::Gs, "gs", RegisterType::Segment, 128, RegisterClass::Segment),
+    RegisterInfo::new(Register::Ldtr, "ldtr", RegisterType::Segment, 128, RegisterClass::Segment),
+    RegisterInfo::new(Register::Tr, "tr", RegisterType::Segment, 128, RegisterClass::Segment),
+    RegisterInfo::new(Register::Idtr, "idtr", RegisterType::Table, 128, RegisterClass::Table),
+    RegisterInfo::new(Register::Gdtr, "gdtr", RegisterType::Table, 128, RegisterClass::Table),
+    RegisterInfo::new(Register::Cr0, "cr0", RegisterType::Reg64, 64, RegisterClass::Control),
+    RegisterInfo::new(Register::Cr2, "cr2", RegisterType::Reg64, 64, RegisterClass::Control),
+    RegisterInfo::new(Register::Cr3, "cr3", RegisterType::Reg64, 64, RegisterClass::Control),
+    RegisterInfo::new(Register::Cr4, "cr4", RegisterType::Reg64, 64, RegisterClass::Control),
+    RegisterInfo::new(Register::Cr8, "cr8", RegisterType::Reg64, 64, RegisterClass::Control),
+    RegisterInfo::new(Register::Dr0, "dr0", RegisterType::Reg64, 64, RegisterClass::Debug),
+    RegisterInfo::new(Register::Dr1, "dr1", RegisterType::Reg64, 64, RegisterClass::Debug),
+    RegisterInfo::new(Register::Dr2, "dr2", RegisterType::Reg64, 64, RegisterClass::Debug),
+    RegisterInfo::new(Register::Dr3, "dr3", RegisterType::Reg64, 64, RegisterClass::Debug),
+    RegisterInfo::new(Register::Dr6, "dr6", RegisterType::Reg64, 64, RegisterClass::Debug),
+    RegisterInfo::new(Register::Dr7, "dr7", RegisterType::Reg64, 64, RegisterClass::Debug),
+    RegisterInfo::new(Register::XCr0, "xcr0", RegisterType::Reg64, 64, RegisterClass::Control),
+    RegisterInfo::new(Register::VirtualCr0, "virtual_cr0", RegisterType::Reg64, 64, RegisterClass::Control),
+    RegisterInfo::new(Register::VirtualCr3, "virtual_cr3", RegisterType::Reg64, 64, RegisterClass::Control),
+    RegisterInfo::new(Register::VirtualCr4, "virtual_cr4", RegisterType::Reg64, 64, RegisterClass::Control),
+    RegisterInfo::new(Register::VirtualCr8, "virtual_cr8", RegisterType::Reg64, 64, RegisterClass::Control),
+    RegisterInfo::new(Register::Xmm0, "xmm0", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm1, "xmm1", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm2, "xmm2", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm3, "xmm3", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm4, "xmm4", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm5, "xmm5", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm6, "xmm6", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm7, "xmm7", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm8, "xmm8", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm9, "xmm9", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm10, "xmm10", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm11, "xmm11", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm12, "xmm12", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm13, "xmm13", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm14, "xmm14", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Xmm15, "xmm15", RegisterType::Reg128, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::FpMmx0, "fp_mmx0", RegisterType::Fp, 80, RegisterClass::Fp),
+    RegisterInfo::new(Register::FpMmx1, "fp_mmx1", RegisterType::Fp, 80, RegisterClass::Fp),
+    RegisterInfo::new(Register::FpMmx2, "fp_mmx2", RegisterType::Fp, 80, RegisterClass::Fp),
+    RegisterInfo::new(Register::FpMmx3, "fp_mmx3", RegisterType::Fp, 80, RegisterClass::Fp),
+    RegisterInfo::new(Register::FpMmx4, "fp_mmx4", RegisterType::Fp, 80, RegisterClass::Fp),
+    RegisterInfo::new(Register::FpMmx5, "fp_mmx5", RegisterType::Fp, 80, RegisterClass::Fp),
+    RegisterInfo::new(Register::FpMmx6, "fp_mmx6", RegisterType::Fp, 80, RegisterClass::Fp),
+    RegisterInfo::new(Register::FpMmx7, "fp_mmx7", RegisterType::Fp, 80, RegisterClass::Fp),
+    RegisterInfo::new(Register::FpControlStatus, "fp_control_status", RegisterType::FpControlStatus, 128, RegisterClass::Fp),
+    RegisterInfo::new(Register::XmmControlStatus, "xmm_control_status", RegisterType::XmmControlStatus, 128, RegisterClass::Xmm),
+    RegisterInfo::new(Register::Tsc, "tsc", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Efer, "efer", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::KernelGsBase, "kernel_gs_base", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::ApicBase, "apic_base", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Pat, "pat", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::SysenterCs, "sysenter_cs", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::SysenterEip, "sysenter_eip", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::SysenterEsp, "sysenter_esp", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Star, "star", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Lstar, "lstar", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Cstar, "cstar", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sfmask, "sfmask", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::InitialApicId, "initial_apic_id", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrCap, "msr_mtrr_cap", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrDefType, "msr_mtrr_def_type", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBase0, "msr_mtrr_phys_base0", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBase1, "msr_mtrr_phys_base1", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBase2, "msr_mtrr_phys_base2", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBase3, "msr_mtrr_phys_base3", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBase4, "msr_mtrr_phys_base4", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBase5, "msr_mtrr_phys_base5", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBase6, "msr_mtrr_phys_base6", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBase7, "msr_mtrr_phys_base7", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBase8, "msr_mtrr_phys_base8", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBase9, "msr_mtrr_phys_base9", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBaseA, "msr_mtrr_phys_basea", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBaseB, "msr_mtrr_phys_baseb", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBaseC, "msr_mtrr_phys_basec", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBaseD, "msr_mtrr_phys_based", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBaseE, "msr_mtrr_phys_basee", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysBaseF, "msr_mtrr_phys_basef", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMask0, "msr_mtrr_phys_mask0", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMask1, "msr_mtrr_phys_mask1", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMask2, "msr_mtrr_phys_mask2", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMask3, "msr_mtrr_phys_mask3", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMask4, "msr_mtrr_phys_mask4", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMask5, "msr_mtrr_phys_mask5", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMask6, "msr_mtrr_phys_mask6", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMask7, "msr_mtrr_phys_mask7", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMask8, "msr_mtrr_phys_mask8", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMask9, "msr_mtrr_phys_mask9", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMaskA, "msr_mtrr_phys_maska", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMaskB, "msr_mtrr_phys_maskb", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMaskC, "msr_mtrr_phys_maskc", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMaskD, "msr_mtrr_phys_maskd", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMaskE, "msr_mtrr_phys_maske", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrPhysMaskF, "msr_mtrr_phys_maskf", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrFix64k00000, "msr_mtrr_fix64k00000", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrFix16k80000, "msr_mtrr_fix16k80000", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrFix16kA0000, "msr_mtrr_fix16ka0000", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrFix4kC0000, "msr_mtrr_fix4kc0000", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrFix4kC8000, "msr_mtrr_fix4kc8000", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrFix4kD0000, "msr_mtrr_fix4kd0000", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrFix4kD8000, "msr_mtrr_fix4kd8000", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrFix4kE0000, "msr_mtrr_fix4ke0000", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrFix4kE8000, "msr_mtrr_fix4ke8000", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrFix4kF0000, "msr_mtrr_fix4kf0000", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MsrMtrrFix4kF8000, "msr_mtrr_fix4kf8000", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::TscAux, "tsc_aux", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Bndcfgs, "bndcfgs", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::MCount, "mcount", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::ACount, "acount", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::SpecCtrl, "spec_ctrl", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::PredCmd, "pred_cmd", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::TscVirtualOffset, "tsc_virtual_offset", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::TsxCtrl, "tsx_ctrl", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Xss, "xss", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::UCet, "ucet", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::SCet, "scet", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Ssp, "ssp", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Pl0Ssp, "pl0_ssp", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Pl1Ssp, "pl1_ssp", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Pl2Ssp, "pl2_ssp", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Pl3Ssp, "pl3_ssp", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::InterruptSspTableAddr, "interrupt_ssp_table_addr", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::TscDeadline, "tsc_deadline", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::TscAdjust, "tsc_adjust", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::UmwaitControl, "umwait_control", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Xfd, "xfd", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::XfdErr, "xfd_err", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::ApicId, "apic_id", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicVersion, "apic_version", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicTpr, "apic_tpr", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicPpr, "apic_ppr", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicEoi, "apic_eoi", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicLdr, "apic_ldr", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicSpurious, "apic_spurious", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIsr0, "apic_isr0", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIsr1, "apic_isr1", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIsr2, "apic_isr2", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIsr3, "apic_isr3", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIsr4, "apic_isr4", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIsr5, "apic_isr5", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIsr6, "apic_isr6", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIsr7, "apic_isr7", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicTmr0, "apic_tmr0", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicTmr1, "apic_tmr1", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicTmr2, "apic_tmr2", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicTmr3, "apic_tmr3", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicTmr4, "apic_tmr4", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicTmr5, "apic_tmr5", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicTmr6, "apic_tmr6", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicTmr7, "apic_tmr7", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIrr0, "apic_irr0", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIrr1, "apic_irr1", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIrr2, "apic_irr2", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIrr3, "apic_irr3", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIrr4, "apic_irr4", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIrr5, "apic_irr5", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIrr6, "apic_irr6", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIrr7, "apic_irr7", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicEse, "apic_ese", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicIcr, "apic_icr", RegisterType::Reg64, 64, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicLvtTimer, "apic_lvt_timer", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicLvtThermal, "apic_lvt_thermal", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicLvtPerfmon, "apic_lvt_perfmon", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicLvtLint0, "apic_lvt_lint0", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicLvtLint1, "apic_lvt_lint1", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicLvtError, "apic_lvt_error", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicInitCount, "apic_init_count", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicCurrentCount, "apic_current_count", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicDivide, "apic_divide", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::ApicSelfIpi, "apic_self_ipi", RegisterType::Reg64, 32, RegisterClass::Apic),
+    RegisterInfo::new(Register::Sint0, "sint0", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint1, "sint1", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint2, "sint2", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint3, "sint3", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint4, "sint4", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint5, "sint5", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint6, "sint6", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint7, "sint7", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint8, "sint8", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint9, "sint9", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint10, "sint10", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint11, "sint11", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint12, "sint12", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint13, "sint13", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint14, "sint14", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sint15, "sint15", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Scontrol, "scontrol", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Sversion, "sversion", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Siefp, "siefp", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Simp, "simp", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Eom, "eom", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::VpRuntime, "vp_runtime", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::Hypercall, "hypercall", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::GuestOsId, "guest_os_id", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::VpAssistPage, "vp_assist_page", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::ReferenceTsc, "reference_tsc", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::ReferenceTscSequence, "reference_tsc_sequence", RegisterType::Reg64, 64, RegisterClass::Msr),
+    RegisterInfo::new(Register::PendingInterruption, "pending_interruption", RegisterType::PendingInterruption, 64, RegisterClass::Event),
+    RegisterInfo::new(Register::InterruptState, "interrupt_state", RegisterType::InterruptState, 64, RegisterClass::Event),
+    // Also holds external interrupt events, which share the layout up to the event type.
+    RegisterInfo::new(Register::PendingEvent, "pending_event", RegisterType::ExceptionEvent, 128, RegisterClass::Event),
+    RegisterInfo::new(Register::DeliverabilityNotifications, "deliverability_notifications", RegisterType::DeliverabilityNotifications, 64, RegisterClass::Event),
+    RegisterInfo::new(Register::InternalActivityState, "internal_activity_state", RegisterType::Reg64, 64, RegisterClass::Event),
+    RegisterInfo::new(Register::PendingDebugException, "pending_debug_exception", RegisterType::Reg64, 64, RegisterClass::Event),
+];
+
 impl Register {
-    // TODO: This is completely wrong...
-    pub const fn ty(&self) -> RegisterType {
-        match self {
-            Register::Rax
-            | Register::Rcx
-            | Register::Rdx
-            | Register::Rbx
-            | Register::Rsp
-            | Register::Rbp
-            | Register::Rsi
-            | Register::Rdi
-            | Register::R8
-            | Register::R9
-            | Register::R10
-            | Register::R11
-            | Register::R12
-            | Register::R13
-            | Register::R14
-            | Register::R15
-            | Register::Rip
-            | Register::Rflags => RegisterType::Reg64,
-            Register::Es
-            | Register::Cs
-            | Register::Ss
-            | Register::Ds
-            | Register::Fs
-            | Register::Gs
-            | Register::Ldtr
-            | Register::Tr => RegisterType::Segment,
-            Register::Idtr | Register::Gdtr => RegisterType::Table,
-            Register::Cr0 | Register::Cr2 | Register::Cr3 | Register::Cr4 | Register::Cr8 => {
-                RegisterType::Reg64
+    /// Every register, in the order of the hypervisor's register names.
+    pub fn all() -> impl Iterator<Item = Register> {
+        REGISTER_INFO.iter().map(|info| info.register)
+    }
+
+    pub const fn from_raw(value: u32) -> Option<Register> {
+        match Self::find(value) {
+            Some(info) => Some(info.register),
+            None => None,
+        }
+    }
+
+    /// Look up a register by its canonical name, ignoring case.
+    pub fn from_name(name: &str) -> Option<Register> {
+        REGISTER_INFO
+            .iter()
+            .find(|info| info.name.eq_ignore_ascii_case(name))
+            .map(|info| info.register)
+    }
+
+    const fn find(value: u32) -> Option<&'static RegisterInfo> {
+        let (mut low, mut high) = (0, REGISTER_INFO.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            let raw = REGISTER_INFO[mid].register as u32;
+            if raw == value {
+                return Some(&REGISTER_INFO[mid]);
+            } else if raw < value {
+                low = mid + 1;
+            } else {
+                high = mid;
             }
-            Register::Dr0
-            | Register::Dr1
-            | Register::Dr2
-            | Register::Dr3
-            | Register::Dr6
-            | Register::Dr7 => RegisterType::Reg64,
-            Register::XCr0
-            | Register::VirtualCr0
-            | Register::VirtualCr3
-            | Register::VirtualCr4
-            | Register::VirtualCr8 => RegisterType::Reg64,
-            Register::Xmm0
-            | Register::Xmm1
-            | Register::Xmm2
-            | Register::Xmm3
-            | Register::Xmm4
-            | Register::Xmm5
-            | Register::Xmm6
-            | Register::Xmm7
-            | Register::Xmm8
-            | Register::Xmm9
-            | Register::Xmm10
-            | Register::Xmm11
-            | Register::Xmm12
-            | Register::Xmm13
-            | Register::Xmm14
-            | Register::Xmm15 => RegisterType::Reg128,
-            Register::FpMmx0
-            | Register::FpMmx1
-            | Register::FpMmx2
-            | Register::FpMmx3
-            | Register::FpMmx4
-            | Register::FpMmx5
-            | Register::FpMmx6
-            | Register::FpMmx7 => RegisterType::Fp, // TODO: Might not be right, this is also aliased for mmx.
-            Register::FpControlStatus => RegisterType::FpControlStatus,
-            Register::XmmControlStatus => RegisterType::XmmControlStatus,
-            Register::Tsc
-            | Register::Efer
-            | Register::KernelGsBase
-            | Register::ApicBase
-            | Register::Pat
-            | Register::SysenterCs
-            | Register::SysenterEip
-            | Register::SysenterEsp
-            | Register::Star
-            | Register::Lstar
-            | Register::Cstar
-            | Register::Sfmask
-            | Register::InitialApicId
-            | Register::MsrMtrrCap
-            | Register::MsrMtrrDefType
-            | Register::MsrMtrrPhysBase0
-            | Register::MsrMtrrPhysBase1
-            | Register::MsrMtrrPhysBase2
-            | Register::MsrMtrrPhysBase3
-            | Register::MsrMtrrPhysBase4
-            | Register::MsrMtrrPhysBase5
-            | Register::MsrMtrrPhysBase6
-            | Register::MsrMtrrPhysBase7
-            | Register::MsrMtrrPhysBase8
-            | Register::MsrMtrrPhysBase9
-            | Register::MsrMtrrPhysBaseA
-            | Register::MsrMtrrPhysBaseB
-            | Register::MsrMtrrPhysBaseC
-            | Register::MsrMtrrPhysBaseD
-            | Register::MsrMtrrPhysBaseE
-            | Register::MsrMtrrPhysBaseF
-            | Register::MsrMtrrPhysMask0
-            | Register::MsrMtrrPhysMask1
-            | Register::MsrMtrrPhysMask2
-            | Register::MsrMtrrPhysMask3
-            | Register::MsrMtrrPhysMask4
-            | Register::MsrMtrrPhysMask5
-            | Register::MsrMtrrPhysMask6
-            | Register::MsrMtrrPhysMask7
-            | Register::MsrMtrrPhysMask8
-            | Register::MsrMtrrPhysMask9
-            | Register::MsrMtrrPhysMaskA
-            | Register::MsrMtrrPhysMaskB
-            | Register::MsrMtrrPhysMaskC
-            | Register::MsrMtrrPhysMaskD
-            | Register::MsrMtrrPhysMaskE
-            | Register::MsrMtrrPhysMaskF
-            | Register::MsrMtrrFix64k00000
-            | Register::MsrMtrrFix16k80000
-            | Register::MsrMtrrFix16kA0000
-            | Register::MsrMtrrFix4kC0000
-            | Register::MsrMtrrFix4kC8000
-            | Register::MsrMtrrFix4kD0000
-            | Register::MsrMtrrFix4kD8000
-            | Register::MsrMtrrFix4kE0000
-            | Register::MsrMtrrFix4kE8000
-            | Register::MsrMtrrFix4kF0000
-            | Register::MsrMtrrFix4kF8000
-            | Register::TscAux
-            | Register::Bndcfgs
-            | Register::MCount
-            | Register::ACount
-            | Register::SpecCtrl
-            | Register::PredCmd
-            | Register::TscVirtualOffset
-            | Register::TsxCtrl
-            | Register::Xss
-            | Register::UCet
-            | Register::SCet
-            | Register::Ssp
-            | Register::Pl0Ssp
-            | Register::Pl1Ssp
-            | Register::Pl2Ssp
-            | Register::Pl3Ssp
-            | Register::InterruptSspTableAddr
-            | Register::TscDeadline
-            | Register::TscAdjust
-            | Register::UmwaitControl
-            | Register::Xfd
-            | Register::XfdErr => RegisterType::Reg64,
-            Register::ApicId
-            | Register::ApicVersion
-            | Register::ApicTpr
-            | Register::ApicPpr
-            | Register::ApicEoi
-            | Register::ApicLdr
-            | Register::ApicSpurious
-            | Register::ApicIsr0
-            | Register::ApicIsr1
-            | Register::ApicIsr2
-            | Register::ApicIsr3
-            | Register::ApicIsr4
-            | Register::ApicIsr5
-            | Register::ApicIsr6
-            | Register::ApicIsr7
-            | Register::ApicTmr0
-            | Register::ApicTmr1
-            | Register::ApicTmr2
-            | Register::ApicTmr3
-            | Register::ApicTmr4
-            | Register::ApicTmr5
-            | Register::ApicTmr6
-            | Register::ApicTmr7
-            | Register::ApicIrr0
-            | Register::ApicIrr1
-            | Register::ApicIrr2
-            | Register::ApicIrr3
-            | Register::ApicIrr4
-            | Register::ApicIrr5
-            | Register::ApicIrr6
-            | Register::ApicIrr7
-            | Register::ApicEse
-            | Register::ApicIcr
-            | Register::ApicLvtTimer
-            | Register::ApicLvtThermal
-            | Register::ApicLvtPerfmon
-            | Register::ApicLvtLint0
-            | Register::ApicLvtLint1
-            | Register::ApicLvtError
-            | Register::ApicInitCount
-            | Register::ApicCurrentCount
-            | Register::ApicDivide
-            | Register::ApicSelfIpi => RegisterType::Reg64,
-            Register::Sint0
-            | Register::Sint1
-            | Register::Sint2
-            | Register::Sint3
-            | Register::Sint4
-            | Register::Sint5
-            | Register::Sint6
-            | Register::Sint7
-            | Register::Sint8
-            | Register::Sint9
-            | Register::Sint10
-            | Register::Sint11
-            | Register::Sint12
-            | Register::Sint13
-            | Register::Sint14
-            | Register::Sint15
-            | Register::Scontrol
-            | Register::Sversion
-            | Register::Siefp
-            | Register::Simp
-            | Register::Eom
-            | Register::VpRuntime
-            | Register::Hypercall
-            | Register::GuestOsId
-            | Register::VpAssistPage
-            | Register::ReferenceTsc
-            | Register::ReferenceTscSequence
-            | Register::PendingInterruption
-            | Register::InterruptState
-            | Register::PendingEvent
-            | Register::DeliverabilityNotifications
-            | Register::InternalActivityState
-            | Register::PendingDebugException => todo!(),
+        }
+        None
+    }
+
+    pub const fn info(&self) -> &'static RegisterInfo {
+        match Self::find(*self as u32) {
+            Some(info) => info,
+            None => panic!("register missing from the metadata table"),
+        }
+    }
+
+    pub const fn ty(&self) -> RegisterType {
+        self.info().ty
+    }
+
+    pub const fn width(&self) -> u32 {
+        self.info().width
+    }
+
+    pub const fn class(&self) -> RegisterClass {
+        self.info().class
+    }
+
+    pub const fn name(&self) -> &'static str {
+        self.info().name
+    }
+}
+
+#[cfg(windows)]
+impl From<WHV_REGISTER_NAME> for Register {
+    fn from(value: WHV_REGISTER_NAME) -> Self {
+        // TODO: Can we enforce this differently?
+        match Self::from_raw(value.0 as u32) {
+            Some(register) => register,
+            None => unreachable!(),
         }
     }
 }
 
+#[cfg(windows)]
 impl From<Register> for WHV_REGISTER_NAME {
     fn from(value: Register) -> Self {
         Self(value as _)
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum RegisterType {
     Reg128,
     Reg64,
@@ -1315,12 +2235,49 @@ pub enum RegisterVal {
 }
 
 impl RegisterVal {
+    /// The zero-extended value of an integer register, `None` for the structured register types.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Self::Reg64(v) => Some(v),
+            Self::Reg32(v) => Some(v.into()),
+            Self::Reg16(v) => Some(v.into()),
+            Self::Reg8(v) => Some(v.into()),
+            _ => None,
+        }
+    }
+
+    /// The zeroed value for a register of type `ty`.
+    pub fn zeroed(ty: RegisterType) -> Self {
+        match ty {
+            RegisterType::Reg128 => Self::Reg128(0),
+            RegisterType::Reg64 => Self::Reg64(0),
+            RegisterType::Reg32 => Self::Reg32(0),
+            RegisterType::Reg16 => Self::Reg16(0),
+            RegisterType::Reg8 => Self::Reg8(0),
+            RegisterType::Fp => Self::Fp(Default::default()),
+            RegisterType::FpControlStatus => Self::FpControlStatus(Default::default()),
+            RegisterType::XmmControlStatus => Self::XmmControlStatus(Default::default()),
+            RegisterType::Segment => Self::Segment(Default::default()),
+            RegisterType::Table => Self::Table(Default::default()),
+            RegisterType::InterruptState => Self::InterruptState(Default::default()),
+            RegisterType::PendingInterruption => Self::PendingInterruption(Default::default()),
+            RegisterType::DeliverabilityNotifications => {
+                Self::DeliverabilityNotifications(Default::default())
+            }
+            RegisterType::ExceptionEvent => Self::ExceptionEvent(Default::default()),
+            RegisterType::ExtIntEvent => Self::ExtIntEvent(Default::default()),
+        }
+    }
+
+    #[cfg(windows)]
     pub fn from_union(ty: RegisterType, raw_val: WHV_REGISTER_VALUE) -> Self {
         // SAFETY: The code corresponds to the union variant.
         unsafe {
             match ty {
                 // TODO: Get rid of this transmute.
-                RegisterType::Reg128 => Self::Reg128(std::mem::transmute(raw_val.Reg128)),
+                RegisterType::Reg128 => {
+                    Self::Reg128(std::mem::transmute::<WHV_UINT128, u128>(raw_val.Reg128))
+                }
                 RegisterType::Reg64 => Self::Reg64(raw_val.Reg64),
                 RegisterType::Reg32 => Self::Reg32(raw_val.Reg32),
                 RegisterType::Reg16 => Self::Reg16(raw_val.Reg16),
@@ -1348,12 +2305,13 @@ impl RegisterVal {
     }
 }
 
+#[cfg(windows)]
 impl From<RegisterVal> for WHV_REGISTER_VALUE {
     fn from(value: RegisterVal) -> Self {
         match value {
             RegisterVal::Reg128(v) => WHV_REGISTER_VALUE {
                 // TODO: This needs to be redone.
-                Reg128: unsafe { std::mem::transmute(v) },
+                Reg128: unsafe { std::mem::transmute::<u128, WHV_UINT128>(v) },
             },
             RegisterVal::Reg64(v) => WHV_REGISTER_VALUE { Reg64: v },
             RegisterVal::Reg32(v) => WHV_REGISTER_VALUE { Reg32: v },
@@ -1416,3 +2374,405 @@ impl From<u8> for RegisterVal {
         Self::Reg8(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[cfg(windows)]
+    use windows::Win32::System::Hypervisor::{
+        WHV_HYPERCALL_CONTEXT, WHV_MEMORY_ACCESS_CONTEXT, WHV_REGISTER_NAME,
+        WHV_RUN_VP_CANCELED_CONTEXT, WHV_RUN_VP_EXIT_CONTEXT, WHV_RUN_VP_EXIT_CONTEXT_0,
+        WHV_RUN_VP_EXIT_REASON, WHV_SYNIC_SINT_DELIVERABLE_CONTEXT, WHV_VP_EXCEPTION_CONTEXT,
+        WHV_X64_APIC_EOI_CONTEXT, WHV_X64_APIC_INIT_SIPI_CONTEXT, WHV_X64_APIC_SMI_CONTEXT,
+        WHV_X64_APIC_WRITE_CONTEXT, WHV_X64_APIC_WRITE_TYPE, WHV_X64_CPUID_ACCESS_CONTEXT,
+        WHV_X64_INTERRUPTION_DELIVERABLE_CONTEXT, WHV_X64_IO_PORT_ACCESS_CONTEXT,
+        WHV_X64_MSR_ACCESS_CONTEXT, WHV_X64_PENDING_INTERRUPTION_TYPE, WHV_X64_RDTSC_CONTEXT,
+        WHV_X64_UNSUPPORTED_FEATURE_CODE, WHV_X64_UNSUPPORTED_FEATURE_CONTEXT,
+    };
+
+    #[cfg(windows)]
+    use super::{
+        ApicWriteType, PendingInterruptionType, RunExitReason, UnsupportedFeatureCode,
+        VpCancelReason,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    use crate::{
+        backend::MockBackend,
+        partition::{PartitionBuilder, PartitionProperty},
+    };
+
+    use super::{
+        ExitContext, Register, RegisterClass, RegisterFile, RegisterType, RegisterVal,
+        RunExitContext, RunPhase, SubRegister, VmExit, REGISTER_INFO,
+    };
+
+    #[test]
+    fn cancel_race() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(1))
+            .unwrap()
+            .setup()
+            .unwrap();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+        let handle = vcpu.cancel_handle();
+        let halt = RunExitContext {
+            context: ExitContext::default(),
+            exit: VmExit::Halt,
+        };
+        mock.push_exit(0, halt);
+        mock.push_exit(0, halt);
+
+        // The handle publishes the cancellation, the run takes it before entering the guest and
+        // only then does the handle see the run in the guest.
+        handle.state.pending.store(true, Ordering::SeqCst);
+        assert!(matches!(vcpu.run().unwrap().exit, VmExit::Canceled(_)));
+        vcpu.cancel.set_phase(RunPhase::InGuest);
+        handle.deliver().unwrap();
+        vcpu.cancel.set_phase(RunPhase::Idle);
+        assert_eq!(vcpu.run().unwrap().exit, VmExit::Halt);
+
+        // The handle sees the run in the guest first, the hypervisor delivers the cancellation.
+        vcpu.cancel.set_phase(RunPhase::InGuest);
+        handle.cancel().unwrap();
+        assert!(!handle.state.pending.load(Ordering::SeqCst));
+        assert!(matches!(vcpu.run().unwrap().exit, VmExit::Canceled(_)));
+        assert_eq!(vcpu.run().unwrap().exit, VmExit::Halt);
+        assert_eq!(mock.pending_exits(0), 0);
+    }
+
+    #[test]
+    fn watchdog_reuse() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(1))
+            .unwrap()
+            .setup()
+            .unwrap();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+        let halt = RunExitContext {
+            context: ExitContext::default(),
+            exit: VmExit::Halt,
+        };
+        mock.push_exit(0, halt);
+        mock.push_exit(0, halt);
+
+        // Every budgeted run re-arms the same watchdog thread.
+        vcpu.run_for(Duration::from_secs(60)).unwrap();
+        let watchdog = vcpu.watchdog.clone().unwrap();
+        let thread = watchdog.thread.as_ref().unwrap().thread().id();
+        vcpu.run_for(Duration::from_secs(60)).unwrap();
+        let again = vcpu.watchdog.as_ref().unwrap();
+        assert!(Arc::ptr_eq(&watchdog, again));
+        assert_eq!(again.thread.as_ref().unwrap().thread().id(), thread);
+        assert_eq!(watchdog.state().deadline, None);
+    }
+
+    #[test]
+    fn stale_cancel() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(1))
+            .unwrap()
+            .setup()
+            .unwrap();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+        let handle = vcpu.cancel_handle();
+        let halt = RunExitContext {
+            context: ExitContext::default(),
+            exit: VmExit::Halt,
+        };
+        mock.push_exit(0, halt);
+
+        // The cancellation reaches the hypervisor after the guest exited on its own, the next run
+        // must not return the canceled exit left behind.
+        vcpu.cancel.set_phase(RunPhase::InGuest);
+        handle.cancel().unwrap();
+        assert!(vcpu.cancel.finish(&VmExit::Halt));
+        assert_eq!(vcpu.run().unwrap().exit, VmExit::Halt);
+        assert_eq!(mock.pending_exits(0), 0);
+    }
+
+    #[test]
+    fn register_table() {
+        assert!(REGISTER_INFO
+            .windows(2)
+            .all(|pair| (pair[0].register as u32) < (pair[1].register as u32)));
+        assert_eq!(Register::all().count(), 226);
+
+        for register in Register::all() {
+            let info = register.info();
+            assert_eq!(info.register, register);
+            assert_eq!(Register::from_raw(register as u32), Some(register));
+            assert_eq!(Register::from_name(info.name), Some(register));
+            assert_eq!(
+                Register::from_name(&info.name.to_uppercase()),
+                Some(register)
+            );
+        }
+        assert_eq!(Register::from_name("cr3"), Some(Register::Cr3));
+        assert_eq!(Register::from_name("xcr0"), Some(Register::XCr0));
+        assert_eq!(Register::from_name("cr9"), None);
+        assert_eq!(Register::from_name("eax"), None);
+        assert_eq!(Register::from_raw(0x2c), None);
+
+        assert_eq!(Register::Rax.width(), 64);
+        assert_eq!(Register::FpMmx0.width(), 80);
+        assert_eq!(Register::ApicTpr.width(), 32);
+        assert_eq!(Register::ApicIcr.width(), 64);
+        assert_eq!(Register::Gdtr.class(), RegisterClass::Table);
+        assert_eq!(Register::Sint0.class(), RegisterClass::Msr);
+        assert_eq!(Register::PendingEvent.ty(), RegisterType::ExceptionEvent);
+    }
+
+    #[test]
+    fn register_value_shape() {
+        for register in Register::all() {
+            let value = RegisterVal::zeroed(register.ty());
+            let shaped = match register.class() {
+                RegisterClass::Gpr
+                | RegisterClass::Control
+                | RegisterClass::Debug
+                | RegisterClass::Msr
+                | RegisterClass::Apic => matches!(value, RegisterVal::Reg64(_)),
+                RegisterClass::Segment => matches!(value, RegisterVal::Segment(_)),
+                RegisterClass::Table => matches!(value, RegisterVal::Table(_)),
+                RegisterClass::Fp => matches!(
+                    (register, value),
+                    (Register::FpControlStatus, RegisterVal::FpControlStatus(_))
+                        | (_, RegisterVal::Fp(_))
+                ),
+                RegisterClass::Xmm => matches!(
+                    (register, value),
+                    (Register::XmmControlStatus, RegisterVal::XmmControlStatus(_))
+                        | (_, RegisterVal::Reg128(_))
+                ),
+                RegisterClass::Event => matches!(
+                    (register, value),
+                    (
+                        Register::PendingInterruption,
+                        RegisterVal::PendingInterruption(_)
+                    ) | (Register::InterruptState, RegisterVal::InterruptState(_))
+                        | (Register::PendingEvent, RegisterVal::ExceptionEvent(_))
+                        | (
+                            Register::DeliverabilityNotifications,
+                            RegisterVal::DeliverabilityNotifications(_)
+                        )
+                        | (Register::InternalActivityState, RegisterVal::Reg64(_))
+                        | (Register::PendingDebugException, RegisterVal::Reg64(_))
+                ),
+            };
+            assert!(shaped, "{register:?} has the wrong shape {value:?}");
+        }
+    }
+
+    #[test]
+    fn sub_registers() {
+        assert_eq!(SubRegister::all().count(), 56);
+        for sub_register in SubRegister::all() {
+            assert_eq!(sub_register.info().sub_register, sub_register);
+            assert_eq!(
+                SubRegister::from_name(sub_register.name()),
+                Some(sub_register)
+            );
+            assert_eq!(sub_register.parent().class(), RegisterClass::Gpr);
+        }
+        assert_eq!(SubRegister::from_name("R8D"), Some(SubRegister::R8d));
+        assert_eq!(SubRegister::Sil.parent(), Register::Rsi);
+        assert_eq!(SubRegister::Ip.parent(), Register::Rip);
+
+        let mut registers = HashMap::from([(Register::Rax, 0x1122_3344_5566_7788)]);
+        assert_eq!(
+            registers.read_sub_register(SubRegister::Eax).unwrap(),
+            0x5566_7788
+        );
+        assert_eq!(
+            registers.read_sub_register(SubRegister::Ax).unwrap(),
+            0x7788
+        );
+        assert_eq!(registers.read_sub_register(SubRegister::Al).unwrap(), 0x88);
+        assert_eq!(registers.read_sub_register(SubRegister::Ah).unwrap(), 0x77);
+
+        let mut write = |sub_register, value| {
+            registers.write_sub_register(sub_register, value).unwrap();
+            registers[&sub_register.parent()]
+        };
+        assert_eq!(write(SubRegister::Al, 0x1ff), 0x1122_3344_5566_77ff);
+        assert_eq!(write(SubRegister::Ah, 0), 0x1122_3344_5566_00ff);
+        assert_eq!(write(SubRegister::Ax, 0xabcd), 0x1122_3344_5566_abcd);
+        assert_eq!(write(SubRegister::Eax, 0xffff_ffff_0000_0001), 0x1);
+        assert_eq!(write(SubRegister::R8b, 0x42), 0x42);
+        assert_eq!(write(SubRegister::R8w, 0x1234), 0x1234);
+        assert_eq!(write(SubRegister::R8d, 0x8000_0000), 0x8000_0000);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn register_names() {
+        for register in Register::all() {
+            assert_eq!(Register::from(WHV_REGISTER_NAME::from(register)), register);
+        }
+    }
+
+    #[cfg(windows)]
+    fn convert(reason: RunExitReason, ext: impl FnOnce(&mut WHV_RUN_VP_EXIT_CONTEXT_0)) -> VmExit {
+        let mut raw = WHV_RUN_VP_EXIT_CONTEXT {
+            ExitReason: WHV_RUN_VP_EXIT_REASON(reason as i32),
+            ..Default::default()
+        };
+        raw.VpContext.Rip = 0xfff0;
+        ext(&mut raw.Anonymous);
+
+        let exit = RunExitContext::from(raw);
+        assert_eq!(exit.context.rip, 0xfff0);
+        assert_eq!(exit.exit_reason(), reason);
+        exit.exit
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn exit_without_context() {
+        assert_eq!(convert(RunExitReason::None, |_| {}), VmExit::None);
+        assert_eq!(
+            convert(RunExitReason::UnrecoverableException, |_| {}),
+            VmExit::UnrecoverableException
+        );
+        assert_eq!(
+            convert(RunExitReason::InvalidVpRegisterValue, |_| {}),
+            VmExit::InvalidVpRegisterValue
+        );
+        assert_eq!(convert(RunExitReason::X64Halt, |_| {}), VmExit::Halt);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn exit_with_context() {
+        let exit = convert(RunExitReason::MemoryAccess, |ext| {
+            ext.MemoryAccess = WHV_MEMORY_ACCESS_CONTEXT {
+                Gpa: 0xfee0_0000,
+                ..Default::default()
+            }
+        });
+        assert!(matches!(exit, VmExit::MemoryAccess(access) if access.gpa == 0xfee0_0000));
+
+        let exit = convert(RunExitReason::X64IoPortAccess, |ext| {
+            ext.IoPortAccess = WHV_X64_IO_PORT_ACCESS_CONTEXT {
+                PortNumber: 0x3f8,
+                ..Default::default()
+            }
+        });
+        assert!(matches!(exit, VmExit::IoPortAccess(access) if access.port_number == 0x3f8));
+
+        let exit = convert(RunExitReason::UnsupportedFeature, |ext| {
+            ext.UnsupportedFeature = WHV_X64_UNSUPPORTED_FEATURE_CONTEXT {
+                FeatureCode: WHV_X64_UNSUPPORTED_FEATURE_CODE(2),
+                ..Default::default()
+            }
+        });
+        assert!(matches!(
+            exit,
+            VmExit::UnsupportedFeature(feature)
+                if feature.feature_code == UnsupportedFeatureCode::TaskSwitchTss
+        ));
+
+        let exit = convert(RunExitReason::X64InterruptWindow, |ext| {
+            ext.InterruptWindow = WHV_X64_INTERRUPTION_DELIVERABLE_CONTEXT {
+                DeliverableType: WHV_X64_PENDING_INTERRUPTION_TYPE(2),
+            }
+        });
+        assert!(matches!(
+            exit,
+            VmExit::InterruptWindow(window)
+                if window.deliverable_type == PendingInterruptionType::Nmi
+        ));
+
+        let exit = convert(RunExitReason::X64ApicEoi, |ext| {
+            ext.ApicEoi = WHV_X64_APIC_EOI_CONTEXT {
+                InterruptVector: 0x30,
+            }
+        });
+        assert!(matches!(exit, VmExit::ApicEoi(eoi) if eoi.interrupt_vec == 0x30));
+
+        let exit = convert(RunExitReason::SynicSintDeliverable, |ext| {
+            ext.SynicSintDeliverable = WHV_SYNIC_SINT_DELIVERABLE_CONTEXT {
+                DeliverableSints: 0b101,
+                ..Default::default()
+            }
+        });
+        assert!(matches!(
+            exit,
+            VmExit::SynicSintDeliverable(sint) if sint.deliverable_sints == 0b101
+        ));
+
+        let exit = convert(RunExitReason::X64MsrAccess, |ext| {
+            ext.MsrAccess = WHV_X64_MSR_ACCESS_CONTEXT {
+                MsrNumber: 0x1b,
+                ..Default::default()
+            }
+        });
+        assert!(matches!(exit, VmExit::MsrAccess(access) if access.msr_number == 0x1b));
+
+        let exit = convert(RunExitReason::X64Cpuid, |ext| {
+            ext.CpuidAccess = WHV_X64_CPUID_ACCESS_CONTEXT {
+                Rax: 0x4000_0000,
+                ..Default::default()
+            }
+        });
+        assert!(matches!(exit, VmExit::Cpuid(access) if access.rax == 0x4000_0000));
+
+        let exit = convert(RunExitReason::Exception, |ext| {
+            ext.VpException = WHV_VP_EXCEPTION_CONTEXT {
+                ExceptionType: 0xe,
+                ..Default::default()
+            }
+        });
+        assert!(matches!(exit, VmExit::Exception(exception) if exception.exception_type == 0xe));
+
+        let exit = convert(RunExitReason::X64Rdtsc, |ext| {
+            ext.ReadTsc = WHV_X64_RDTSC_CONTEXT {
+                Tsc: 1234,
+                ..Default::default()
+            }
+        });
+        assert!(matches!(exit, VmExit::Rdtsc(rdtsc) if rdtsc.tsc == 1234));
+
+        let exit = convert(RunExitReason::X64ApicSmiTrap, |ext| {
+            ext.ApicSmi = WHV_X64_APIC_SMI_CONTEXT { ApicIcr: 0x200 }
+        });
+        assert!(matches!(exit, VmExit::ApicSmi(smi) if smi.apic_icr == 0x200));
+
+        let exit = convert(RunExitReason::Hypercall, |ext| {
+            ext.Hypercall = WHV_HYPERCALL_CONTEXT {
+                Rcx: 0x1,
+                ..Default::default()
+            }
+        });
+        assert!(matches!(exit, VmExit::Hypercall(hypercall) if hypercall.rcx == 0x1));
+
+        let exit = convert(RunExitReason::X64ApicInitSipiTrap, |ext| {
+            ext.ApicInitSipi = WHV_X64_APIC_INIT_SIPI_CONTEXT { ApicIcr: 0x4500 }
+        });
+        assert!(matches!(exit, VmExit::ApicInitSipi(init_sipi) if init_sipi.apic_icr == 0x4500));
+
+        let exit = convert(RunExitReason::X64ApicWriteTrap, |ext| {
+            ext.ApicWrite = WHV_X64_APIC_WRITE_CONTEXT {
+                Type: WHV_X64_APIC_WRITE_TYPE(0xf0),
+                WriteValue: 0x1ff,
+                ..Default::default()
+            }
+        });
+        assert!(matches!(
+            exit,
+            VmExit::ApicWrite(write) if write.ty == ApicWriteType::Svr && write.write_value == 0x1ff
+        ));
+
+        let exit = convert(RunExitReason::Canceled, |ext| {
+            ext.CancelReason = WHV_RUN_VP_CANCELED_CONTEXT::default()
+        });
+        assert_eq!(exit, VmExit::Canceled(VpCancelReason::User));
+    }
+}
@@ -0,0 +1,447 @@
+use crate::{
+    fields::FpRegister,
+    flags::X64SegmentRegisterAttributes,
+    processor::{
+        Register, RegisterVal, SegmentRegister, TableRegister, X64FpControlStatusRegister,
+        X64XmmControlStatusRegister,
+    },
+};
+
+// Register states below default to the power-up state from the Intel SDM (Vol. 3, Table 9-1).
+
+/// The general purpose registers, instruction pointer and flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct X64Registers {
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbx: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+impl X64Registers {
+    pub const REGISTERS: [Register; 18] = [
+        Register::Rax,
+        Register::Rcx,
+        Register::Rdx,
+        Register::Rbx,
+        Register::Rsp,
+        Register::Rbp,
+        Register::Rsi,
+        Register::Rdi,
+        Register::R8,
+        Register::R9,
+        Register::R10,
+        Register::R11,
+        Register::R12,
+        Register::R13,
+        Register::R14,
+        Register::R15,
+        Register::Rip,
+        Register::Rflags,
+    ];
+
+    /// Build from the values of [`X64Registers::REGISTERS`], in order.
+    pub fn from_values(values: &[RegisterVal]) -> Self {
+        let value = |i: usize| reg64(values, i);
+        Self {
+            rax: value(0),
+            rcx: value(1),
+            rdx: value(2),
+            rbx: value(3),
+            rsp: value(4),
+            rbp: value(5),
+            rsi: value(6),
+            rdi: value(7),
+            r8: value(8),
+            r9: value(9),
+            r10: value(10),
+            r11: value(11),
+            r12: value(12),
+            r13: value(13),
+            r14: value(14),
+            r15: value(15),
+            rip: value(16),
+            rflags: value(17),
+        }
+    }
+
+    /// The values of [`X64Registers::REGISTERS`], in order.
+    pub fn to_values(&self) -> Vec<(Register, RegisterVal)> {
+        let values = [
+            self.rax,
+            self.rcx,
+            self.rdx,
+            self.rbx,
+            self.rsp,
+            self.rbp,
+            self.rsi,
+            self.rdi,
+            self.r8,
+            self.r9,
+            self.r10,
+            self.r11,
+            self.r12,
+            self.r13,
+            self.r14,
+            self.r15,
+            self.rip,
+            self.rflags,
+        ];
+        Self::REGISTERS
+            .into_iter()
+            .zip(values.map(RegisterVal::Reg64))
+            .collect()
+    }
+}
+
+impl Default for X64Registers {
+    fn default() -> Self {
+        Self {
+            rax: 0,
+            rcx: 0,
+            // Family, model and stepping of the processor, we claim a family 6 part.
+            rdx: 0x600,
+            rbx: 0,
+            rsp: 0,
+            rbp: 0,
+            rsi: 0,
+            rdi: 0,
+            r8: 0,
+            r9: 0,
+            r10: 0,
+            r11: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            rip: 0xfff0,
+            rflags: 0x2,
+        }
+    }
+}
+
+/// The segment, descriptor table and control registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct X64SpecialRegisters {
+    pub es: SegmentRegister,
+    pub cs: SegmentRegister,
+    pub ss: SegmentRegister,
+    pub ds: SegmentRegister,
+    pub fs: SegmentRegister,
+    pub gs: SegmentRegister,
+    pub ldtr: SegmentRegister,
+    pub tr: SegmentRegister,
+    pub idtr: TableRegister,
+    pub gdtr: TableRegister,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub cr8: u64,
+    pub efer: u64,
+    pub apic_base: u64,
+}
+
+impl X64SpecialRegisters {
+    pub const REGISTERS: [Register; 17] = [
+        Register::Es,
+        Register::Cs,
+        Register::Ss,
+        Register::Ds,
+        Register::Fs,
+        Register::Gs,
+        Register::Ldtr,
+        Register::Tr,
+        Register::Idtr,
+        Register::Gdtr,
+        Register::Cr0,
+        Register::Cr2,
+        Register::Cr3,
+        Register::Cr4,
+        Register::Cr8,
+        Register::Efer,
+        Register::ApicBase,
+    ];
+
+    /// Build from the values of [`X64SpecialRegisters::REGISTERS`], in order.
+    pub fn from_values(values: &[RegisterVal]) -> Self {
+        let segment = |i: usize| match values.get(i) {
+            Some(RegisterVal::Segment(v)) => *v,
+            _ => Default::default(),
+        };
+        let table = |i: usize| match values.get(i) {
+            Some(RegisterVal::Table(v)) => *v,
+            _ => Default::default(),
+        };
+        Self {
+            es: segment(0),
+            cs: segment(1),
+            ss: segment(2),
+            ds: segment(3),
+            fs: segment(4),
+            gs: segment(5),
+            ldtr: segment(6),
+            tr: segment(7),
+            idtr: table(8),
+            gdtr: table(9),
+            cr0: reg64(values, 10),
+            cr2: reg64(values, 11),
+            cr3: reg64(values, 12),
+            cr4: reg64(values, 13),
+            cr8: reg64(values, 14),
+            efer: reg64(values, 15),
+            apic_base: reg64(values, 16),
+        }
+    }
+
+    /// The values of [`X64SpecialRegisters::REGISTERS`], in order.
+    pub fn to_values(&self) -> Vec<(Register, RegisterVal)> {
+        let values = [
+            RegisterVal::Segment(self.es),
+            RegisterVal::Segment(self.cs),
+            RegisterVal::Segment(self.ss),
+            RegisterVal::Segment(self.ds),
+            RegisterVal::Segment(self.fs),
+            RegisterVal::Segment(self.gs),
+            RegisterVal::Segment(self.ldtr),
+            RegisterVal::Segment(self.tr),
+            RegisterVal::Table(self.idtr),
+            RegisterVal::Table(self.gdtr),
+            RegisterVal::Reg64(self.cr0),
+            RegisterVal::Reg64(self.cr2),
+            RegisterVal::Reg64(self.cr3),
+            RegisterVal::Reg64(self.cr4),
+            RegisterVal::Reg64(self.cr8),
+            RegisterVal::Reg64(self.efer),
+            RegisterVal::Reg64(self.apic_base),
+        ];
+        Self::REGISTERS.into_iter().zip(values).collect()
+    }
+}
+
+impl Default for X64SpecialRegisters {
+    fn default() -> Self {
+        let segment = |selector: u16, base: u64, attributes: u16| SegmentRegister {
+            base,
+            limit: 0xffff,
+            selector,
+            attributes: X64SegmentRegisterAttributes::from_bits_retain(attributes),
+        };
+        // Present, read/write and accessed data segments.
+        let data = segment(0, 0, 0x93);
+        Self {
+            es: data,
+            // Present, execute/read and accessed code segment, the first fetch is at 0xfffffff0.
+            cs: segment(0xf000, 0xffff_0000, 0x9b),
+            ss: data,
+            ds: data,
+            fs: data,
+            gs: data,
+            ldtr: segment(0, 0, 0x82),
+            // A busy 32-bit TSS, VM entry rejects a TSS that is not busy.
+            tr: segment(0, 0, 0x8b),
+            idtr: TableRegister::new(0, 0xffff),
+            gdtr: TableRegister::new(0, 0xffff),
+            // CD, NW and ET.
+            cr0: 0x6000_0010,
+            cr2: 0,
+            cr3: 0,
+            cr4: 0,
+            cr8: 0,
+            efer: 0,
+            // Enabled and the bootstrap processor.
+            apic_base: 0xfee0_0900,
+        }
+    }
+}
+
+/// The x87, MMX and SSE registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct X64FpuState {
+    pub fp_registers: [FpRegister; 8],
+    pub xmm_registers: [u128; 16],
+    pub fp_control_status: X64FpControlStatusRegister,
+    pub xmm_control_status: X64XmmControlStatusRegister,
+}
+
+impl X64FpuState {
+    pub const REGISTERS: [Register; 26] = [
+        Register::FpMmx0,
+        Register::FpMmx1,
+        Register::FpMmx2,
+        Register::FpMmx3,
+        Register::FpMmx4,
+        Register::FpMmx5,
+        Register::FpMmx6,
+        Register::FpMmx7,
+        Register::Xmm0,
+        Register::Xmm1,
+        Register::Xmm2,
+        Register::Xmm3,
+        Register::Xmm4,
+        Register::Xmm5,
+        Register::Xmm6,
+        Register::Xmm7,
+        Register::Xmm8,
+        Register::Xmm9,
+        Register::Xmm10,
+        Register::Xmm11,
+        Register::Xmm12,
+        Register::Xmm13,
+        Register::Xmm14,
+        Register::Xmm15,
+        Register::FpControlStatus,
+        Register::XmmControlStatus,
+    ];
+
+    /// Build from the values of [`X64FpuState::REGISTERS`], in order.
+    pub fn from_values(values: &[RegisterVal]) -> Self {
+        let fp = |i: usize| match values.get(i) {
+            Some(RegisterVal::Fp(v)) => *v,
+            _ => Default::default(),
+        };
+        let xmm = |i: usize| match values.get(i) {
+            Some(RegisterVal::Reg128(v)) => *v,
+            _ => 0,
+        };
+        Self {
+            fp_registers: std::array::from_fn(fp),
+            xmm_registers: std::array::from_fn(|i| xmm(8 + i)),
+            fp_control_status: match values.get(24) {
+                Some(RegisterVal::FpControlStatus(v)) => *v,
+                _ => Default::default(),
+            },
+            xmm_control_status: match values.get(25) {
+                Some(RegisterVal::XmmControlStatus(v)) => *v,
+                _ => Default::default(),
+            },
+        }
+    }
+
+    /// The values of [`X64FpuState::REGISTERS`], in order.
+    pub fn to_values(&self) -> Vec<(Register, RegisterVal)> {
+        let values = self
+            .fp_registers
+            .iter()
+            .map(|&v| RegisterVal::Fp(v))
+            .chain(self.xmm_registers.iter().map(|&v| RegisterVal::Reg128(v)))
+            .chain([
+                RegisterVal::FpControlStatus(self.fp_control_status),
+                RegisterVal::XmmControlStatus(self.xmm_control_status),
+            ]);
+        Self::REGISTERS.into_iter().zip(values).collect()
+    }
+}
+
+impl Default for X64FpuState {
+    fn default() -> Self {
+        Self {
+            fp_registers: Default::default(),
+            xmm_registers: Default::default(),
+            fp_control_status: X64FpControlStatusRegister {
+                control: 0x40,
+                // The abridged tag word, every register is valid and holds zero.
+                tag: 0xff,
+                ..Default::default()
+            },
+            xmm_control_status: X64XmmControlStatusRegister {
+                // All exceptions masked.
+                status_control: 0x1f80,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+fn reg64(values: &[RegisterVal], i: usize) -> u64 {
+    values
+        .get(i)
+        .and_then(RegisterVal::as_u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        backend::MockBackend,
+        partition::{PartitionBuilder, PartitionProperty},
+        processor::{Register, RegisterVal},
+    };
+
+    use super::{X64FpuState, X64Registers, X64SpecialRegisters};
+
+    #[test]
+    fn round_trip() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(1))
+            .unwrap()
+            .setup()
+            .unwrap();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+
+        // Unset registers read as zero in the mock, not the reset state.
+        assert_eq!(vcpu.get_regs().unwrap().rip, 0);
+
+        let regs = X64Registers {
+            rax: 0xbeef,
+            r15: 0xf00d,
+            ..Default::default()
+        };
+        vcpu.set_regs(&regs).unwrap();
+        assert_eq!(vcpu.get_regs().unwrap(), regs);
+        assert_eq!(
+            mock.register(0, Register::Rip),
+            Some(RegisterVal::Reg64(0xfff0))
+        );
+
+        let mut sregs = X64SpecialRegisters::default();
+        sregs.cr0 |= 1;
+        sregs.gdtr.base = 0x1000;
+        vcpu.set_sregs(&sregs).unwrap();
+        assert_eq!(vcpu.get_sregs().unwrap(), sregs);
+        assert_eq!(vcpu.paging_state().unwrap().cr0, 0x6000_0011);
+
+        let mut fpu = X64FpuState::default();
+        fpu.xmm_registers[15] = u128::MAX;
+        vcpu.set_fpu(&fpu).unwrap();
+        assert_eq!(vcpu.get_fpu().unwrap(), fpu);
+        assert_eq!(
+            mock.register(0, Register::Xmm15),
+            Some(RegisterVal::Reg128(u128::MAX))
+        );
+    }
+
+    #[test]
+    fn reset_state() {
+        let sregs = X64SpecialRegisters::default();
+        // The first instruction is fetched from the top of the 4GiB address space.
+        assert_eq!(sregs.cs.base + X64Registers::default().rip, 0xffff_fff0);
+        assert_eq!(
+            X64SpecialRegisters::from_values(
+                &sregs
+                    .to_values()
+                    .into_iter()
+                    .map(|(_, v)| v)
+                    .collect::<Vec<_>>()
+            ),
+            sregs
+        );
+    }
+}
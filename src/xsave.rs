@@ -0,0 +1,504 @@
+use crate::{fields::FpRegister, flags::XsaveComponents, Error, Result};
+
+/// The size of an area holding only the x87 and SSE state, the legacy region and the header.
+pub const XSAVE_MIN_SIZE: usize = 576;
+
+const XSTATE_BV: usize = 512;
+const XCOMP_BV: usize = 520;
+
+/// Size, offset in the standard format and whether the compacted format aligns it to 64 bytes, for
+/// the components after SSE. Supervisor components only ever appear in the compacted format, the
+/// size of the LBR component depends on the processor so it is left out.
+const EXTENDED_COMPONENTS: &[(XsaveComponents, usize, Option<usize>, bool)] = &[
+    (XsaveComponents::Avx, 256, Some(576), false),
+    (XsaveComponents::BndRegs, 64, Some(960), false),
+    (XsaveComponents::BndCsr, 64, Some(1024), false),
+    (XsaveComponents::Opmask, 64, Some(1088), false),
+    (XsaveComponents::ZmmHi256, 512, Some(1152), false),
+    (XsaveComponents::Hi16Zmm, 1024, Some(1664), false),
+    (XsaveComponents::Pt, 128, None, false),
+    (XsaveComponents::Pkru, 8, Some(2688), false),
+    (XsaveComponents::Pasid, 8, None, false),
+    (XsaveComponents::CetU, 16, None, false),
+    (XsaveComponents::CetS, 24, None, false),
+    (XsaveComponents::Hdc, 8, None, false),
+    (XsaveComponents::Uintr, 48, None, false),
+    (XsaveComponents::Hwp, 8, None, false),
+    (XsaveComponents::TileCfg, 64, Some(2752), false),
+    (XsaveComponents::TileData, 8192, Some(2816), true),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct X87State {
+    pub control: u16,
+    pub status: u16,
+    /// The abridged tag word, a set bit marks a valid register.
+    pub tag: u8,
+    pub last_opcode: u16,
+    pub last_ip: u64,
+    pub last_dp: u64,
+    pub registers: [FpRegister; 8],
+}
+
+/// The state after `FNINIT`, what the processor restores for a component marked as initial.
+impl Default for X87State {
+    fn default() -> Self {
+        Self {
+            control: 0x37f,
+            status: 0,
+            tag: 0,
+            last_opcode: 0,
+            last_ip: 0,
+            last_dp: 0,
+            registers: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SseState {
+    pub mxcsr: u32,
+    /// The `MXCSR` bits supported by the processor, only ever written by the processor.
+    pub mxcsr_mask: u32,
+    pub xmm: [u128; 16],
+}
+
+impl Default for SseState {
+    fn default() -> Self {
+        Self {
+            mxcsr: 0x1f80,
+            mxcsr_mask: 0,
+            xmm: [0; 16],
+        }
+    }
+}
+
+/// The typed contents of an XSAVE area in either the standard or compacted format.
+///
+/// Components the area has no room for are `None`, components the area marks as being in their
+/// initial state are read as zero.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct XsaveArea {
+    /// The components not in their initial state.
+    pub xstate_bv: XsaveComponents,
+    /// The components held by a compacted area, along with [`XsaveComponents::Compacted`].
+    pub xcomp_bv: XsaveComponents,
+    pub x87: X87State,
+    pub sse: SseState,
+    /// The upper 128 bits of YMM0 to YMM15.
+    pub ymm_hi128: Option<[u128; 16]>,
+    /// K0 to K7.
+    pub opmask: Option<[u64; 8]>,
+    /// The upper 256 bits of ZMM0 to ZMM15, lower half first.
+    pub zmm_hi256: Option<[[u128; 2]; 16]>,
+    /// ZMM16 to ZMM31, lowest quarter first.
+    pub hi16_zmm: Option<[[u128; 4]; 16]>,
+    pub pkru: Option<u32>,
+}
+
+impl XsaveArea {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < XSAVE_MIN_SIZE {
+            return Err(Error::XsaveAreaTruncated(bytes.len(), XSAVE_MIN_SIZE));
+        }
+        let xstate_bv = XsaveComponents::from_bits_retain(read_u64(bytes, XSTATE_BV));
+        let xcomp_bv = XsaveComponents::from_bits_retain(read_u64(bytes, XCOMP_BV));
+        if xcomp_bv.contains(XsaveComponents::Compacted) {
+            let size = compacted_end(xcomp_bv, 63)?;
+            if bytes.len() < size {
+                return Err(Error::XsaveAreaTruncated(bytes.len(), size));
+            }
+        }
+
+        let x87 = match xstate_bv.contains(XsaveComponents::X87) {
+            true => X87State {
+                control: read_u16(bytes, 0),
+                status: read_u16(bytes, 2),
+                tag: bytes[4],
+                last_opcode: read_u16(bytes, 6),
+                last_ip: read_u64(bytes, 8),
+                last_dp: read_u64(bytes, 16),
+                registers: std::array::from_fn(|i| {
+                    FpRegister::from_bytes(read(bytes, 32 + i * 16))
+                }),
+            },
+            false => X87State::default(),
+        };
+        // MXCSR is saved along with either the SSE or AVX state, so it is always read.
+        let sse = SseState {
+            mxcsr: read_u32(bytes, 24),
+            mxcsr_mask: read_u32(bytes, 28),
+            xmm: match xstate_bv.contains(XsaveComponents::Sse) {
+                true => std::array::from_fn(|i| read_u128(bytes, 160 + i * 16)),
+                false => [0; 16],
+            },
+        };
+
+        // The offset of a component's data, unless it is in its initial state.
+        let component = |component: XsaveComponents| -> Result<Option<Option<usize>>> {
+            Ok(locate(bytes, component)?
+                .map(|offset| xstate_bv.contains(component).then_some(offset)))
+        };
+        Ok(Self {
+            xstate_bv,
+            xcomp_bv,
+            x87,
+            sse,
+            ymm_hi128: component(XsaveComponents::Avx)?.map(|offset| {
+                std::array::from_fn(|i| {
+                    offset.map_or(0, |offset| read_u128(bytes, offset + i * 16))
+                })
+            }),
+            opmask: component(XsaveComponents::Opmask)?.map(|offset| {
+                std::array::from_fn(|i| offset.map_or(0, |offset| read_u64(bytes, offset + i * 8)))
+            }),
+            zmm_hi256: component(XsaveComponents::ZmmHi256)?.map(|offset| {
+                std::array::from_fn(|i| {
+                    std::array::from_fn(|j| {
+                        offset.map_or(0, |offset| read_u128(bytes, offset + i * 32 + j * 16))
+                    })
+                })
+            }),
+            hi16_zmm: component(XsaveComponents::Hi16Zmm)?.map(|offset| {
+                std::array::from_fn(|i| {
+                    std::array::from_fn(|j| {
+                        offset.map_or(0, |offset| read_u128(bytes, offset + i * 64 + j * 16))
+                    })
+                })
+            }),
+            pkru: component(XsaveComponents::Pkru)?
+                .map(|offset| offset.map_or(0, |offset| read_u32(bytes, offset))),
+        })
+    }
+
+    /// Write the state back into `bytes`, an area in the layout it was read from.
+    ///
+    /// The x87, SSE and every component that is `Some` are marked as not in their initial state,
+    /// other components are left as they are.
+    pub fn apply(&self, bytes: &mut [u8]) -> Result<()> {
+        if bytes.len() < XSAVE_MIN_SIZE {
+            return Err(Error::XsaveAreaTruncated(bytes.len(), XSAVE_MIN_SIZE));
+        }
+        let mut xstate_bv = XsaveComponents::from_bits_retain(read_u64(bytes, XSTATE_BV));
+
+        write(bytes, 0, self.x87.control.to_le_bytes());
+        write(bytes, 2, self.x87.status.to_le_bytes());
+        bytes[4] = self.x87.tag;
+        write(bytes, 6, self.x87.last_opcode.to_le_bytes());
+        write(bytes, 8, self.x87.last_ip.to_le_bytes());
+        write(bytes, 16, self.x87.last_dp.to_le_bytes());
+        for (i, register) in self.x87.registers.iter().enumerate() {
+            write(bytes, 32 + i * 16, register.to_bytes());
+        }
+        write(bytes, 24, self.sse.mxcsr.to_le_bytes());
+        write(bytes, 28, self.sse.mxcsr_mask.to_le_bytes());
+        for (i, xmm) in self.sse.xmm.iter().enumerate() {
+            write(bytes, 160 + i * 16, xmm.to_le_bytes());
+        }
+        xstate_bv |= XsaveComponents::X87 | XsaveComponents::Sse;
+
+        let mut component = |component: XsaveComponents, data: Vec<u8>| -> Result<()> {
+            let offset =
+                locate(bytes, component)?.ok_or(Error::MissingXsaveComponents(component))?;
+            bytes[offset..offset + data.len()].copy_from_slice(&data);
+            xstate_bv |= component;
+            Ok(())
+        };
+        if let Some(ymm_hi128) = self.ymm_hi128 {
+            component(
+                XsaveComponents::Avx,
+                ymm_hi128.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            )?;
+        }
+        if let Some(opmask) = self.opmask {
+            component(
+                XsaveComponents::Opmask,
+                opmask.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            )?;
+        }
+        if let Some(zmm_hi256) = self.zmm_hi256 {
+            component(
+                XsaveComponents::ZmmHi256,
+                zmm_hi256
+                    .iter()
+                    .flatten()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect(),
+            )?;
+        }
+        if let Some(hi16_zmm) = self.hi16_zmm {
+            component(
+                XsaveComponents::Hi16Zmm,
+                hi16_zmm
+                    .iter()
+                    .flatten()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect(),
+            )?;
+        }
+        if let Some(pkru) = self.pkru {
+            component(XsaveComponents::Pkru, pkru.to_le_bytes().to_vec())?;
+        }
+
+        write(bytes, XSTATE_BV, xstate_bv.bits().to_le_bytes());
+        Ok(())
+    }
+
+    pub fn is_compacted(&self) -> bool {
+        self.xcomp_bv.contains(XsaveComponents::Compacted)
+    }
+
+    /// YMM`index`, lower half first. `None` past YMM15 or if the area holds no AVX state.
+    pub fn ymm(&self, index: usize) -> Option<[u128; 2]> {
+        Some([*self.sse.xmm.get(index)?, *self.ymm_hi128?.get(index)?])
+    }
+
+    /// ZMM`index`, lowest quarter first. `None` past ZMM31 or if the area holds no AVX-512 state.
+    pub fn zmm(&self, index: usize) -> Option<[u128; 4]> {
+        match index {
+            0..=15 => {
+                let [low, high] = self.ymm(index)?;
+                let [upper_low, upper_high] = self.zmm_hi256?[index];
+                Some([low, high, upper_low, upper_high])
+            }
+            16..=31 => Some(self.hi16_zmm?[index - 16]),
+            _ => None,
+        }
+    }
+}
+
+/// The offset of `component` in `bytes`, `None` if the area has no room for it.
+fn locate(bytes: &[u8], component: XsaveComponents) -> Result<Option<usize>> {
+    let xcomp_bv = XsaveComponents::from_bits_retain(read_u64(bytes, XCOMP_BV));
+    let Some(&(_, size, standard, aligned)) = EXTENDED_COMPONENTS.iter().find(|c| c.0 == component)
+    else {
+        return Ok(None);
+    };
+
+    if !xcomp_bv.contains(XsaveComponents::Compacted) {
+        return Ok(standard.filter(|offset| offset + size <= bytes.len()));
+    }
+    if !xcomp_bv.contains(component) {
+        return Ok(None);
+    }
+    let offset = aligned_offset(
+        compacted_end(xcomp_bv, component.bits().trailing_zeros())?,
+        aligned,
+    );
+    if offset + size > bytes.len() {
+        return Err(Error::XsaveAreaTruncated(bytes.len(), offset + size));
+    }
+    Ok(Some(offset))
+}
+
+/// Where the components of a compacted area below bit `until` end, they are packed in order after
+/// the header.
+fn compacted_end(xcomp_bv: XsaveComponents, until: u32) -> Result<usize> {
+    let mut offset = XSAVE_MIN_SIZE;
+    for bit in 2..until {
+        let held = XsaveComponents::from_bits_retain(1 << bit);
+        if !xcomp_bv.contains(held) {
+            continue;
+        }
+        let &(_, size, _, aligned) = EXTENDED_COMPONENTS
+            .iter()
+            .find(|c| c.0 == held)
+            .ok_or(Error::UnknownXsaveComponents(held))?;
+        offset = aligned_offset(offset, aligned) + size;
+    }
+    Ok(offset)
+}
+
+fn aligned_offset(offset: usize, aligned: bool) -> usize {
+    match aligned {
+        true => offset.next_multiple_of(64),
+        false => offset,
+    }
+}
+
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> [u8; N] {
+    bytes[offset..offset + N].try_into().unwrap()
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(read(bytes, offset))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(read(bytes, offset))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(read(bytes, offset))
+}
+
+fn read_u128(bytes: &[u8], offset: usize) -> u128 {
+    u128::from_le_bytes(read(bytes, offset))
+}
+
+fn write<const N: usize>(bytes: &mut [u8], offset: usize, data: [u8; N]) {
+    bytes[offset..offset + N].copy_from_slice(&data);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        backend::MockBackend,
+        fields::FpRegister,
+        flags::XsaveComponents,
+        partition::{PartitionBuilder, PartitionProperty},
+        Error,
+    };
+
+    use super::{XsaveArea, XSAVE_MIN_SIZE};
+
+    fn area(size: usize, xstate_bv: XsaveComponents, xcomp_bv: XsaveComponents) -> Vec<u8> {
+        let mut bytes = vec![0; size];
+        bytes[512..520].copy_from_slice(&xstate_bv.bits().to_le_bytes());
+        bytes[520..528].copy_from_slice(&xcomp_bv.bits().to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn standard() {
+        let all = XsaveComponents::X87
+            | XsaveComponents::Sse
+            | XsaveComponents::Avx
+            | XsaveComponents::Opmask
+            | XsaveComponents::ZmmHi256
+            | XsaveComponents::Hi16Zmm
+            | XsaveComponents::Pkru;
+        let mut bytes = area(2696, all, XsaveComponents::empty());
+        bytes[0..2].copy_from_slice(&0x27fu16.to_le_bytes());
+        bytes[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+        bytes[32..40].copy_from_slice(&0x8000_0000_0000_0000u64.to_le_bytes());
+        bytes[40..42].copy_from_slice(&0x3fffu16.to_le_bytes());
+        bytes[160 + 16..160 + 32].copy_from_slice(&1u128.to_le_bytes());
+        bytes[576 + 16..576 + 32].copy_from_slice(&2u128.to_le_bytes());
+        bytes[1088 + 8..1088 + 16].copy_from_slice(&3u64.to_le_bytes());
+        bytes[1152 + 32 + 16..1152 + 64].copy_from_slice(&4u128.to_le_bytes());
+        bytes[1664..1680].copy_from_slice(&5u128.to_le_bytes());
+        bytes[2688..2692].copy_from_slice(&0x55u32.to_le_bytes());
+
+        let xsave = XsaveArea::parse(&bytes).unwrap();
+        assert!(!xsave.is_compacted());
+        assert_eq!(xsave.x87.control, 0x27f);
+        assert_eq!(
+            xsave.x87.registers[0],
+            FpRegister::from_bytes(bytes[32..48].try_into().unwrap())
+        );
+        assert_eq!(xsave.sse.mxcsr, 0x1f80);
+        assert_eq!(xsave.ymm(1), Some([1, 2]));
+        assert_eq!(xsave.opmask.unwrap()[1], 3);
+        assert_eq!(xsave.zmm(1), Some([1, 2, 0, 4]));
+        assert_eq!(xsave.zmm(16), Some([5, 0, 0, 0]));
+        assert_eq!(xsave.ymm(16), None);
+        assert_eq!(xsave.zmm(32), None);
+        assert_eq!(xsave.pkru, Some(0x55));
+
+        // A legacy sized area has no room for the extended components.
+        let xsave = XsaveArea::parse(&bytes[..XSAVE_MIN_SIZE]).unwrap();
+        assert_eq!(xsave.ymm_hi128, None);
+        assert_eq!(xsave.pkru, None);
+    }
+
+    #[test]
+    fn init_state() {
+        let mut bytes = area(2696, XsaveComponents::empty(), XsaveComponents::empty());
+        bytes[0..2].copy_from_slice(&0x27fu16.to_le_bytes());
+        bytes[160..176].copy_from_slice(&1u128.to_le_bytes());
+        bytes[2688..2692].copy_from_slice(&0x55u32.to_le_bytes());
+
+        let xsave = XsaveArea::parse(&bytes).unwrap();
+        assert_eq!(xsave.x87.control, 0x37f);
+        assert_eq!(xsave.sse.xmm[0], 0);
+        assert_eq!(xsave.ymm_hi128, Some([0; 16]));
+        assert_eq!(xsave.pkru, Some(0));
+    }
+
+    #[test]
+    fn compacted() {
+        let held = XsaveComponents::X87 | XsaveComponents::Sse;
+        let xcomp_bv =
+            XsaveComponents::Compacted | held | XsaveComponents::Avx | XsaveComponents::Pkru;
+        let mut bytes = area(840, held | XsaveComponents::Pkru, xcomp_bv);
+        bytes[832..836].copy_from_slice(&0x55u32.to_le_bytes());
+
+        let xsave = XsaveArea::parse(&bytes).unwrap();
+        assert!(xsave.is_compacted());
+        assert_eq!(xsave.pkru, Some(0x55));
+        assert_eq!(xsave.ymm_hi128, Some([0; 16]));
+        assert_eq!(xsave.opmask, None);
+
+        assert!(matches!(
+            XsaveArea::parse(&bytes[..836]),
+            Err(Error::XsaveAreaTruncated(836, 840))
+        ));
+
+        let bytes = area(4096, held, xcomp_bv | XsaveComponents::Lbr);
+        assert!(matches!(
+            XsaveArea::parse(&bytes),
+            Err(Error::UnknownXsaveComponents(XsaveComponents::Lbr))
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(matches!(
+            XsaveArea::parse(&[0; 512]),
+            Err(Error::XsaveAreaTruncated(512, XSAVE_MIN_SIZE))
+        ));
+    }
+
+    #[test]
+    fn apply() {
+        let xcomp_bv = XsaveComponents::Compacted
+            | XsaveComponents::X87
+            | XsaveComponents::Sse
+            | XsaveComponents::Avx;
+        let mut bytes = area(832, XsaveComponents::empty(), xcomp_bv);
+
+        let mut xsave = XsaveArea::parse(&bytes).unwrap();
+        xsave.sse.xmm[3] = 7;
+        xsave.ymm_hi128.as_mut().unwrap()[3] = 8;
+        xsave.apply(&mut bytes).unwrap();
+
+        let parsed = XsaveArea::parse(&bytes).unwrap();
+        assert_eq!(
+            parsed.xstate_bv,
+            XsaveComponents::X87 | XsaveComponents::Sse | XsaveComponents::Avx
+        );
+        assert_eq!(parsed.ymm(3), Some([7, 8]));
+        assert_eq!(parsed.x87, xsave.x87);
+
+        xsave.pkru = Some(0x55);
+        assert!(matches!(
+            xsave.apply(&mut bytes),
+            Err(Error::MissingXsaveComponents(XsaveComponents::Pkru))
+        ));
+    }
+
+    #[test]
+    fn processor_state() {
+        let mock = Arc::new(MockBackend::new());
+        let mut partition = PartitionBuilder::with_backend(mock.clone())
+            .property(PartitionProperty::ProcessorCount(1))
+            .unwrap()
+            .setup()
+            .unwrap();
+        let mut vcpu = partition.create_virtual_processor(0).unwrap();
+
+        let mut bytes = vcpu.get_xsave_state().unwrap();
+        let mut xsave = XsaveArea::parse(&bytes).unwrap();
+        assert!(xsave.is_compacted());
+        assert_eq!(xsave.ymm_hi128, None);
+
+        xsave.sse.mxcsr = 0x9fc0;
+        xsave.apply(&mut bytes).unwrap();
+        vcpu.set_xsave_state(&bytes).unwrap();
+        let xsave = XsaveArea::parse(&vcpu.get_xsave_state().unwrap()).unwrap();
+        assert_eq!(xsave.sse.mxcsr, 0x9fc0);
+    }
+}